@@ -0,0 +1,225 @@
+//! A global allocator that counts allocated bytes while tracking is enabled.
+//!
+//! The binary installs `CountingAllocator` as its global allocator. Tracking is off by
+//! default, in which case each allocation only pays for one relaxed load. Once enabled,
+//! every allocation and deallocation updates shared atomics, so timings taken with
+//! tracking on include some contention between threads.
+//!
+//! Once a pool is reserved with `pool::reserve`, allocations are served from it instead
+//! of the system allocator.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fmt, ptr,
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+};
+
+use crate::pool;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Bytes allocated and not yet freed since tracking was enabled.
+static CURRENT: AtomicI64 = AtomicI64::new(0);
+/// Highest value of `CURRENT` since the start of the phase.
+static PEAK: AtomicI64 = AtomicI64::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Wraps the system allocator and counts allocations while tracking is enabled.
+pub struct CountingAllocator;
+
+impl CountingAllocator {
+    fn on_alloc(size: usize) {
+        if ENABLED.load(Ordering::Relaxed) {
+            let current = CURRENT.fetch_add(size as i64, Ordering::Relaxed) + size as i64;
+            PEAK.fetch_max(current, Ordering::Relaxed);
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            ALLOCATED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn on_dealloc(size: usize) {
+        if ENABLED.load(Ordering::Relaxed) {
+            CURRENT.fetch_sub(size as i64, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut ptr = pool::alloc(layout);
+        if ptr.is_null() {
+            ptr = System.alloc(layout);
+        }
+        if !ptr.is_null() {
+            Self::on_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let mut ptr = pool::alloc(layout);
+        if ptr.is_null() {
+            ptr = System.alloc_zeroed(layout);
+        } else {
+            // Pool memory is reused, so it has to be cleared.
+            ptr.write_bytes(0, layout.size());
+        }
+        if !ptr.is_null() {
+            Self::on_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if pool::contains(ptr) {
+            pool::dealloc(ptr, layout);
+        } else {
+            System.dealloc(ptr, layout);
+        }
+        Self::on_dealloc(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = if pool::contains(ptr) && pool::fits_in_place(layout, new_size) {
+            ptr
+        } else if pool::contains(ptr) || pool::serves(new_layout) {
+            // Move between the pool and the system allocator, or within the pool.
+            let new_ptr = self.alloc(new_layout);
+            if !new_ptr.is_null() {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            return new_ptr;
+        } else {
+            System.realloc(ptr, layout, new_size)
+        };
+        if !new_ptr.is_null() {
+            Self::on_dealloc(layout.size());
+            Self::on_alloc(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// Start tracking. Memory allocated before this call is not counted, so it should be
+/// called before the input is generated.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Memory allocated during a phase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Bytes in use at the end of the phase.
+    pub current: i64,
+    /// Highest number of bytes in use during the phase.
+    pub peak: i64,
+    pub allocations: u64,
+    /// Total bytes requested by the allocations, including those freed again.
+    pub allocated_bytes: u64,
+}
+
+/// Reset the peak and the allocation counts at the start of a phase.
+pub fn start_phase() {
+    PEAK.store(CURRENT.load(Ordering::Relaxed), Ordering::Relaxed);
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    ALLOCATED_BYTES.store(0, Ordering::Relaxed);
+}
+
+/// The memory allocated since `start_phase`.
+pub fn finish_phase() -> MemoryStats {
+    MemoryStats {
+        current: CURRENT.load(Ordering::Relaxed),
+        peak: PEAK.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+/// A byte count, formatted with a binary unit, e.g. "1.50 MiB".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bytes(pub i64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut value = self.0 as f64;
+        let mut unit = 0;
+        while value.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{} B", self.0)
+        } else {
+            write!(f, "{:.2} {}", value, UNITS[unit])
+        }
+    }
+}
+
+/// Parses a byte count with an optional binary unit, e.g. "512", "64K", "64KiB" or "2G".
+impl FromStr for Bytes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (value, unit) = s.split_at(digits);
+        let value = value.parse::<i64>()?;
+        let shift = match unit.trim().trim_end_matches("iB").trim_end_matches('B') {
+            "" => 0,
+            "K" | "k" => 10,
+            "M" | "m" => 20,
+            "G" | "g" => 30,
+            "T" | "t" => 40,
+            _ => anyhow::bail!("unknown unit: {}", unit),
+        };
+        value
+            .checked_mul(1 << shift)
+            .map(Bytes)
+            .ok_or_else(|| anyhow::anyhow!("{} is too large", s))
+    }
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "memory: peak {}, current {}, {} allocations of {}",
+            Bytes(self.peak),
+            Bytes(self.current),
+            self.allocations,
+            Bytes(self.allocated_bytes as i64)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes() {
+        assert_eq!(Bytes(512).to_string(), "512 B");
+        assert_eq!(Bytes(1536).to_string(), "1.50 KiB");
+        assert_eq!(Bytes(3 << 30).to_string(), "3.00 GiB");
+        assert_eq!(Bytes(-2048).to_string(), "-2.00 KiB");
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        let parse = |s: &str| s.parse::<Bytes>().map(|b| b.0).ok();
+        assert_eq!(parse("512"), Some(512));
+        assert_eq!(parse("64K"), Some(64 << 10));
+        assert_eq!(parse("64KiB"), Some(64 << 10));
+        assert_eq!(parse("3 GB"), Some(3 << 30));
+        assert_eq!(parse("1.5G"), None);
+        assert_eq!(parse("8X"), None);
+    }
+}
@@ -0,0 +1,335 @@
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    join_benchmark::{bucket_hash, partition_hash},
+    tuple::{DataChunk, Distribution, Key},
+};
+
+/// Hash functions that can be analyzed. `Xxh3` is the one used by the hash tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HashFunction {
+    /// xxh3 with the same seeds as `bucket_hash` and `partition_hash`.
+    Xxh3,
+    /// Fibonacci hashing, which takes the high bits of `key * 2^64 / phi`.
+    Multiplicative,
+    /// The low bits of the key itself.
+    Identity,
+}
+
+impl HashFunction {
+    /// Map a key to one of `bucket_num` buckets. `bucket_num` must be a power of 2.
+    pub fn bucket(self, key: Key, bucket_num: usize) -> usize {
+        match self {
+            HashFunction::Xxh3 => bucket_hash(key) as usize & (bucket_num - 1),
+            HashFunction::Multiplicative => fibonacci_hash(key, bucket_num),
+            HashFunction::Identity => key as usize & (bucket_num - 1),
+        }
+    }
+
+    /// Map a key to one of `partition_num` partitions. `partition_num` must be a power of 2.
+    pub fn partition(self, key: Key, partition_num: usize) -> usize {
+        match self {
+            HashFunction::Xxh3 => partition_hash(key) as usize & (partition_num - 1),
+            HashFunction::Multiplicative => fibonacci_hash(key, partition_num),
+            HashFunction::Identity => key as usize & (partition_num - 1),
+        }
+    }
+}
+
+fn fibonacci_hash(key: Key, num: usize) -> usize {
+    if num == 1 {
+        return 0;
+    }
+    (key.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - num.trailing_zeros())) as usize
+}
+
+/// Bucket occupancy and partition balance statistics of a workload under a hash function.
+#[derive(Debug)]
+pub struct HashAnalysis {
+    pub bucket_num: usize,
+    pub partition_num: usize,
+    pub inner_tuple_num: usize,
+    pub outer_tuple_num: usize,
+    /// `histogram[i]` is the number of buckets holding exactly `i` tuples.
+    pub histogram: Vec<usize>,
+    pub max_chain_length: usize,
+    /// Mean number of tuples in non-empty buckets.
+    pub mean_chain_length: f64,
+    pub empty_bucket_fraction: f64,
+    /// Pearson's chi-squared statistic against a uniform bucket assignment.
+    pub chi_squared: f64,
+    /// Upper tail probability of `chi_squared` with `bucket_num - 1` degrees of freedom.
+    pub chi_squared_p_value: f64,
+    /// Mean size of the bucket hit by an outer tuple, i.e. the expected chain walk per probe.
+    pub probe_weighted_chain_length: f64,
+    /// Largest partition size divided by mean partition size.
+    pub inner_partition_imbalance: f64,
+    pub outer_partition_imbalance: f64,
+}
+
+impl HashAnalysis {
+    pub fn new(
+        hash: HashFunction,
+        bucket_num: usize,
+        partition_num: usize,
+        inner: &[DataChunk],
+        outer: &[DataChunk],
+    ) -> Result<Self> {
+        if !bucket_num.is_power_of_two() {
+            return Err(anyhow!("Bucket number must be a power of 2"));
+        }
+        if !partition_num.is_power_of_two() {
+            return Err(anyhow!("Partition number must be a power of 2"));
+        }
+
+        let mut bucket_sizes = vec![0usize; bucket_num];
+        let mut inner_partition_sizes = vec![0usize; partition_num];
+        for tuple in inner.iter().flatten() {
+            bucket_sizes[hash.bucket(tuple.key(), bucket_num)] += 1;
+            inner_partition_sizes[hash.partition(tuple.key(), partition_num)] += 1;
+        }
+        let inner_tuple_num = bucket_sizes.iter().sum::<usize>();
+
+        let max_chain_length = bucket_sizes.iter().copied().max().unwrap_or(0);
+        let mut histogram = vec![0usize; max_chain_length + 1];
+        for &size in &bucket_sizes {
+            histogram[size] += 1;
+        }
+        let empty_bucket_fraction = histogram[0] as f64 / bucket_num as f64;
+        let non_empty = bucket_num - histogram[0];
+        let mean_chain_length = if non_empty == 0 {
+            0.0
+        } else {
+            inner_tuple_num as f64 / non_empty as f64
+        };
+
+        let expected = inner_tuple_num as f64 / bucket_num as f64;
+        let chi_squared = if expected == 0.0 {
+            0.0
+        } else {
+            bucket_sizes
+                .iter()
+                .map(|&size| (size as f64 - expected).powi(2) / expected)
+                .sum()
+        };
+        let chi_squared_p_value = chi_squared_upper_tail(chi_squared, (bucket_num - 1) as f64);
+
+        let (probe_chain_sum, outer_partition_sizes) = outer
+            .par_iter()
+            .map(|chunk| {
+                let mut chain_sum = 0usize;
+                let mut partition_sizes = vec![0usize; partition_num];
+                for tuple in chunk {
+                    chain_sum += bucket_sizes[hash.bucket(tuple.key(), bucket_num)];
+                    partition_sizes[hash.partition(tuple.key(), partition_num)] += 1;
+                }
+                (chain_sum, partition_sizes)
+            })
+            .reduce(
+                || (0, vec![0usize; partition_num]),
+                |(a_sum, mut a_sizes), (b_sum, b_sizes)| {
+                    a_sizes.iter_mut().zip(b_sizes).for_each(|(a, b)| *a += b);
+                    (a_sum + b_sum, a_sizes)
+                },
+            );
+        let outer_tuple_num = outer_partition_sizes.iter().sum::<usize>();
+        let probe_weighted_chain_length = if outer_tuple_num == 0 {
+            0.0
+        } else {
+            probe_chain_sum as f64 / outer_tuple_num as f64
+        };
+
+        Ok(Self {
+            bucket_num,
+            partition_num,
+            inner_tuple_num,
+            outer_tuple_num,
+            histogram,
+            max_chain_length,
+            mean_chain_length,
+            empty_bucket_fraction,
+            chi_squared,
+            chi_squared_p_value,
+            probe_weighted_chain_length,
+            inner_partition_imbalance: imbalance(&inner_partition_sizes),
+            outer_partition_imbalance: imbalance(&outer_partition_sizes),
+        })
+    }
+
+    pub fn print(&self) {
+        println!("buckets: {}", self.bucket_num);
+        println!("partitions: {}", self.partition_num);
+        println!("max chain length: {}", self.max_chain_length);
+        println!("mean chain length: {:.3}", self.mean_chain_length);
+        println!("empty bucket fraction: {:.4}", self.empty_bucket_fraction);
+        println!(
+            "chi-squared: {:.1} (dof = {}, p = {:.4})",
+            self.chi_squared,
+            self.bucket_num - 1,
+            self.chi_squared_p_value
+        );
+        println!(
+            "probe-weighted chain length: {:.3}",
+            self.probe_weighted_chain_length
+        );
+        println!(
+            "partition imbalance (max / mean): inner {:.3}, outer {:.3}",
+            self.inner_partition_imbalance, self.outer_partition_imbalance
+        );
+        println!("bucket size histogram:");
+        for (size, count) in self.histogram.iter().enumerate() {
+            println!("  {:>4}: {}", size, count);
+        }
+    }
+
+    /// Write the bucket size histogram as CSV, overwriting `path`.
+    pub fn write_histogram_csv(&self, path: &Path) -> Result<()> {
+        let mut file = std::fs::File::create(path)?;
+        writeln!(file, "bucket_size,bucket_count,bucket_fraction")?;
+        for (size, count) in self.histogram.iter().enumerate() {
+            writeln!(
+                file,
+                "{},{},{}",
+                size,
+                count,
+                *count as f64 / self.bucket_num as f64
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Append the summary statistics as one CSV row to `path`, writing a header if it is new.
+    pub fn append_summary_csv(
+        &self,
+        path: &Path,
+        hash: HashFunction,
+        distribution: Distribution,
+    ) -> Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(
+                file,
+                "hash,distribution,inner_tuple_num,outer_tuple_num,bucket_num,partition_num,\
+                 max_chain_length,mean_chain_length,empty_bucket_fraction,chi_squared,\
+                 chi_squared_p_value,probe_weighted_chain_length,inner_partition_imbalance,\
+                 outer_partition_imbalance"
+            )?;
+        }
+        writeln!(
+            file,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            hash.to_possible_value().unwrap().get_name(),
+            distribution.to_possible_value().unwrap().get_name(),
+            self.inner_tuple_num,
+            self.outer_tuple_num,
+            self.bucket_num,
+            self.partition_num,
+            self.max_chain_length,
+            self.mean_chain_length,
+            self.empty_bucket_fraction,
+            self.chi_squared,
+            self.chi_squared_p_value,
+            self.probe_weighted_chain_length,
+            self.inner_partition_imbalance,
+            self.outer_partition_imbalance
+        )?;
+        Ok(())
+    }
+}
+
+fn imbalance(sizes: &[usize]) -> f64 {
+    let total = sizes.iter().sum::<usize>();
+    if total == 0 {
+        return 1.0;
+    }
+    let mean = total as f64 / sizes.len() as f64;
+    *sizes.iter().max().unwrap() as f64 / mean
+}
+
+/// Upper tail probability of the chi-squared distribution, using the Wilson-Hilferty
+/// normal approximation. The degrees of freedom (bucket number) are always large here.
+fn chi_squared_upper_tail(chi_squared: f64, dof: f64) -> f64 {
+    if dof <= 0.0 {
+        return 1.0;
+    }
+    let variance = 2.0 / (9.0 * dof);
+    let z = ((chi_squared / dof).cbrt() - (1.0 - variance)) / variance.sqrt();
+    0.5 * erfc(z / std::f64::consts::SQRT_2)
+}
+
+/// Complementary error function (Numerical Recipes' `erfcc`, relative error < 1.2e-7).
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t
+        * (-z * z - 1.265_512_23
+            + t * (1.000_023_68
+                + t * (0.374_091_96
+                    + t * (0.096_784_18
+                        + t * (-0.186_288_06
+                            + t * (0.278_868_07
+                                + t * (-1.135_203_98
+                                    + t * (1.488_515_87
+                                        + t * (-0.822_152_23 + t * 0.170_872_77)))))))))
+            .exp();
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuple::TupleGenerator;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_identity_hash_on_dense_keys() {
+        let gen = TupleGenerator::new(64, 2, 16);
+        let (inner, outer) = gen.gen_uniform();
+        let analysis = HashAnalysis::new(HashFunction::Identity, 16, 4, &inner, &outer).unwrap();
+
+        // Dense keys fill every bucket exactly evenly.
+        assert_eq!(analysis.histogram, vec![0, 0, 0, 0, 16]);
+        assert_eq!(analysis.max_chain_length, 4);
+        assert_eq!(analysis.empty_bucket_fraction, 0.0);
+        assert_eq!(analysis.chi_squared, 0.0);
+        assert_eq!(analysis.probe_weighted_chain_length, 4.0);
+        assert_eq!(analysis.inner_partition_imbalance, 1.0);
+        assert_eq!(analysis.outer_tuple_num, 128);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_xxh3_histogram() {
+        let gen = TupleGenerator::new(1024, 1, 64);
+        let (inner, outer) = gen.gen_high_skew();
+        let analysis = HashAnalysis::new(HashFunction::Xxh3, 256, 16, &inner, &outer).unwrap();
+
+        assert_eq!(analysis.histogram.iter().sum::<usize>(), 256);
+        let tuple_num = analysis
+            .histogram
+            .iter()
+            .enumerate()
+            .map(|(size, count)| size * count)
+            .sum::<usize>();
+        assert_eq!(tuple_num, 1024);
+        assert!((0.0..=1.0).contains(&analysis.chi_squared_p_value));
+        assert!(analysis.inner_partition_imbalance >= 1.0);
+    }
+
+    #[test]
+    fn test_chi_squared_upper_tail() {
+        // The median of a chi-squared distribution is roughly its degrees of freedom.
+        let p = chi_squared_upper_tail(1000.0, 1000.0);
+        assert!((p - 0.5).abs() < 0.02);
+        assert!(chi_squared_upper_tail(2000.0, 1000.0) < 1e-6);
+        assert!(chi_squared_upper_tail(0.0, 1000.0) > 1.0 - 1e-6);
+    }
+}
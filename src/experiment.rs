@@ -0,0 +1,521 @@
+use std::{fmt, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alloc::MemoryStats,
+    join_benchmark::{
+        bloom::FilterStats,
+        grace::GraceHashJoin,
+        independent::{IndependentHashJoin, BROADCAST_PROBE, ROUTED_PROBE},
+        numa::Topology,
+        partitioned::PartitionedHashJoin,
+        prefetch::Prefetch,
+        radix::RadixHashJoin,
+        sequential::SequentialHashJoin,
+        shared::{SharedHashJoin, SharedTable},
+        sort_merge::SortMergeJoin,
+        BucketSizing, HashJoinBenchmark, Instrumentation, RunResult, DYNAMIC_SCHEDULING,
+        STATIC_SCHEDULING,
+    },
+    perf::CounterValues,
+    spill::SpillStats,
+    stats::Summary,
+    tuple::{DataChunk, Distribution},
+    work::worker_imbalance,
+};
+
+/// Hash join algorithms that can be benchmarked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+    Sequential,
+    Shared,
+    Partitioned,
+    /// Partitioned join that spills partitions to disk beyond the memory limit.
+    Grace,
+    /// Sort-merge join, as a baseline for the hash joins.
+    SortMerge,
+    /// Multi-pass radix partitioning with bucket-chained tables per partition.
+    Radix,
+    /// Private table per thread, probing all tables.
+    IndependentBroadcast,
+    /// Private table per thread, probing the table that holds the key.
+    IndependentRouted,
+}
+
+impl Algorithm {
+    /// Whether the algorithm partitions its input, i.e. depends on the partition number.
+    pub fn uses_partitions(self) -> bool {
+        matches!(
+            self,
+            Algorithm::Partitioned | Algorithm::Grace | Algorithm::Radix
+        )
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Algorithm::Sequential => write!(f, "Sequential"),
+            Algorithm::Shared => write!(f, "Shared"),
+            Algorithm::Partitioned => write!(f, "Partitioned"),
+            Algorithm::Grace => write!(f, "Grace"),
+            Algorithm::SortMerge => write!(f, "Sort-Merge"),
+            Algorithm::Radix => write!(f, "Radix"),
+            Algorithm::IndependentBroadcast => write!(f, "Independent (Broadcast)"),
+            Algorithm::IndependentRouted => write!(f, "Independent (Routed)"),
+        }
+    }
+}
+
+/// How work is assigned to threads in the parallel algorithms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scheduling {
+    Static,
+    Dynamic,
+}
+
+impl fmt::Display for Scheduling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scheduling::Static => write!(f, "Static"),
+            Scheduling::Dynamic => write!(f, "Dynamic"),
+        }
+    }
+}
+
+/// Parameters of the hash tables and partitions shared by all algorithms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct JoinParams {
+    /// How many buckets the hash tables get.
+    pub buckets: BucketSizing,
+    /// The hash table of the shared algorithm.
+    pub shared_table: SharedTable,
+    pub partition_num: usize,
+    /// Memory limit in bytes of the algorithms that spill to disk. Unlimited if `None`.
+    pub memory_limit: Option<usize>,
+    /// Prefetching in the probe phase of the sequential and shared algorithms.
+    pub prefetch: Prefetch,
+    /// Bits per key of the Bloom filters built alongside the hash tables. No filters if
+    /// `None`.
+    pub bloom_bits_per_key: Option<usize>,
+    /// Let the sequential and shared algorithms build a direct-addressed array instead
+    /// of a hash table if the inner keys are dense.
+    pub dense_tables: bool,
+    /// Place the partitions of the partitioned algorithm on NUMA nodes, if the machine
+    /// has several.
+    pub numa: bool,
+}
+
+/// A workload and the algorithm to run on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Experiment {
+    pub distribution: Distribution,
+    pub algorithm: Algorithm,
+    /// `None` for the sequential algorithm.
+    pub scheduling: Option<Scheduling>,
+}
+
+impl Experiment {
+    pub fn new(
+        distribution: Distribution,
+        algorithm: Algorithm,
+        scheduling: Option<Scheduling>,
+    ) -> Self {
+        Self {
+            distribution,
+            algorithm,
+            scheduling,
+        }
+    }
+
+    /// The cartesian product of the given distributions, algorithms and scheduling methods,
+    /// without duplicates. The sequential algorithm ignores the scheduling method.
+    pub fn product(
+        distributions: &[Distribution],
+        algorithms: &[Algorithm],
+        schedulings: &[Scheduling],
+    ) -> Vec<Self> {
+        let mut experiments = Vec::new();
+        for &distribution in distributions {
+            for &algorithm in algorithms {
+                let schedulings = match algorithm {
+                    Algorithm::Sequential => vec![None],
+                    _ => schedulings.iter().copied().map(Some).collect(),
+                };
+                for scheduling in schedulings {
+                    let experiment = Self::new(distribution, algorithm, scheduling);
+                    if !experiments.contains(&experiment) {
+                        experiments.push(experiment);
+                    }
+                }
+            }
+        }
+        experiments
+    }
+
+    /// Run the algorithm once on the given input in the current rayon thread pool.
+    pub fn run(
+        &self,
+        params: &JoinParams,
+        instrumentation: &Instrumentation,
+        inner: Vec<DataChunk>,
+        outer: Vec<DataChunk>,
+    ) -> RunResult {
+        let JoinParams {
+            buckets,
+            shared_table,
+            partition_num,
+            memory_limit,
+            prefetch,
+            bloom_bits_per_key: bloom,
+            dense_tables,
+            numa,
+        } = *params;
+        let memory_limit = memory_limit.unwrap_or(usize::MAX);
+        match (self.algorithm, self.scheduling) {
+            (Algorithm::Sequential, _) => {
+                SequentialHashJoin::new(buckets, prefetch, bloom, dense_tables, inner, outer)
+                    .run(instrumentation)
+            }
+            (Algorithm::Shared, Some(Scheduling::Static)) => {
+                SharedHashJoin::<STATIC_SCHEDULING>::new(
+                    buckets,
+                    shared_table,
+                    prefetch,
+                    bloom,
+                    dense_tables,
+                    inner,
+                    outer,
+                )
+                .run(instrumentation)
+            }
+            (Algorithm::Shared, _) => SharedHashJoin::<DYNAMIC_SCHEDULING>::new(
+                buckets,
+                shared_table,
+                prefetch,
+                bloom,
+                dense_tables,
+                inner,
+                outer,
+            )
+            .run(instrumentation),
+            (Algorithm::Partitioned, Some(Scheduling::Static)) => {
+                PartitionedHashJoin::<STATIC_SCHEDULING>::new(
+                    buckets,
+                    partition_num,
+                    bloom,
+                    numa.then(Topology::detect).flatten(),
+                    inner,
+                    outer,
+                )
+                .run(instrumentation)
+            }
+            (Algorithm::Partitioned, _) => PartitionedHashJoin::<DYNAMIC_SCHEDULING>::new(
+                buckets,
+                partition_num,
+                bloom,
+                numa.then(Topology::detect).flatten(),
+                inner,
+                outer,
+            )
+            .run(instrumentation),
+            (Algorithm::Grace, Some(Scheduling::Static)) => {
+                GraceHashJoin::<STATIC_SCHEDULING>::new(
+                    buckets,
+                    partition_num,
+                    memory_limit,
+                    bloom,
+                    inner,
+                    outer,
+                )
+                .run(instrumentation)
+            }
+            (Algorithm::Grace, _) => GraceHashJoin::<DYNAMIC_SCHEDULING>::new(
+                buckets,
+                partition_num,
+                memory_limit,
+                bloom,
+                inner,
+                outer,
+            )
+            .run(instrumentation),
+            (Algorithm::SortMerge, Some(Scheduling::Static)) => {
+                SortMergeJoin::<STATIC_SCHEDULING>::new(inner, outer).run(instrumentation)
+            }
+            (Algorithm::SortMerge, _) => {
+                SortMergeJoin::<DYNAMIC_SCHEDULING>::new(inner, outer).run(instrumentation)
+            }
+            (Algorithm::Radix, Some(Scheduling::Static)) => {
+                RadixHashJoin::<STATIC_SCHEDULING>::new(buckets, partition_num, bloom, inner, outer)
+                    .run(instrumentation)
+            }
+            (Algorithm::Radix, _) => RadixHashJoin::<DYNAMIC_SCHEDULING>::new(
+                buckets,
+                partition_num,
+                bloom,
+                inner,
+                outer,
+            )
+            .run(instrumentation),
+            (Algorithm::IndependentBroadcast, Some(Scheduling::Static)) => {
+                IndependentHashJoin::<STATIC_SCHEDULING, BROADCAST_PROBE>::new(
+                    buckets, bloom, inner, outer,
+                )
+                .run(instrumentation)
+            }
+            (Algorithm::IndependentBroadcast, _) => IndependentHashJoin::<
+                DYNAMIC_SCHEDULING,
+                BROADCAST_PROBE,
+            >::new(buckets, bloom, inner, outer)
+            .run(instrumentation),
+            (Algorithm::IndependentRouted, Some(Scheduling::Static)) => {
+                IndependentHashJoin::<STATIC_SCHEDULING, ROUTED_PROBE>::new(
+                    buckets, bloom, inner, outer,
+                )
+                .run(instrumentation)
+            }
+            (Algorithm::IndependentRouted, _) => IndependentHashJoin::<
+                DYNAMIC_SCHEDULING,
+                ROUTED_PROBE,
+            >::new(buckets, bloom, inner, outer)
+            .run(instrumentation),
+        }
+    }
+}
+
+impl Experiment {
+    /// Run the experiment `warmup + repetitions` times on fresh copies of the input and
+    /// return the results of the measured runs.
+    pub fn run_repeated(
+        &self,
+        params: &JoinParams,
+        instrumentation: &Instrumentation,
+        inner: &[DataChunk],
+        outer: &[DataChunk],
+        warmup: usize,
+        repetitions: usize,
+    ) -> Vec<RunResult> {
+        let mut results = Vec::with_capacity(repetitions);
+        for i in 0..warmup + repetitions {
+            let result = self.run(params, instrumentation, inner.to_vec(), outer.to_vec());
+            if i >= warmup {
+                results.push(result);
+            }
+        }
+        results
+    }
+}
+
+/// Print how much longer each phase took on average with memory from the system
+/// allocator than with memory from the pre-faulted pool.
+pub fn print_allocation_cost(pooled: &[RunResult], system: &[RunResult]) {
+    let mean_ms = |results: &[RunResult], elapsed: &dyn Fn(&RunResult) -> Duration| {
+        results
+            .iter()
+            .map(|result| elapsed(result).as_secs_f64() * 1000.0)
+            .sum::<f64>()
+            / results.len() as f64
+    };
+    let cost_ms = |elapsed: &dyn Fn(&RunResult) -> Duration| {
+        mean_ms(system, elapsed) - mean_ms(pooled, elapsed)
+    };
+    let Some(first) = pooled.first() else {
+        return;
+    };
+    let mut costs = first
+        .phases()
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| {
+            let phase_ms = cost_ms(&|result| result.phases()[i].1.elapsed);
+            format!("{} {:.3}ms", name, phase_ms)
+        })
+        .collect::<Vec<_>>();
+    let total_ms = cost_ms(&RunResult::total);
+    costs.push(format!("total {:.3}ms", total_ms));
+    println!("allocation cost: {}", costs.join(", "));
+}
+
+/// Print the phase times of a single run, or summary statistics of repeated runs.
+/// Performance counters, worker imbalance, memory, spilling and Bloom filter tests, if any,
+/// are averaged over the runs.
+pub fn print_results(results: &[RunResult]) {
+    let print_counters = |i: usize| {
+        let mut mean = CounterValues::default();
+        for result in results {
+            let Some(counters) = &result.phases()[i].1.counters else {
+                return;
+            };
+            for &(name, value) in &counters.values {
+                match mean.values.iter_mut().find(|(n, _)| *n == name) {
+                    Some((_, sum)) => *sum += value,
+                    None => mean.values.push((name, value)),
+                }
+            }
+        }
+        mean.values
+            .iter_mut()
+            .for_each(|(_, sum)| *sum /= results.len() as u64);
+        println!("  {}", mean.display_per_tuple(results[0].output.tuple_num));
+    };
+
+    // Imbalance is averaged over the runs, per-worker work is only printed for a single run.
+    let print_workers = |i: usize| {
+        let Some(runs) = results
+            .iter()
+            .map(|result| result.phases()[i].1.workers.as_deref())
+            .collect::<Option<Vec<_>>>()
+        else {
+            return;
+        };
+        let (busy, tuples) = runs.iter().fold((0.0, 0.0), |(busy, tuples), workers| {
+            let (b, t) = worker_imbalance(workers);
+            (busy + b, tuples + t)
+        });
+        let idle = runs
+            .iter()
+            .flat_map(|workers| workers.iter().map(|worker| worker.idle))
+            .sum::<Duration>()
+            / runs.iter().map(|workers| workers.len()).sum::<usize>() as u32;
+        println!(
+            "  imbalance (max/mean): busy {:.3}, tuples {:.3}, mean idle {:?}",
+            busy / runs.len() as f64,
+            tuples / runs.len() as f64,
+            idle
+        );
+        if let [workers] = runs.as_slice() {
+            for (worker, stats) in workers.iter().enumerate() {
+                println!(
+                    "    worker {}: {} tuples, busy {:?}, idle {:?}",
+                    worker, stats.tuples, stats.busy, stats.idle
+                );
+            }
+        }
+    };
+
+    let print_memory = |i: usize| {
+        let Some(runs) = results
+            .iter()
+            .map(|result| result.phases()[i].1.memory)
+            .collect::<Option<Vec<_>>>()
+        else {
+            return;
+        };
+        let n = runs.len();
+        let mean = MemoryStats {
+            current: runs.iter().map(|m| m.current).sum::<i64>() / n as i64,
+            peak: runs.iter().map(|m| m.peak).sum::<i64>() / n as i64,
+            allocations: runs.iter().map(|m| m.allocations).sum::<u64>() / n as u64,
+            allocated_bytes: runs.iter().map(|m| m.allocated_bytes).sum::<u64>() / n as u64,
+        };
+        println!("  {}", mean);
+    };
+
+    let print_spill = |i: usize| {
+        let spills = results
+            .iter()
+            .filter_map(|result| result.phases()[i].1.spill)
+            .collect::<Vec<_>>();
+        if spills.is_empty() {
+            return;
+        }
+        let n = results.len();
+        let mean = SpillStats {
+            bytes_written: spills.iter().map(|s| s.bytes_written).sum::<u64>() / n as u64,
+            bytes_read: spills.iter().map(|s| s.bytes_read).sum::<u64>() / n as u64,
+            io_time: spills.iter().map(|s| s.io_time).sum::<Duration>() / n as u32,
+        };
+        println!("  {}", mean);
+    };
+
+    let print_table = || {
+        let Some(table) = results[0].table else {
+            return;
+        };
+        println!("table: {}", table);
+    };
+
+    let print_filter = |i: usize| {
+        let filters = results
+            .iter()
+            .filter_map(|result| result.phases()[i].1.filter)
+            .collect::<Vec<_>>();
+        if filters.is_empty() {
+            return;
+        }
+        let n = results.len() as u64;
+        let mean = FilterStats {
+            tested: filters.iter().map(|f| f.tested).sum::<u64>() / n,
+            filtered: filters.iter().map(|f| f.filtered).sum::<u64>() / n,
+        };
+        println!("  {}", mean);
+    };
+
+    print_table();
+    if let [result] = results {
+        for (i, (name, phase)) in result.phases().into_iter().enumerate() {
+            println!("{}: {:?}", name, phase.elapsed);
+            print_counters(i);
+            print_workers(i);
+            print_memory(i);
+            print_spill(i);
+            print_filter(i);
+        }
+        return;
+    }
+    println!("{} runs", results.len());
+    for (i, (name, _)) in results[0].phases().into_iter().enumerate() {
+        let summary = Summary::from_durations(results.iter().map(|r| r.phases()[i].1.elapsed));
+        println!("{}: {}", name, summary.unwrap());
+        print_counters(i);
+        print_workers(i);
+        print_memory(i);
+        print_spill(i);
+        print_filter(i);
+    }
+    let summary = Summary::from_durations(results.iter().map(RunResult::total));
+    println!("total: {}", summary.unwrap());
+}
+
+/// Formats as e.g. "Uniform + Shared + Dynamic".
+impl fmt::Display for Experiment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} + {}", self.distribution, self.algorithm)?;
+        if let Some(scheduling) = self.scheduling {
+            write!(f, " + {}", scheduling)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_product() {
+        let experiments = Experiment::product(
+            &[Distribution::Uniform, Distribution::HighSkew],
+            &[Algorithm::Sequential, Algorithm::Shared, Algorithm::Shared],
+            &[Scheduling::Dynamic, Scheduling::Static],
+        );
+        let names = experiments
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "Uniform + Sequential",
+                "Uniform + Shared + Dynamic",
+                "Uniform + Shared + Static",
+                "High Skew + Sequential",
+                "High Skew + Shared + Dynamic",
+                "High Skew + Shared + Static",
+            ]
+        );
+    }
+}
@@ -1,37 +1,403 @@
+pub mod bloom;
+pub mod grace;
 mod hash_bucket;
 mod hash_table;
+pub mod independent;
+pub mod numa;
 pub mod partitioned;
+pub mod prefetch;
+pub mod radix;
 pub mod sequential;
 pub mod shared;
+mod simd;
+pub mod sort_merge;
 
-use crate::tuple::{Key, Tuple};
+use std::{fmt, hash::Hash, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Result};
+
+use rayon::{
+    iter::{IntoParallelRefIterator, ParallelIterator},
+    slice::ParallelSlice,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alloc::{self, MemoryStats},
+    perf::{CounterValues, PerfCounters},
+    spill::{self, SpillStats},
+    tuple::{DataChunk, Key, Tuple},
+    work::{self, WorkTracker, WorkerStats},
+};
 
 pub type SchedulingType = bool;
 pub const STATIC_SCHEDULING: SchedulingType = true;
 pub const DYNAMIC_SCHEDULING: SchedulingType = false;
 
-fn time_phase<T>(name: &str, f: impl FnOnce() -> T) -> T {
+/// Optional measurements taken around each phase in addition to wall time.
+#[derive(Default)]
+pub struct Instrumentation {
+    pub perf: Option<PerfCounters>,
+    /// Account the tuples and busy time of each worker.
+    pub work: bool,
+    /// Count allocated bytes. Requires `CountingAllocator` to be the global allocator
+    /// and tracking to be enabled.
+    pub memory: bool,
+}
+
+fn time_phase<T>(
+    instrumentation: &Instrumentation,
+    f: impl FnOnce() -> T,
+) -> (T, PhaseMeasurement) {
+    if let Some(perf) = &instrumentation.perf {
+        perf.start();
+    }
+    if instrumentation.work {
+        work::start_phase();
+    }
+    if instrumentation.memory {
+        alloc::start_phase();
+    }
+    spill::start_phase();
+    bloom::start_phase();
     let start = std::time::Instant::now();
     let ret = f();
     let elapsed = start.elapsed();
-    println!("{}: {:?}", name, elapsed);
-    ret
+    let counters = instrumentation.perf.as_ref().map(PerfCounters::stop);
+    let memory = instrumentation.memory.then(alloc::finish_phase);
+    let spill = spill::finish_phase();
+    let filter = bloom::finish_phase();
+    let workers = instrumentation
+        .work
+        .then(|| work::finish_phase(rayon::current_num_threads(), elapsed))
+        .flatten();
+    (
+        ret,
+        PhaseMeasurement {
+            elapsed,
+            counters,
+            workers,
+            memory,
+            spill,
+            filter,
+        },
+    )
+}
+
+/// Measurements of a single phase.
+#[derive(Debug, Clone, Default)]
+pub struct PhaseMeasurement {
+    pub elapsed: Duration,
+    pub counters: Option<CounterValues>,
+    /// Work of each worker, if work accounting is enabled and the phase is parallel.
+    pub workers: Option<Vec<WorkerStats>>,
+    pub memory: Option<MemoryStats>,
+    /// Spilling to disk, if the algorithm spilled in the phase.
+    pub spill: Option<SpillStats>,
+    /// Outer tuples tested against Bloom filters, if the algorithm used any in the phase.
+    pub filter: Option<bloom::FilterStats>,
+}
+
+/// Summary of the tuples produced by the probe phase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JoinOutput {
+    pub tuple_num: usize,
+    /// Order-independent checksum of the produced tuples. Two joins produce the same
+    /// multiset of tuples if, with high probability, their checksums are equal.
+    pub checksum: u64,
+}
+
+impl JoinOutput {
+    /// Record a produced tuple.
+    pub fn push(&mut self, tuple: &Tuple) {
+        self.tuple_num += 1;
+        self.checksum = self.checksum.wrapping_add(mix(tuple.key()));
+    }
+}
+
+/// The splitmix64 finalizer. Summing mixed keys instead of raw keys makes it unlikely
+/// that different multisets have the same checksum.
+fn mix(key: Key) -> u64 {
+    let mut x = key.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+impl std::ops::AddAssign for JoinOutput {
+    fn add_assign(&mut self, other: Self) {
+        self.tuple_num += other.tuple_num;
+        self.checksum = self.checksum.wrapping_add(other.checksum);
+    }
+}
+
+impl std::iter::Sum for JoinOutput {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |mut acc, output| {
+            acc += output;
+            acc
+        })
+    }
+}
+
+/// The kind of table an algorithm built, for the algorithms that choose one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TableKind {
+    Hash,
+    /// A direct-addressed array of the inner keys, which were dense.
+    Dense,
+}
+
+impl fmt::Display for TableKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableKind::Hash => write!(f, "hash table"),
+            TableKind::Dense => write!(f, "dense array"),
+        }
+    }
+}
+
+/// Measurements of a single run of a benchmark.
+#[derive(Debug, Clone, Default)]
+pub struct RunResult {
+    pub partition: PhaseMeasurement,
+    pub build: PhaseMeasurement,
+    pub probe: PhaseMeasurement,
+    pub output: JoinOutput,
+    pub table: Option<TableKind>,
+}
+
+impl RunResult {
+    pub fn phases(&self) -> [(&'static str, &PhaseMeasurement); 3] {
+        [
+            ("partition", &self.partition),
+            ("build", &self.build),
+            ("probe", &self.probe),
+        ]
+    }
+
+    pub fn total(&self) -> Duration {
+        self.partition.elapsed + self.build.elapsed + self.probe.elapsed
+    }
+
+    /// Spilling summed over all phases, if any phase spilled.
+    pub fn spill(&self) -> Option<SpillStats> {
+        self.phases()
+            .into_iter()
+            .filter_map(|(_, phase)| phase.spill)
+            .reduce(|a, b| SpillStats {
+                bytes_written: a.bytes_written + b.bytes_written,
+                bytes_read: a.bytes_read + b.bytes_read,
+                io_time: a.io_time + b.io_time,
+            })
+    }
+
+    /// Bloom filter tests summed over all phases, if any phase tested tuples.
+    pub fn filter(&self) -> Option<bloom::FilterStats> {
+        self.phases()
+            .into_iter()
+            .filter_map(|(_, phase)| phase.filter)
+            .reduce(|a, b| bloom::FilterStats {
+                tested: a.tested + b.tested,
+                filtered: a.filtered + b.filtered,
+            })
+    }
 }
 
 pub type NoOutput = ();
 
+/// Size of the chunks that split `len` items among `num_threads` threads in static
+/// scheduling. At least 1, so that inputs smaller than the thread number still work.
+fn thread_chunk_size(len: usize, num_threads: usize) -> usize {
+    len.div_ceil(num_threads).max(1)
+}
+
+/// Average number of tuples per bucket that hash tables are sized for. Always positive
+/// and finite.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadFactor(f64);
+
+impl LoadFactor {
+    pub fn new(load_factor: f64) -> Result<Self> {
+        if !(load_factor.is_finite() && load_factor > 0.0) {
+            return Err(anyhow!("load factor must be positive: {}", load_factor));
+        }
+        Ok(Self(load_factor))
+    }
+
+    pub fn get(self) -> f64 {
+        self.0
+    }
+}
+
+impl Default for LoadFactor {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
+// Load factors are never NaN.
+impl Eq for LoadFactor {}
+
+impl Hash for LoadFactor {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+impl FromStr for LoadFactor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::new(s.trim().parse()?)
+    }
+}
+
+impl fmt::Display for LoadFactor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How many buckets the hash tables of a join get.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BucketSizing {
+    /// A total number of buckets, split evenly among the tables.
+    Fixed(usize),
+    /// Each table gets a power of 2 buckets, enough to hold its inner tuples at the load
+    /// factor.
+    LoadFactor(LoadFactor),
+}
+
+impl Default for BucketSizing {
+    fn default() -> Self {
+        BucketSizing::LoadFactor(LoadFactor::default())
+    }
+}
+
+impl BucketSizing {
+    /// The buckets of a table of `tuple_num` inner tuples that is one of `table_num`
+    /// tables. Always a power of 2.
+    pub fn bucket_num(self, tuple_num: usize, table_num: usize) -> usize {
+        match self {
+            BucketSizing::Fixed(bucket_num) => (bucket_num / table_num).max(1).next_power_of_two(),
+            BucketSizing::LoadFactor(load_factor) => {
+                ((tuple_num as f64 / load_factor.get()).ceil() as usize).next_power_of_two()
+            }
+        }
+    }
+
+    /// The total number of buckets, if it is fixed.
+    pub fn fixed(self) -> Option<usize> {
+        match self {
+            BucketSizing::Fixed(bucket_num) => Some(bucket_num),
+            BucketSizing::LoadFactor(_) => None,
+        }
+    }
+
+    pub fn load_factor(self) -> Option<LoadFactor> {
+        match self {
+            BucketSizing::Fixed(_) => None,
+            BucketSizing::LoadFactor(load_factor) => Some(load_factor),
+        }
+    }
+}
+
+impl fmt::Display for BucketSizing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BucketSizing::Fixed(bucket_num) => write!(f, "{} buckets", bucket_num),
+            BucketSizing::LoadFactor(load_factor) => write!(f, "load factor {}", load_factor),
+        }
+    }
+}
+
+/// Call `f` on every tuple of `chunks` in parallel, one chunk after another. With static
+/// scheduling, each chunk is divided into equal parts for each thread.
+fn for_each_tuple<const S: SchedulingType>(chunks: &[DataChunk], f: impl Fn(&Tuple) + Sync) {
+    for chunk in chunks {
+        if S == STATIC_SCHEDULING {
+            let num_threads = rayon::current_num_threads();
+            let thread_chunk_size = thread_chunk_size(chunk.len(), num_threads);
+            rayon::scope(|s| {
+                for chunk in chunk.chunks(thread_chunk_size) {
+                    let f = &f;
+                    s.spawn(move |_| {
+                        let mut work = WorkTracker::start();
+                        chunk.iter().for_each(f);
+                        work.add(chunk.len());
+                    });
+                }
+            });
+        } else {
+            chunk
+                .par_iter()
+                .for_each_init(WorkTracker::start, |work, tuple| {
+                    f(tuple);
+                    work.add(1);
+                });
+        }
+    }
+}
+
+/// Inner tuples a task takes at once in `for_each_batch` with dynamic scheduling.
+const BUILD_MORSEL_SIZE: usize = 1024;
+
+/// Like `for_each_tuple`, but call `f` on each thread's part of a chunk with static
+/// scheduling, and on morsels of `BUILD_MORSEL_SIZE` tuples with dynamic scheduling.
+fn for_each_batch<const S: SchedulingType>(chunks: &[DataChunk], f: impl Fn(&[Tuple]) + Sync) {
+    for chunk in chunks {
+        if S == STATIC_SCHEDULING {
+            let num_threads = rayon::current_num_threads();
+            let thread_chunk_size = thread_chunk_size(chunk.len(), num_threads);
+            rayon::scope(|s| {
+                for chunk in chunk.chunks(thread_chunk_size) {
+                    let f = &f;
+                    s.spawn(move |_| {
+                        let mut work = WorkTracker::start();
+                        f(chunk);
+                        work.add(chunk.len());
+                    });
+                }
+            });
+        } else {
+            chunk.par_chunks(BUILD_MORSEL_SIZE).for_each_init(
+                WorkTracker::start,
+                |work, morsel| {
+                    f(morsel);
+                    work.add(morsel.len());
+                },
+            );
+        }
+    }
+}
+
 pub trait HashJoinBenchmark {
     type PartitionOutput;
     type BuildOutput;
 
     fn partition(&mut self) -> Self::PartitionOutput;
     fn build(&mut self, partition_output: Self::PartitionOutput) -> Self::BuildOutput;
-    fn probe(&mut self, build_output: Self::BuildOutput);
+    fn probe(&mut self, build_output: Self::BuildOutput) -> JoinOutput;
+
+    /// The kind of table the build phase chose, if the algorithm chooses one.
+    fn table_kind(&self) -> Option<TableKind> {
+        None
+    }
 
-    fn run(&mut self) {
-        let partition_output = time_phase("partition", || self.partition());
-        let build_output = time_phase("build", || self.build(partition_output));
-        time_phase("probe", || self.probe(build_output));
+    /// Run all phases once. The benchmark consumes its input, so it can only be run once.
+    fn run(&mut self, instrumentation: &Instrumentation) -> RunResult {
+        let (partition_output, partition) = time_phase(instrumentation, || self.partition());
+        let (build_output, build) = time_phase(instrumentation, || self.build(partition_output));
+        let (output, probe) = time_phase(instrumentation, || self.probe(build_output));
+        RunResult {
+            partition,
+            build,
+            probe,
+            output,
+            table: self.table_kind(),
+        }
     }
 
     /// Clone the tuple to simulate outputting it.
@@ -63,11 +429,46 @@ pub trait HashJoinBenchmark {
 ///
 /// 1. The key may not always be u64 in real life.
 /// 2. The overhead of hash function is significant.
+///
+/// Probe loops hash batches of keys with `simd::bucket_indices` instead, which gives the
+/// same hashes.
 pub fn bucket_hash(key: Key) -> u64 {
-    xxhash_rust::xxh3::xxh3_64_with_seed(&key.to_le_bytes(), 821)
+    xxhash_rust::xxh3::xxh3_64_with_seed(&key.to_le_bytes(), BUCKET_SEED)
 }
 
+const BUCKET_SEED: u64 = 821;
+
 /// Hash a key to partition index.
 pub fn partition_hash(key: Key) -> u64 {
-    xxhash_rust::xxh3::xxh3_64_with_seed(&key.to_le_bytes(), 804)
+    partition_hash_with_seed(key, PARTITION_SEED)
+}
+
+pub(crate) const PARTITION_SEED: u64 = 804;
+
+/// Hash a key to partition index with a different seed, to split a partition that
+/// `partition_hash` put all its keys into. Seed `PARTITION_SEED` is `partition_hash`.
+pub fn partition_hash_with_seed(key: Key, seed: u64) -> u64 {
+    xxhash_rust::xxh3::xxh3_64_with_seed(&key.to_le_bytes(), seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_sizing() {
+        let fixed = BucketSizing::Fixed(1024);
+        assert_eq!(fixed.bucket_num(10, 1), 1024);
+        assert_eq!(fixed.bucket_num(10, 16), 64);
+        assert_eq!(fixed.bucket_num(10, 4096), 1);
+
+        let load_factor = |lf: &str| BucketSizing::LoadFactor(lf.parse().unwrap());
+        assert_eq!(load_factor("1").bucket_num(1000, 16), 1024);
+        assert_eq!(load_factor("4").bucket_num(1000, 1), 256);
+        assert_eq!(load_factor("0.5").bucket_num(1000, 1), 2048);
+        assert_eq!(load_factor("1").bucket_num(0, 1), 1);
+        for invalid in ["0", "-1", "inf", "NaN", "x"] {
+            assert!(invalid.parse::<LoadFactor>().is_err());
+        }
+    }
 }
@@ -0,0 +1,253 @@
+//! Bloom filters that let the probe phase skip outer tuples without a match.
+//!
+//! The filters are register-blocked: each key sets and tests a few bits of a single
+//! 64-bit word, so that a test is one memory access instead of one per bit. This has a
+//! higher false positive rate than a standard Bloom filter with the same bits per key,
+//! but is much cheaper when most tests fail. The tested and filtered tuples of all
+//! threads are accumulated per phase.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::tuple::{Key, Tuple};
+
+use super::prefetch::PROBE_MORSEL_SIZE;
+
+const BLOOM_SEED: u64 = 907;
+/// Bits of a word set by a key. Each bit position takes 6 bits of the low half of the hash.
+const MAX_HASH_NUM: u32 = 8;
+
+static TESTED: AtomicU64 = AtomicU64::new(0);
+static FILTERED: AtomicU64 = AtomicU64::new(0);
+
+pub struct BloomFilter {
+    words: Vec<AtomicU64>,
+    /// Bits set in a word by each key.
+    hash_num: u32,
+}
+
+impl BloomFilter {
+    /// A filter for `key_num` keys with `bits_per_key` bits for each key, rounded up to
+    /// whole words.
+    pub fn new(key_num: usize, bits_per_key: usize) -> Self {
+        let word_num = (key_num * bits_per_key).div_ceil(64).max(1);
+        // The optimal number of hash functions of a standard Bloom filter.
+        let hash_num = (bits_per_key as f64 * std::f64::consts::LN_2).round() as u32;
+        Self {
+            words: (0..word_num).map(|_| AtomicU64::new(0)).collect(),
+            hash_num: hash_num.clamp(1, MAX_HASH_NUM),
+        }
+    }
+
+    /// The word of the key and the bits the key sets in it.
+    fn word_and_mask(&self, key: Key) -> (usize, u64) {
+        let hash = xxhash_rust::xxh3::xxh3_128_with_seed(&key.to_le_bytes(), BLOOM_SEED);
+        // Map the high half to a word without requiring a power of 2 words, and take the
+        // bit positions from the low half, so that they are independent of the word
+        // however many words there are.
+        let (high, low) = ((hash >> 64) as u64, hash as u64);
+        let word = ((high as u128 * self.words.len() as u128) >> 64) as usize;
+        let mask = (0..self.hash_num).fold(0, |mask, i| mask | 1 << ((low >> (6 * i)) & 63));
+        (word, mask)
+    }
+
+    pub fn insert(&mut self, key: Key) {
+        let (word, mask) = self.word_and_mask(key);
+        *self.words[word].get_mut() |= mask;
+    }
+
+    /// Insert a key while other threads may insert into the filter too.
+    pub fn insert_concurrent(&self, key: Key) {
+        let (word, mask) = self.word_and_mask(key);
+        self.words[word].fetch_or(mask, Ordering::Relaxed);
+    }
+
+    /// Whether the key may have been inserted. Never false for an inserted key.
+    pub fn contains(&self, key: Key) -> bool {
+        let (word, mask) = self.word_and_mask(key);
+        self.words[word].load(Ordering::Relaxed) & mask == mask
+    }
+}
+
+/// Build a filter from `key_num` keys if `bits_per_key` is given.
+pub(super) fn build<'a>(
+    bits_per_key: Option<usize>,
+    key_num: usize,
+    tuples: impl IntoIterator<Item = &'a Tuple>,
+) -> Option<BloomFilter> {
+    let mut filter = BloomFilter::new(key_num, bits_per_key?);
+    tuples
+        .into_iter()
+        .for_each(|tuple| filter.insert(tuple.key()));
+    Some(filter)
+}
+
+/// Tests outer tuples against an optional filter and adds the tested and filtered
+/// tuples to the phase counters when dropped. Without a filter, every tuple passes and
+/// nothing is counted.
+pub(super) struct ProbeFilter<'a> {
+    filter: Option<&'a BloomFilter>,
+    tested: u64,
+    filtered: u64,
+    /// The tuples of a batch that passed.
+    passed: Vec<Tuple>,
+}
+
+impl<'a> ProbeFilter<'a> {
+    pub fn new(filter: Option<&'a BloomFilter>) -> Self {
+        Self {
+            filter,
+            tested: 0,
+            filtered: 0,
+            passed: Vec::new(),
+        }
+    }
+
+    pub fn pass(&mut self, tuple: &Tuple) -> bool {
+        let Some(filter) = self.filter else {
+            return true;
+        };
+        self.tested += 1;
+        let pass = filter.contains(tuple.key());
+        self.filtered += u64::from(!pass);
+        pass
+    }
+
+    /// Call `probe` on batches of the tuples that pass, or on all tuples at once without
+    /// a filter.
+    pub fn probe(&mut self, tuples: &[Tuple], mut probe: impl FnMut(&[Tuple])) {
+        if self.filter.is_none() {
+            return probe(tuples);
+        }
+        let mut passed = std::mem::take(&mut self.passed);
+        for batch in tuples.chunks(PROBE_MORSEL_SIZE) {
+            passed.clear();
+            passed.extend(batch.iter().filter(|tuple| self.pass(tuple)).cloned());
+            probe(&passed);
+        }
+        self.passed = passed;
+    }
+}
+
+impl Drop for ProbeFilter<'_> {
+    fn drop(&mut self) {
+        if self.tested > 0 {
+            TESTED.fetch_add(self.tested, Ordering::Relaxed);
+            FILTERED.fetch_add(self.filtered, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Outer tuples tested against Bloom filters in a phase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FilterStats {
+    pub tested: u64,
+    /// Tuples that the filters ruled out, so that they were not probed.
+    pub filtered: u64,
+}
+
+/// Reset the counters at the start of a phase.
+pub fn start_phase() {
+    TESTED.store(0, Ordering::Relaxed);
+    FILTERED.store(0, Ordering::Relaxed);
+}
+
+/// The tuples tested since `start_phase`, or `None` if no tuple was tested.
+pub fn finish_phase() -> Option<FilterStats> {
+    let stats = FilterStats {
+        tested: TESTED.load(Ordering::Relaxed),
+        filtered: FILTERED.load(Ordering::Relaxed),
+    };
+    (stats.tested > 0).then_some(stats)
+}
+
+impl fmt::Display for FilterStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bloom filter: filtered {} of {} outer tuples ({:.1}%)",
+            self.filtered,
+            self.tested,
+            self.filtered as f64 / self.tested as f64 * 100.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_false_positive_rate() {
+        let keys = 0..10_000;
+        let mut filter = BloomFilter::new(10_000, 10);
+        keys.clone().for_each(|key| filter.insert(key));
+        assert!(keys.clone().all(|key| filter.contains(key)));
+        let false_positives = (10_000..20_000).filter(|&key| filter.contains(key)).count();
+        // A standard filter has about 1%, a register-blocked one a few times more.
+        assert!(false_positives < 500, "{} false positives", false_positives);
+    }
+
+    /// Filters with more words than fit in the low bits of a 64-bit hash keep their
+    /// false positive rate.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_false_positive_rate_of_large_filter() {
+        let key_num = 1 << 20;
+        let mut filter = BloomFilter::new(key_num, 8);
+        (0..key_num as Key).for_each(|key| filter.insert(key));
+        let false_positives = (key_num as Key..key_num as Key + 100_000)
+            .filter(|&key| filter.contains(key))
+            .count();
+        assert!(
+            false_positives < 5_000,
+            "{} false positives",
+            false_positives
+        );
+    }
+
+    #[test]
+    fn test_probe_filter() {
+        let tuples = (0..3000).map(Tuple::new).collect::<Vec<_>>();
+        let filter = build(Some(16), 1000, &tuples[..1000]);
+        let mut probe_filter = ProbeFilter::new(filter.as_ref());
+        let mut passed = vec![];
+        probe_filter.probe(&tuples, |batch| passed.extend_from_slice(batch));
+        assert!(passed.starts_with(&tuples[..1000]));
+        assert_eq!(probe_filter.tested, 3000);
+        assert_eq!(probe_filter.filtered, 3000 - passed.len() as u64);
+
+        let mut no_filter = ProbeFilter::new(None);
+        assert!(no_filter.pass(&tuples[2000]));
+        assert_eq!(no_filter.tested, 0);
+    }
+
+    proptest! {
+        /// Inserting concurrently or not sets the same bits, and every inserted key is
+        /// contained.
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_no_false_negatives(
+            keys in prop::collection::vec(any::<u64>(), 0..200),
+            bits_per_key in 1..20usize,
+        ) {
+            let mut filter = BloomFilter::new(keys.len(), bits_per_key);
+            let concurrent = BloomFilter::new(keys.len(), bits_per_key);
+            for &key in &keys {
+                filter.insert(key);
+                concurrent.insert_concurrent(key);
+            }
+            for &key in &keys {
+                prop_assert!(filter.contains(key));
+            }
+            let words = |filter: &BloomFilter| {
+                filter.words.iter().map(|w| w.load(Ordering::Relaxed)).collect::<Vec<_>>()
+            };
+            prop_assert_eq!(words(&filter), words(&concurrent));
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use std::io;
+
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefMutIterator, ParallelIterator,
+};
+
+use crate::{
+    spill::SpillFile,
+    tuple::{DataChunk, Tuple},
+    work::WorkTracker,
+};
+
+use super::{
+    bloom::{BloomFilter, ProbeFilter},
+    hash_table::sequential::SequentialHashTable,
+    partition_hash_with_seed,
+    partitioned::{partition_chunk, Partition, PartitionedHashJoin, ProbePartition},
+    prefetch::{self, Prefetch},
+    BucketSizing, HashJoinBenchmark, JoinOutput, SchedulingType, PARTITION_SEED,
+};
+
+/// Number of sub-partitions an oversized spilled partition is split into.
+const REPARTITION_FANOUT: usize = 8;
+/// Maximum number of times a spilled partition is re-partitioned. Partitions that are
+/// still too large, e.g. because all their tuples have the same key, are joined anyway.
+const MAX_REPARTITION_DEPTH: u64 = 4;
+
+/// A partition whose tuples were written to disk.
+#[derive(Default)]
+pub struct SpilledPartition {
+    inner: SpillFile,
+    outer: SpillFile,
+}
+
+/// Hybrid Grace hash join partitions like `PartitionedHashJoin`, but only keeps
+/// partitions in memory as long as their inner tuples fit in the memory limit. The other
+/// partitions are spilled to disk and joined one by one per thread after the in-memory
+/// ones. Spilled partitions that still do not fit are re-partitioned with a new seed.
+///
+/// The memory limit bounds the inner tuples that are kept in memory, which dominate the
+/// hash tables. It is applied after each chunk of inner tuples while partitioning, so at
+/// most one chunk of tuples of spilled partitions is buffered beyond it. Outer tuples of
+/// in-memory partitions are buffered as in `PartitionedHashJoin`, and each spill file
+/// has its own write buffer.
+pub struct GraceHashJoin<const S: SchedulingType> {
+    inner: Option<Vec<DataChunk>>,
+    outer: Option<Vec<DataChunk>>,
+    buckets: BucketSizing,
+    partition_num: usize,
+    memory_limit: usize,
+    bloom_bits_per_key: Option<usize>,
+    /// Builds and probes the in-memory partitions.
+    partitioned: PartitionedHashJoin<S>,
+}
+
+impl<const S: SchedulingType> GraceHashJoin<S> {
+    pub fn new(
+        buckets: BucketSizing,
+        partition_num: usize,
+        memory_limit: usize,
+        bloom_bits_per_key: Option<usize>,
+        inner: Vec<DataChunk>,
+        outer: Vec<DataChunk>,
+    ) -> Self {
+        Self {
+            inner: Some(inner),
+            outer: Some(outer),
+            buckets,
+            partition_num,
+            memory_limit,
+            bloom_bits_per_key,
+            partitioned: PartitionedHashJoin::new(
+                buckets,
+                partition_num,
+                bloom_bits_per_key,
+                None,
+                vec![],
+                vec![],
+            ),
+        }
+    }
+
+    /// Join a spilled partition whose tuples were partitioned with `seed`, splitting it
+    /// first if its inner tuples do not fit in `memory_limit`. Its hash table is sized by
+    /// `buckets` as one of `partition_num` tables.
+    fn join_spilled(
+        partition: SpilledPartition,
+        memory_limit: usize,
+        buckets: BucketSizing,
+        partition_num: usize,
+        bloom_bits_per_key: Option<usize>,
+        seed: u64,
+    ) -> io::Result<JoinOutput> {
+        let inner_num = partition.inner.tuple_num();
+        if inner_num * std::mem::size_of::<Tuple>() <= memory_limit
+            || seed - PARTITION_SEED >= MAX_REPARTITION_DEPTH
+        {
+            return Self::join_in_memory(partition, buckets, partition_num, bloom_bits_per_key);
+        }
+
+        let seed = seed + 1;
+        let sub_partition_idx = |tuple: &Tuple| {
+            partition_hash_with_seed(tuple.key(), seed) as usize & (REPARTITION_FANOUT - 1)
+        };
+        let mut sub_partitions = (0..REPARTITION_FANOUT)
+            .map(|_| SpilledPartition::default())
+            .collect::<Vec<_>>();
+        let mut inner = partition.inner.into_reader()?;
+        while let Some(block) = inner.next_block()? {
+            for tuple in &block {
+                sub_partitions[sub_partition_idx(tuple)].inner.push(tuple)?;
+            }
+        }
+        if let Some(idx) = sub_partitions
+            .iter()
+            .position(|p| p.inner.tuple_num() == inner_num)
+        {
+            // All inner tuples are in the same sub-partition, e.g. because they have the
+            // same key, so splitting cannot make progress.
+            let inner = sub_partitions.swap_remove(idx).inner;
+            return Self::join_in_memory(
+                SpilledPartition {
+                    inner,
+                    outer: partition.outer,
+                },
+                buckets,
+                partition_num,
+                bloom_bits_per_key,
+            );
+        }
+        let mut outer = partition.outer.into_reader()?;
+        while let Some(block) = outer.next_block()? {
+            for tuple in &block {
+                sub_partitions[sub_partition_idx(tuple)].outer.push(tuple)?;
+            }
+        }
+
+        let mut output = JoinOutput::default();
+        for sub_partition in sub_partitions {
+            if sub_partition.inner.tuple_num() > 0 && sub_partition.outer.tuple_num() > 0 {
+                output += Self::join_spilled(
+                    sub_partition,
+                    memory_limit,
+                    buckets,
+                    partition_num,
+                    bloom_bits_per_key,
+                    seed,
+                )?;
+            }
+        }
+        Ok(output)
+    }
+
+    /// Build a hash table, and a Bloom filter if `bloom_bits_per_key` is given, from the
+    /// inner tuples and probe it with the outer tuples, reading both from disk.
+    fn join_in_memory(
+        partition: SpilledPartition,
+        buckets: BucketSizing,
+        partition_num: usize,
+        bloom_bits_per_key: Option<usize>,
+    ) -> io::Result<JoinOutput> {
+        let bucket_num = buckets.bucket_num(partition.inner.tuple_num(), partition_num);
+        let mut hash_table = SequentialHashTable::<Vec<Tuple>>::new(bucket_num).unwrap();
+        let mut filter =
+            bloom_bits_per_key.map(|bits| BloomFilter::new(partition.inner.tuple_num(), bits));
+        let mut inner = partition.inner.into_reader()?;
+        while let Some(block) = inner.next_block()? {
+            for tuple in block {
+                if let Some(filter) = &mut filter {
+                    filter.insert(tuple.key());
+                }
+                hash_table.insert(tuple);
+            }
+        }
+
+        let mut output = JoinOutput::default();
+        let mut probe_filter = ProbeFilter::new(filter.as_ref());
+        let mut outer = partition.outer.into_reader()?;
+        while let Some(block) = outer.next_block()? {
+            probe_filter.probe(&block, |tuples| {
+                prefetch::probe(&hash_table, tuples, Prefetch::None, |tuple| {
+                    Self::produce_tuple(&tuple);
+                    output.push(&tuple);
+                })
+            });
+        }
+        Ok(output)
+    }
+}
+
+/// Spill the in-memory partitions from the first one whose inner tuples, together with
+/// those of the in-memory partitions before it, no longer fit in `memory_limit`.
+fn spill_over_limit(
+    partitions: &[Partition],
+    spills: &mut [Option<SpilledPartition>],
+    memory_limit: usize,
+) {
+    let mut resident_size = 0;
+    for (partition, spill) in partitions.iter().zip(spills) {
+        if spill.is_none() {
+            resident_size += partition.inner_buffer.count() * std::mem::size_of::<Tuple>();
+            if resident_size > memory_limit {
+                *spill = Some(SpilledPartition::default());
+            }
+        }
+    }
+}
+
+/// Move the tuples in the buffers selected by `buffer` of the spilled partitions to disk.
+fn spill_buffers(
+    partitions: &mut [Partition],
+    spills: &mut [Option<SpilledPartition>],
+    buffer: fn(&mut Partition) -> &mut boxcar::Vec<Tuple>,
+    file: fn(&mut SpilledPartition) -> &mut SpillFile,
+) {
+    partitions
+        .par_iter_mut()
+        .zip(spills.par_iter_mut())
+        .for_each_init(WorkTracker::start, |work, (partition, spill)| {
+            let Some(spill) = spill else {
+                return;
+            };
+            let tuples = std::mem::take(buffer(partition));
+            work.add(tuples.count());
+            for tuple in tuples.into_iter() {
+                file(spill).push(&tuple).expect("failed to spill tuple");
+            }
+        });
+}
+
+impl<const S: SchedulingType> HashJoinBenchmark for GraceHashJoin<S> {
+    type PartitionOutput = (Vec<Partition>, Vec<SpilledPartition>);
+    type BuildOutput = (Vec<ProbePartition>, Vec<SpilledPartition>);
+
+    fn partition(&mut self) -> Self::PartitionOutput {
+        let mut partitions = vec![Partition::new(); self.partition_num];
+        let mut spills = (0..self.partition_num).map(|_| None).collect::<Vec<_>>();
+
+        // Keep the partitions whose inner tuples fit in memory, and spill the others after
+        // each chunk, so that at most one chunk of them is in memory.
+        for chunk in self.inner.take().unwrap() {
+            partition_chunk::<S>(chunk, &partitions, |p| &p.inner_buffer);
+            spill_over_limit(&partitions, &mut spills, self.memory_limit);
+            spill_buffers(
+                &mut partitions,
+                &mut spills,
+                |p| &mut p.inner_buffer,
+                |s| &mut s.inner,
+            );
+        }
+
+        // Spill the outer tuples of spilled partitions after each chunk, so that at most
+        // one chunk of them is in memory.
+        for chunk in self.outer.take().unwrap() {
+            partition_chunk::<S>(chunk, &partitions, |p| &p.outer_buffer);
+            spill_buffers(
+                &mut partitions,
+                &mut spills,
+                |p| &mut p.outer_buffer,
+                |s| &mut s.outer,
+            );
+        }
+
+        // Write the rest of the spilled tuples, so that all of them are on disk at the end
+        // of the phase.
+        spills.par_iter_mut().flatten().for_each(|spill| {
+            spill.inner.flush().expect("failed to spill tuples");
+            spill.outer.flush().expect("failed to spill tuples");
+        });
+
+        let mut resident = Vec::new();
+        let mut spilled = Vec::new();
+        for (partition, spill) in partitions.into_iter().zip(spills) {
+            match spill {
+                Some(spill) => spilled.push(spill),
+                None => resident.push(partition),
+            }
+        }
+        (resident, spilled)
+    }
+
+    fn build(&mut self, (resident, spilled): Self::PartitionOutput) -> Self::BuildOutput {
+        (self.partitioned.build(resident), spilled)
+    }
+
+    fn probe(&mut self, (resident, spilled): Self::BuildOutput) -> JoinOutput {
+        let mut output = self.partitioned.probe(resident);
+        // Spilled partitions are always scheduled dynamically. Each thread joins one at a
+        // time, so each gets an equal share of the memory limit.
+        let memory_limit = self.memory_limit / rayon::current_num_threads();
+        output += spilled
+            .into_par_iter()
+            .map_init(WorkTracker::start, |work, partition| {
+                work.add(partition.outer.tuple_num());
+                Self::join_spilled(
+                    partition,
+                    memory_limit,
+                    self.buckets,
+                    self.partition_num,
+                    self.bloom_bits_per_key,
+                    PARTITION_SEED,
+                )
+                .expect("failed to join spilled partition")
+            })
+            .sum();
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        join_benchmark::DYNAMIC_SCHEDULING,
+        tuple::{Distribution, TupleGenerator},
+    };
+
+    /// The inner tuples of the in-memory partitions fit in the memory limit, and every
+    /// other inner tuple is on disk at the end of the partition phase.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_resident_partitions_fit_memory_limit() {
+        let (inner, outer) = TupleGenerator::new(2048, 2, 256).generate(Distribution::Uniform);
+        let memory_limit = 1024 * std::mem::size_of::<Tuple>();
+        let mut join = GraceHashJoin::<DYNAMIC_SCHEDULING>::new(
+            BucketSizing::Fixed(64),
+            16,
+            memory_limit,
+            None,
+            inner,
+            outer,
+        );
+        let (resident, spilled) = join.partition();
+        let resident_num = resident
+            .iter()
+            .map(|p| p.inner_buffer.count())
+            .sum::<usize>();
+        let spilled_num = spilled.iter().map(|p| p.inner.tuple_num()).sum::<usize>();
+        assert!(resident_num * std::mem::size_of::<Tuple>() <= memory_limit);
+        assert!(!spilled.is_empty());
+        assert_eq!(resident_num + spilled_num, 2048);
+    }
+}
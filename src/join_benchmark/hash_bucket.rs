@@ -1,6 +1,6 @@
 mod vec;
 
-use crate::tuple::Tuple;
+use crate::tuple::{Key, Tuple};
 
 /// A hash bucket supports pushing tuples and iterating over them.
 pub trait HashBucket: Default {
@@ -10,6 +10,11 @@ pub trait HashBucket: Default {
 
     fn push(&mut self, tuple: Tuple);
     fn iter(&self) -> Self::TupleIter<'_>;
+
+    /// Call `f` on every tuple with the given key.
+    fn for_each_match(&self, key: Key, mut f: impl FnMut(&Tuple)) {
+        self.iter().filter(|t| t.key_match(key)).for_each(&mut f);
+    }
 }
 
 // TODO: implement blocked linked list to see the effect of cache invalidation due to reallocation.
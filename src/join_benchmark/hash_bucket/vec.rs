@@ -1,4 +1,7 @@
-use crate::tuple::Tuple;
+use crate::{
+    join_benchmark::simd,
+    tuple::{Key, Tuple},
+};
 
 use super::HashBucket;
 
@@ -12,4 +15,8 @@ impl HashBucket for Vec<Tuple> {
     fn iter(&self) -> Self::TupleIter<'_> {
         self.as_slice().iter()
     }
+
+    fn for_each_match(&self, key: Key, f: impl FnMut(&Tuple)) {
+        simd::for_each_match(self, key, f);
+    }
 }
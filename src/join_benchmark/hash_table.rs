@@ -1,2 +1,64 @@
-pub(super) mod sequential;
+pub(super) mod bucket_chained;
 pub(super) mod concurrent;
+pub(super) mod dense;
+pub(super) mod growable;
+pub(super) mod lock_free;
+pub(super) mod sequential;
+
+/// Property test helpers that check a hash table against a `HashMap` model.
+#[cfg(test)]
+pub(super) mod model {
+    use std::collections::HashMap;
+
+    use proptest::prelude::*;
+
+    use crate::tuple::{Key, Tuple};
+
+    #[derive(Debug, Clone, Copy)]
+    pub enum Op {
+        Insert(Key),
+        Lookup(Key),
+    }
+
+    /// Mostly keys from a small range, so that keys repeat and share buckets.
+    pub fn keys() -> impl Strategy<Value = Key> {
+        prop_oneof![4 => 0..32u64, 1 => any::<u64>()]
+    }
+
+    pub fn ops() -> impl Strategy<Value = Vec<Op>> {
+        let op = prop_oneof![keys().prop_map(Op::Insert), keys().prop_map(Op::Lookup)];
+        prop::collection::vec(op, 0..200)
+    }
+
+    /// Powers of 2 from 1 to 64.
+    pub fn bucket_nums() -> impl Strategy<Value = usize> {
+        (0..7u32).prop_map(|exp| 1 << exp)
+    }
+
+    /// Apply `ops` to `table` and the model, and check that every lookup, and finally
+    /// every inserted key, returns the same tuples from both.
+    pub fn check_against_model<T>(
+        table: &mut T,
+        ops: &[Op],
+        insert: impl Fn(&mut T, Tuple),
+        lookup: impl Fn(&T, Key) -> Vec<Tuple>,
+    ) -> Result<(), TestCaseError> {
+        let mut model = HashMap::<Key, Vec<Tuple>>::new();
+        for &op in ops {
+            match op {
+                Op::Insert(key) => {
+                    insert(table, Tuple::new(key));
+                    model.entry(key).or_default().push(Tuple::new(key));
+                }
+                Op::Lookup(key) => {
+                    let expected = model.get(&key).cloned().unwrap_or_default();
+                    prop_assert_eq!(lookup(table, key), expected);
+                }
+            }
+        }
+        for (&key, expected) in &model {
+            prop_assert_eq!(&lookup(table, key), expected);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{
+    join_benchmark::{bucket_hash, prefetch::PROBE_BATCH_SIZE, simd},
+    tuple::{Key, Tuple},
+};
+
+use anyhow::{anyhow, Result};
+
+/// Marks the end of a chain. Chain links are stored as index + 1.
+const END: u32 = 0;
+
+/// A hash table that stores its tuples in one array and chains the tuples of each bucket
+/// through a parallel array of indices, as in the radix join of Balkesen et al. Unlike
+/// `SequentialHashTable`, buckets are not separately allocated, so building the table
+/// only writes to three arrays. Holds at most `u32::MAX` tuples.
+pub struct BucketChainedHashTable {
+    tuples: Vec<Tuple>,
+    /// The last tuple inserted into each bucket.
+    heads: Vec<u32>,
+    /// The tuple inserted into the same bucket before each tuple.
+    next: Vec<u32>,
+    bucket_num: usize,
+}
+
+impl BucketChainedHashTable {
+    pub fn new(bucket_num: usize) -> Result<BucketChainedHashTable> {
+        Self::from_tuples(bucket_num, vec![])
+    }
+
+    /// Build a table from the given tuples.
+    pub fn from_tuples(bucket_num: usize, tuples: Vec<Tuple>) -> Result<BucketChainedHashTable> {
+        // Bucket number must be a power of 2 so modding can be optimized to bitwise AND.
+        if !bucket_num.is_power_of_two() {
+            return Err(anyhow!("Bucket number must be a power of 2"));
+        }
+        if tuples.len() >= u32::MAX as usize {
+            return Err(anyhow!("Too many tuples for a bucket-chained hash table"));
+        }
+
+        let mut hash_table = BucketChainedHashTable {
+            heads: vec![END; bucket_num],
+            next: Vec::with_capacity(tuples.len()),
+            tuples: vec![],
+            bucket_num,
+        };
+        for tuple in &tuples {
+            hash_table.link(tuple.key());
+        }
+        hash_table.tuples = tuples;
+        Ok(hash_table)
+    }
+
+    /// Chain the tuple with index `self.next.len()` into its bucket.
+    fn link(&mut self, key: Key) {
+        let head = &mut self.heads[bucket_hash(key) as usize & (self.bucket_num - 1)];
+        self.next.push(*head);
+        *head = self.next.len() as u32;
+    }
+
+    pub fn insert(&mut self, tuple: Tuple) {
+        self.link(tuple.key());
+        self.tuples.push(tuple);
+    }
+
+    /// Call `f` on every tuple matching each of `tuples`. The buckets of a batch of
+    /// tuples are computed at once.
+    pub fn probe(&self, tuples: &[Tuple], mut f: impl FnMut(&Tuple)) {
+        let mut indices = [0; PROBE_BATCH_SIZE];
+        for batch in tuples.chunks(PROBE_BATCH_SIZE) {
+            let indices = &mut indices[..batch.len()];
+            simd::bucket_indices(batch, self.bucket_num, indices);
+            for (tuple, &idx) in batch.iter().zip(indices.iter()) {
+                let mut link = self.heads[idx];
+                while link != END {
+                    let inner = &self.tuples[link as usize - 1];
+                    if inner.key_match(tuple.key()) {
+                        f(inner);
+                    }
+                    link = self.next[link as usize - 1];
+                }
+            }
+        }
+    }
+
+    /// All tuples with the given key, in reverse insertion order.
+    pub fn get_matching_tuples(&self, key: Key) -> impl Iterator<Item = Tuple> + '_ {
+        let mut link = self.heads[bucket_hash(key) as usize & (self.bucket_num - 1)];
+        std::iter::from_fn(move || {
+            while link != END {
+                let tuple = &self.tuples[link as usize - 1];
+                link = self.next[link as usize - 1];
+                if tuple.key_match(key) {
+                    return Some(tuple.clone());
+                }
+            }
+            None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::join_benchmark::hash_table::model;
+
+    #[test]
+    fn test_bucket_chained_hash_table() {
+        let hash_table =
+            BucketChainedHashTable::from_tuples(4, (0..32).map(Tuple::new).collect()).unwrap();
+        for key in 0..32 {
+            assert_eq!(
+                hash_table.get_matching_tuples(key).collect::<Vec<_>>(),
+                vec![Tuple::new(key)]
+            );
+        }
+        assert_eq!(hash_table.get_matching_tuples(32).count(), 0);
+        assert!(BucketChainedHashTable::new(3).is_err());
+
+        let outer = [31, 32, 0].map(Tuple::new);
+        let mut matches = vec![];
+        hash_table.probe(&outer, |tuple| matches.push(tuple.key()));
+        assert_eq!(matches, vec![31, 0]);
+    }
+
+    proptest! {
+        /// Tuples with the same key are equal, so the reverse order does not matter to
+        /// the model.
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_bucket_chained_hash_table_matches_model(
+            bucket_num in model::bucket_nums(),
+            ops in model::ops(),
+        ) {
+            let mut hash_table = BucketChainedHashTable::new(bucket_num).unwrap();
+            model::check_against_model(
+                &mut hash_table,
+                &ops,
+                |table, tuple| table.insert(tuple),
+                |table, key| table.get_matching_tuples(key).collect(),
+            )?;
+        }
+    }
+}
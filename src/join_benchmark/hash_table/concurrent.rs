@@ -1,12 +1,17 @@
 use crate::{
-    join_benchmark::{bucket_hash, hash_bucket::HashBucket},
+    join_benchmark::{
+        bucket_hash,
+        hash_bucket::HashBucket,
+        prefetch::{self, BucketTable, Prefetch, ProbeTable},
+    },
+    sync::Mutex,
     tuple::{Key, Tuple},
 };
 
 use anyhow::{anyhow, Result};
 
 pub struct ConcurrentHashTable<B: HashBucket> {
-    buckets: Vec<parking_lot::Mutex<B>>,
+    buckets: Vec<Mutex<B>>,
     bucket_num: usize,
 }
 
@@ -18,7 +23,7 @@ impl<B: HashBucket> ConcurrentHashTable<B> {
         }
         let mut buckets = Vec::with_capacity(bucket_num);
         for _ in 0..bucket_num {
-            buckets.push(parking_lot::Mutex::new(B::default()));
+            buckets.push(Mutex::new(B::default()));
         }
         Ok(ConcurrentHashTable {
             buckets,
@@ -26,24 +31,60 @@ impl<B: HashBucket> ConcurrentHashTable<B> {
         })
     }
 
+    /// A table of the given buckets, whose tuples must be in the bucket of their key.
+    pub(super) fn from_buckets(buckets: Vec<Mutex<B>>) -> ConcurrentHashTable<B> {
+        debug_assert!(buckets.len().is_power_of_two());
+        ConcurrentHashTable {
+            bucket_num: buckets.len(),
+            buckets,
+        }
+    }
+
     pub fn insert(&self, tuple: Tuple) {
         let bucket = &self.buckets[bucket_hash(tuple.key()) as usize & (self.bucket_num - 1)];
-        let mut bucket = bucket.lock();
-        bucket.push(tuple);
+        bucket.with_lock(|bucket| bucket.push(tuple));
     }
 
-    pub fn get_matching_tuples(&self, key: Key) -> Option<Tuple> {
+    /// All tuples with the given key, in insertion order.
+    ///
+    /// The bucket is read without locking it, so this must not be called while tuples are
+    /// being inserted.
+    pub fn get_matching_tuples(&self, key: Key) -> impl Iterator<Item = Tuple> + '_ {
         let bucket = &self.buckets[bucket_hash(key) as usize & (self.bucket_num - 1)];
-        let bucket = unsafe { bucket.make_guard_unchecked() };
-        let tuple = bucket.iter().find(move |t| t.key_match(key)).cloned();
-        std::mem::forget(bucket);
-        tuple
+        let bucket = unsafe { bucket.get_unchecked() };
+        bucket.iter().filter(move |t| t.key_match(key)).cloned()
+    }
+}
+
+/// Like `get_matching_tuples`, the bucket must not be read while tuples are being
+/// inserted.
+impl<B: HashBucket> BucketTable for ConcurrentHashTable<B> {
+    type Bucket = B;
+
+    fn bucket_num(&self) -> usize {
+        self.bucket_num
+    }
+
+    fn bucket_at(&self, idx: usize) -> &B {
+        unsafe { self.buckets[idx].get_unchecked() }
     }
 }
 
-#[cfg(test)]
+impl<B: HashBucket> ProbeTable for ConcurrentHashTable<B> {
+    fn probe(&self, tuples: &[Tuple], prefetch: Prefetch, f: impl FnMut(Tuple)) {
+        prefetch::probe(self, tuples, prefetch, f)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
 mod tests {
+    use std::collections::HashMap;
+
+    use proptest::prelude::*;
+    use rayon::prelude::*;
+
     use super::*;
+    use crate::join_benchmark::hash_table::model;
 
     #[test]
     fn test_concurrent_hash_table() {
@@ -51,13 +92,194 @@ mod tests {
         let tuple = Tuple::new(1);
         hash_table.insert(tuple.clone());
 
-        assert_eq!(hash_table.get_matching_tuples(1), Some(tuple));
+        assert_eq!(
+            hash_table.get_matching_tuples(1).collect::<Vec<_>>(),
+            vec![tuple]
+        );
 
-        assert_eq!(hash_table.get_matching_tuples(2), None);
+        assert_eq!(hash_table.get_matching_tuples(2).count(), 0);
 
         let tuple = Tuple::new(2);
         hash_table.insert(tuple.clone());
 
-        assert_eq!(hash_table.get_matching_tuples(2), Some(tuple));
+        assert_eq!(
+            hash_table.get_matching_tuples(2).collect::<Vec<_>>(),
+            vec![tuple]
+        );
+    }
+
+    /// Every tuple of a key matches, and only the tuples of that key.
+    #[test]
+    fn test_concurrent_hash_table_duplicate_keys() {
+        let hash_table = ConcurrentHashTable::<Vec<Tuple>>::new(16).unwrap();
+        let tuple = Tuple::new(1);
+        hash_table.insert(tuple.clone());
+        hash_table.insert(tuple.clone());
+        hash_table.insert(Tuple::new(17));
+
+        assert_eq!(
+            hash_table.get_matching_tuples(1).collect::<Vec<_>>(),
+            vec![tuple.clone(), tuple]
+        );
+        assert_eq!(
+            hash_table.get_matching_tuples(17).collect::<Vec<_>>(),
+            vec![Tuple::new(17)]
+        );
+    }
+
+    /// Concurrent inserts followed by concurrent unlocked probes. Under Miri, this checks
+    /// that the probes do not race with the inserts.
+    #[test]
+    fn test_concurrent_inserts_then_probe() {
+        let hash_table = ConcurrentHashTable::<Vec<Tuple>>::new(4).unwrap();
+        std::thread::scope(|s| {
+            for thread in 0..4 {
+                let hash_table = &hash_table;
+                s.spawn(move || (0..8).for_each(|key| hash_table.insert(Tuple::new(key + thread))));
+            }
+        });
+        std::thread::scope(|s| {
+            for key in 0..11 {
+                let hash_table = &hash_table;
+                s.spawn(move || {
+                    let expected = (0..4).filter(|thread| (*thread..thread + 8).contains(&key));
+                    assert_eq!(
+                        hash_table.get_matching_tuples(key).count(),
+                        expected.count()
+                    );
+                });
+            }
+        });
+    }
+
+    proptest! {
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_concurrent_hash_table_matches_model(
+            bucket_num in model::bucket_nums(),
+            ops in model::ops(),
+        ) {
+            let mut hash_table = ConcurrentHashTable::<Vec<Tuple>>::new(bucket_num).unwrap();
+            model::check_against_model(
+                &mut hash_table,
+                &ops,
+                |table, tuple| table.insert(tuple),
+                |table, key| table.get_matching_tuples(key).collect(),
+            )?;
+        }
+
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_concurrent_hash_table_parallel_inserts(
+            bucket_num in model::bucket_nums(),
+            keys in prop::collection::vec(model::keys(), 0..1000),
+        ) {
+            let hash_table = ConcurrentHashTable::<Vec<Tuple>>::new(bucket_num).unwrap();
+            keys.par_iter()
+                .for_each(|&key| hash_table.insert(Tuple::new(key)));
+
+            let mut counts = HashMap::<Key, usize>::new();
+            for &key in &keys {
+                *counts.entry(key).or_default() += 1;
+            }
+            for (key, count) in counts {
+                prop_assert_eq!(
+                    hash_table.get_matching_tuples(key).collect::<Vec<_>>(),
+                    vec![Tuple::new(key); count]
+                );
+            }
+        }
+    }
+}
+
+/// Run with `RUSTFLAGS="--cfg loom" cargo test --release --lib loom_`.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::{sync::Arc, thread};
+
+    use super::*;
+
+    fn table(bucket_num: usize) -> Arc<ConcurrentHashTable<Vec<Tuple>>> {
+        Arc::new(ConcurrentHashTable::new(bucket_num).unwrap())
+    }
+
+    fn count(table: &ConcurrentHashTable<Vec<Tuple>>, key: Key) -> usize {
+        table.get_matching_tuples(key).count()
+    }
+
+    #[test]
+    fn loom_concurrent_inserts() {
+        loom::model(|| {
+            // A single bucket, so that both inserts contend for it.
+            let hash_table = table(1);
+            let threads = [1, 2]
+                .map(|key| {
+                    let hash_table = hash_table.clone();
+                    thread::spawn(move || hash_table.insert(Tuple::new(key)))
+                })
+                .map(|thread| thread.join().unwrap());
+            assert_eq!(threads.len(), 2);
+            assert_eq!(count(&hash_table, 1), 1);
+            assert_eq!(count(&hash_table, 2), 1);
+        });
+    }
+
+    #[test]
+    fn loom_concurrent_inserts_of_same_key() {
+        loom::model(|| {
+            let hash_table = table(2);
+            let thread = {
+                let hash_table = hash_table.clone();
+                thread::spawn(move || {
+                    hash_table.insert(Tuple::new(3));
+                    hash_table.insert(Tuple::new(4));
+                })
+            };
+            hash_table.insert(Tuple::new(3));
+            thread.join().unwrap();
+            assert_eq!(count(&hash_table, 3), 2);
+            assert_eq!(count(&hash_table, 4), 1);
+        });
+    }
+
+    /// Probing after the inserting threads are joined, as the build and probe phases do.
+    #[test]
+    fn loom_insert_then_probe() {
+        loom::model(|| {
+            let hash_table = table(2);
+            let builders = (0..2)
+                .map(|key| {
+                    let hash_table = hash_table.clone();
+                    thread::spawn(move || hash_table.insert(Tuple::new(key)))
+                })
+                .collect::<Vec<_>>();
+            builders.into_iter().for_each(|t| t.join().unwrap());
+
+            let probers = (0..2)
+                .map(|key| {
+                    let hash_table = hash_table.clone();
+                    thread::spawn(move || count(&hash_table, key))
+                })
+                .collect::<Vec<_>>();
+            for prober in probers {
+                assert_eq!(prober.join().unwrap(), 1);
+            }
+        });
+    }
+
+    /// Probing while another thread inserts breaks the contract of `get_matching_tuples`,
+    /// and loom reports the unsynchronized read.
+    #[test]
+    #[should_panic(expected = "Causality violation")]
+    fn loom_probe_during_insert_is_detected() {
+        loom::model(|| {
+            let hash_table = table(1);
+            let thread = {
+                let hash_table = hash_table.clone();
+                thread::spawn(move || hash_table.insert(Tuple::new(1)))
+            };
+            count(&hash_table, 1);
+            thread.join().unwrap();
+        });
     }
 }
@@ -0,0 +1,310 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use anyhow::{anyhow, Result};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    join_benchmark::{
+        for_each_tuple,
+        prefetch::{Prefetch, ProbeTable},
+        SchedulingType, TableKind,
+    },
+    tuple::{DataChunk, Key, Tuple},
+};
+
+/// Maximum number of slots per inner tuple of a `DenseTable`. Key ranges with more slots
+/// than that are sparse, and joined with a hash table instead.
+pub const MAX_SLOTS_PER_KEY: u64 = 2;
+
+/// The smallest and largest key of a relation, and its number of tuples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyRange {
+    pub min: Key,
+    pub max: Key,
+    pub count: usize,
+}
+
+impl KeyRange {
+    fn of_key(key: Key) -> Self {
+        Self {
+            min: key,
+            max: key,
+            count: 1,
+        }
+    }
+
+    fn merge(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            count: self.count + other.count,
+        }
+    }
+
+    /// The key range of a relation, or `None` if it is empty.
+    pub fn of(chunks: &[DataChunk]) -> Option<Self> {
+        chunks
+            .iter()
+            .flatten()
+            .map(|tuple| Self::of_key(tuple.key()))
+            .reduce(Self::merge)
+    }
+
+    /// Like `of`, but scans the relation in parallel.
+    pub fn par_of(chunks: &[DataChunk]) -> Option<Self> {
+        chunks
+            .par_iter()
+            .flat_map(|chunk| chunk.par_iter())
+            .map(|tuple| Self::of_key(tuple.key()))
+            .reduce_with(Self::merge)
+    }
+
+    /// Number of keys from `min` to `max`, saturating at `u64::MAX`.
+    pub fn slot_num(&self) -> u64 {
+        (self.max - self.min).saturating_add(1)
+    }
+
+    /// Whether the keys are dense enough for a `DenseTable`.
+    pub fn is_dense(&self) -> bool {
+        self.slot_num() <= self.count as u64 * MAX_SLOTS_PER_KEY && self.count < u32::MAX as usize
+    }
+}
+
+/// A direct-addressed table of the keys in a dense range. The tuples are sorted by key
+/// into one array, and the tuples with key `min + i` are at `offsets[i]..offsets[i + 1]`,
+/// so that a lookup needs neither hashing nor key comparisons.
+pub struct DenseTable {
+    min: Key,
+    offsets: Vec<u32>,
+    tuples: Vec<Tuple>,
+}
+
+/// The tuple array of a `DenseTable` being scattered into by multiple threads.
+struct ScatterTarget {
+    tuples: *mut Tuple,
+    len: usize,
+}
+
+// Every thread writes to positions reserved with an atomic cursor, so no two threads
+// write to the same tuple.
+unsafe impl Sync for ScatterTarget {}
+
+impl ScatterTarget {
+    /// # Safety
+    ///
+    /// No other thread may access the tuple at `pos` concurrently.
+    unsafe fn write(&self, pos: usize, tuple: Tuple) {
+        assert!(pos < self.len);
+        self.tuples.add(pos).write(tuple);
+    }
+}
+
+impl DenseTable {
+    fn check(range: &KeyRange) -> Result<usize> {
+        if !range.is_dense() {
+            return Err(anyhow!("Keys are too sparse for a dense table"));
+        }
+        Ok(range.slot_num() as usize)
+    }
+
+    fn slot(&self, key: Key) -> Option<usize> {
+        let slot = key.wrapping_sub(self.min);
+        (slot < (self.offsets.len() - 1) as u64).then_some(slot as usize)
+    }
+
+    /// Build the table with a counting sort. Tuples with the same key keep their order.
+    pub fn new(range: KeyRange, chunks: &[DataChunk]) -> Result<DenseTable> {
+        let slot_num = Self::check(&range)?;
+        let mut offsets = vec![0; slot_num + 1];
+        for tuple in chunks.iter().flatten() {
+            offsets[(tuple.key() - range.min) as usize + 1] += 1;
+        }
+        for i in 1..offsets.len() {
+            offsets[i] += offsets[i - 1];
+        }
+        let mut cursors = offsets[..slot_num].to_vec();
+        let mut tuples = vec![Tuple::new(range.min); range.count];
+        for tuple in chunks.iter().flatten() {
+            let cursor = &mut cursors[(tuple.key() - range.min) as usize];
+            tuples[*cursor as usize] = tuple.clone();
+            *cursor += 1;
+        }
+        Ok(DenseTable {
+            min: range.min,
+            offsets,
+            tuples,
+        })
+    }
+
+    /// Build the table with a counting sort in parallel. Tuples with the same key are in
+    /// no particular order.
+    pub fn par_new<const S: SchedulingType>(
+        range: KeyRange,
+        chunks: &[DataChunk],
+    ) -> Result<DenseTable> {
+        let slot_num = Self::check(&range)?;
+        let slot = |tuple: &Tuple| (tuple.key() - range.min) as usize;
+        let mut cursors = (0..slot_num).map(|_| AtomicU32::new(0)).collect::<Vec<_>>();
+        for_each_tuple::<S>(chunks, |tuple| {
+            cursors[slot(tuple)].fetch_add(1, Ordering::Relaxed);
+        });
+        // Turn the counts into the start of each slot.
+        let mut offsets = Vec::with_capacity(slot_num + 1);
+        offsets.push(0);
+        for cursor in &mut cursors {
+            let start = *offsets.last().unwrap();
+            offsets.push(start + *cursor.get_mut());
+            *cursor.get_mut() = start;
+        }
+
+        let mut tuples = vec![Tuple::new(range.min); range.count];
+        let target = ScatterTarget {
+            tuples: tuples.as_mut_ptr(),
+            len: tuples.len(),
+        };
+        for_each_tuple::<S>(chunks, |tuple| {
+            let pos = cursors[slot(tuple)].fetch_add(1, Ordering::Relaxed);
+            // SAFETY: The position was reserved by this thread only.
+            unsafe { target.write(pos as usize, tuple.clone()) };
+        });
+        Ok(DenseTable {
+            min: range.min,
+            offsets,
+            tuples,
+        })
+    }
+
+    /// All tuples with the given key.
+    pub fn get_matching_tuples(&self, key: Key) -> &[Tuple] {
+        match self.slot(key) {
+            Some(slot) => {
+                &self.tuples[self.offsets[slot] as usize..self.offsets[slot + 1] as usize]
+            }
+            None => &[],
+        }
+    }
+
+    /// Call `f` on every tuple matching each of `tuples`.
+    pub fn probe(&self, tuples: &[Tuple], mut f: impl FnMut(&Tuple)) {
+        for tuple in tuples {
+            self.get_matching_tuples(tuple.key())
+                .iter()
+                .for_each(&mut f);
+        }
+    }
+}
+
+/// The table a join builds: a `DenseTable` if the inner keys are dense, and a hash table
+/// otherwise.
+pub enum JoinTable<T> {
+    Dense(DenseTable),
+    Hash(T),
+}
+
+impl<T> JoinTable<T> {
+    pub fn kind(&self) -> TableKind {
+        match self {
+            JoinTable::Dense(_) => TableKind::Dense,
+            JoinTable::Hash(_) => TableKind::Hash,
+        }
+    }
+
+    /// Probe the table with every tuple of `tuples`. Only hash table lookups are
+    /// prefetched, since a dense table lookup is a single array access.
+    pub(in crate::join_benchmark) fn probe(
+        &self,
+        tuples: &[Tuple],
+        prefetch: Prefetch,
+        mut f: impl FnMut(Tuple),
+    ) where
+        T: ProbeTable,
+    {
+        match self {
+            JoinTable::Dense(table) => table.probe(tuples, |tuple| f(tuple.clone())),
+            JoinTable::Hash(table) => table.probe(tuples, prefetch, f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::join_benchmark::{DYNAMIC_SCHEDULING, STATIC_SCHEDULING};
+
+    #[test]
+    fn test_key_range() {
+        let chunks = vec![
+            vec![Tuple::new(5), Tuple::new(3)],
+            vec![],
+            vec![Tuple::new(8)],
+        ];
+        let range = KeyRange {
+            min: 3,
+            max: 8,
+            count: 3,
+        };
+        assert_eq!(KeyRange::of(&chunks), Some(range));
+        assert_eq!(KeyRange::par_of(&chunks), Some(range));
+        assert_eq!(range.slot_num(), 6);
+        assert!(range.is_dense());
+        assert!(!KeyRange { max: 9, ..range }.is_dense());
+        assert_eq!(KeyRange::of(&[vec![]]), None);
+
+        let full = KeyRange {
+            min: 0,
+            max: Key::MAX,
+            count: 2,
+        };
+        assert_eq!(full.slot_num(), u64::MAX);
+        assert!(!full.is_dense());
+        assert!(DenseTable::new(full, &[]).is_err());
+    }
+
+    #[test]
+    fn test_dense_table() {
+        let chunks = vec![(10..20).chain([12, 19]).map(Tuple::new).collect()];
+        let table = DenseTable::new(KeyRange::of(&chunks).unwrap(), &chunks).unwrap();
+        assert_eq!(
+            table.get_matching_tuples(12),
+            [Tuple::new(12), Tuple::new(12)]
+        );
+        assert_eq!(table.get_matching_tuples(10), [Tuple::new(10)]);
+        for key in [0, 9, 20, Key::MAX] {
+            assert!(table.get_matching_tuples(key).is_empty());
+        }
+    }
+
+    proptest! {
+        /// Sequential and parallel builds find the same tuples for every key.
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_dense_table_matches_relation(
+            min in any::<u64>(),
+            offsets in prop::collection::vec(prop::collection::vec(0..64u64, 0..50), 1..5),
+        ) {
+            let chunks = offsets
+                .iter()
+                .map(|chunk| chunk.iter().map(|offset| Tuple::new(min.saturating_add(*offset))).collect())
+                .collect::<Vec<DataChunk>>();
+            let Some(range) = KeyRange::of(&chunks).filter(KeyRange::is_dense) else {
+                return Ok(());
+            };
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+            let tables = [
+                DenseTable::new(range, &chunks).unwrap(),
+                pool.install(|| DenseTable::par_new::<STATIC_SCHEDULING>(range, &chunks)).unwrap(),
+                pool.install(|| DenseTable::par_new::<DYNAMIC_SCHEDULING>(range, &chunks)).unwrap(),
+            ];
+            for key in range.min.saturating_sub(1)..=range.max.saturating_add(1) {
+                let expected = chunks.iter().flatten().filter(|t| t.key() == key).count();
+                for table in &tables {
+                    let matches = table.get_matching_tuples(key);
+                    prop_assert_eq!(matches.len(), expected);
+                    prop_assert!(matches.iter().all(|t| t.key() == key));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! A concurrent hash table that grows while tuples are inserted, for build sides whose
+//! size is not known up front.
+//!
+//! When the table holds more tuples than its buckets times the load factor, it doubles
+//! its buckets with a cooperative incremental rehash: the new bucket array is allocated
+//! under a short exclusive lock, and the tuples of the old buckets are then moved in
+//! chunks by the inserting threads, one chunk per insert, while other inserts go on.
+//! A key whose old bucket has not been moved yet is still inserted into the old bucket,
+//! so tuples with the same key keep their insertion order. Once built, the table is
+//! turned into a `ConcurrentHashTable`, so that it is probed as fast as a fixed one.
+
+use std::sync::atomic::Ordering;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    join_benchmark::{bucket_hash, hash_bucket::HashBucket, LoadFactor},
+    sync::{AtomicBool, AtomicUsize, Mutex, RwLock},
+    tuple::Tuple,
+};
+
+use super::concurrent::ConcurrentHashTable;
+
+/// Initial number of buckets when the size of the build side is unknown.
+pub const INITIAL_BUCKET_NUM: usize = 1024;
+
+/// Old buckets whose tuples an insert moves during a rehash.
+const MIGRATION_CHUNK_SIZE: usize = 64;
+
+/// The buckets of the table before it doubled, while their tuples are being moved.
+struct Migration<B: HashBucket> {
+    buckets: Vec<Mutex<B>>,
+    /// Whether each old bucket has been moved. Only accessed under its bucket lock.
+    migrated: Vec<AtomicBool>,
+    /// The next chunk of old buckets to move.
+    next: AtomicUsize,
+    /// Old buckets moved so far.
+    done: AtomicUsize,
+}
+
+struct Tables<B: HashBucket> {
+    buckets: Vec<Mutex<B>>,
+    /// The table doubles when it holds this many tuples.
+    grow_at: usize,
+    migration: Option<Migration<B>>,
+}
+
+pub struct GrowableHashTable<B: HashBucket> {
+    tables: RwLock<Tables<B>>,
+    tuple_num: AtomicUsize,
+    load_factor: LoadFactor,
+}
+
+fn new_buckets<B: HashBucket>(bucket_num: usize) -> Vec<Mutex<B>> {
+    (0..bucket_num).map(|_| Mutex::new(B::default())).collect()
+}
+
+fn grow_at(bucket_num: usize, load_factor: LoadFactor) -> usize {
+    ((bucket_num as f64 * load_factor.get()).ceil() as usize).max(1)
+}
+
+impl<B: HashBucket> Tables<B> {
+    fn insert(&self, tuple: Tuple) {
+        let hash = bucket_hash(tuple.key()) as usize;
+        let mut tuple = Some(tuple);
+        if let Some(migration) = &self.migration {
+            let idx = hash & (migration.buckets.len() - 1);
+            migration.buckets[idx].with_lock(|bucket| {
+                if !migration.migrated[idx].load(Ordering::Relaxed) {
+                    bucket.push(tuple.take().unwrap());
+                }
+            });
+        }
+        if let Some(tuple) = tuple {
+            self.buckets[hash & (self.buckets.len() - 1)].with_lock(|bucket| bucket.push(tuple));
+        }
+    }
+
+    /// Move the tuples of an old bucket to the two new buckets they hash to.
+    fn migrate_bucket(&self, migration: &Migration<B>, idx: usize) {
+        let old_bucket_num = migration.buckets.len();
+        migration.buckets[idx].with_lock(|old| {
+            // Until the old bucket is marked as moved, inserts of its keys wait for its
+            // lock, so no other thread accesses the new buckets.
+            self.buckets[idx].with_lock(|low| {
+                self.buckets[idx + old_bucket_num].with_lock(|high| {
+                    for tuple in std::mem::take(old).iter() {
+                        if bucket_hash(tuple.key()) as usize & old_bucket_num == 0 {
+                            low.push(tuple.clone());
+                        } else {
+                            high.push(tuple.clone());
+                        }
+                    }
+                })
+            });
+            migration.migrated[idx].store(true, Ordering::Relaxed);
+        });
+    }
+
+    /// Move the next chunk of old buckets, if any. Returns whether the migration is
+    /// complete after that chunk.
+    fn migrate_chunk(&self) -> bool {
+        let Some(migration) = &self.migration else {
+            return false;
+        };
+        let len = migration.buckets.len();
+        let start = migration
+            .next
+            .fetch_add(MIGRATION_CHUNK_SIZE, Ordering::Relaxed);
+        if start >= len {
+            return false;
+        }
+        let end = (start + MIGRATION_CHUNK_SIZE).min(len);
+        for idx in start..end {
+            self.migrate_bucket(migration, idx);
+        }
+        migration.done.fetch_add(end - start, Ordering::Relaxed) + end - start == len
+    }
+}
+
+impl<B: HashBucket> GrowableHashTable<B> {
+    /// A table of `bucket_num` buckets that doubles whenever it holds more than
+    /// `load_factor` tuples per bucket.
+    pub fn new(bucket_num: usize, load_factor: LoadFactor) -> Result<GrowableHashTable<B>> {
+        // Bucket number must be a power of 2 so modding can be optimized to bitwise AND.
+        if !bucket_num.is_power_of_two() {
+            return Err(anyhow!("Bucket number must be a power of 2"));
+        }
+        Ok(GrowableHashTable {
+            tables: RwLock::new(Tables {
+                buckets: new_buckets(bucket_num),
+                grow_at: grow_at(bucket_num, load_factor),
+                migration: None,
+            }),
+            tuple_num: AtomicUsize::new(0),
+            load_factor,
+        })
+    }
+
+    pub fn insert(&self, tuple: Tuple) {
+        let grow = {
+            let tables = self.tables.read();
+            tables.insert(tuple);
+            let migrated = tables.migrate_chunk();
+            let tuple_num = self.tuple_num.fetch_add(1, Ordering::Relaxed) + 1;
+            // Only one insert reaches the threshold, so threads do not queue up for the
+            // exclusive lock.
+            migrated || tuple_num == tables.grow_at
+        };
+        if grow {
+            self.grow();
+        }
+    }
+
+    /// Finish the migration if all old buckets were moved, and start doubling the table
+    /// if it holds too many tuples and no migration is running. If one is, the insert
+    /// that completes it calls this again.
+    fn grow(&self) {
+        let mut tables = self.tables.write();
+        if let Some(migration) = &tables.migration {
+            if migration.done.load(Ordering::Relaxed) < migration.buckets.len() {
+                return;
+            }
+            tables.migration = None;
+        }
+        if self.tuple_num.load(Ordering::Relaxed) < tables.grow_at {
+            return;
+        }
+        let old_bucket_num = tables.buckets.len();
+        let buckets = std::mem::replace(&mut tables.buckets, new_buckets(old_bucket_num * 2));
+        tables.grow_at = grow_at(old_bucket_num * 2, self.load_factor);
+        tables.migration = Some(Migration {
+            buckets,
+            migrated: (0..old_bucket_num)
+                .map(|_| AtomicBool::new(false))
+                .collect(),
+            next: AtomicUsize::new(0),
+            done: AtomicUsize::new(0),
+        });
+    }
+
+    #[cfg(all(test, not(loom)))]
+    fn bucket_num(&self) -> usize {
+        self.tables.read().buckets.len()
+    }
+
+    /// Move the tuples left in old buckets and turn the table into a fixed one for
+    /// probing.
+    pub fn finish(self) -> ConcurrentHashTable<B> {
+        let mut tables = self.tables.into_inner();
+        if let Some(migration) = tables.migration.take() {
+            let start = migration.next.load(Ordering::Relaxed);
+            for idx in start.min(migration.buckets.len())..migration.buckets.len() {
+                tables.migrate_bucket(&migration, idx);
+            }
+        }
+        ConcurrentHashTable::from_buckets(tables.buckets)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::collections::HashMap;
+
+    use proptest::prelude::*;
+    use rayon::prelude::*;
+
+    use super::*;
+    use crate::{join_benchmark::hash_table::model, tuple::Key};
+
+    fn load_factor(load_factor: f64) -> LoadFactor {
+        LoadFactor::new(load_factor).unwrap()
+    }
+
+    #[test]
+    fn test_growable_hash_table_grows() {
+        let hash_table = GrowableHashTable::<Vec<Tuple>>::new(1, load_factor(2.0)).unwrap();
+        for key in 0..1000 {
+            hash_table.insert(Tuple::new(key % 300));
+        }
+        assert!(hash_table.bucket_num() >= 256);
+        let hash_table = hash_table.finish();
+        for key in 0..300 {
+            let expected = if key < 100 { 4 } else { 3 };
+            assert_eq!(hash_table.get_matching_tuples(key).count(), expected);
+        }
+        assert_eq!(hash_table.get_matching_tuples(300).count(), 0);
+    }
+
+    /// Many threads insert while the table doubles many times, starting from a single
+    /// bucket, so that inserts race with allocations and with moving tuples.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_growable_hash_table_stress() {
+        const THREAD_NUM: u64 = 8;
+        const TUPLES_PER_THREAD: u64 = 50_000;
+        for load_factor in [load_factor(0.5), load_factor(4.0)] {
+            let hash_table = GrowableHashTable::<Vec<Tuple>>::new(1, load_factor).unwrap();
+            std::thread::scope(|s| {
+                for thread in 0..THREAD_NUM {
+                    let hash_table = &hash_table;
+                    s.spawn(move || {
+                        for i in 0..TUPLES_PER_THREAD {
+                            // Every key is inserted by two threads.
+                            hash_table.insert(Tuple::new((thread / 2) * TUPLES_PER_THREAD + i));
+                        }
+                    });
+                }
+            });
+            let tuple_num = (THREAD_NUM * TUPLES_PER_THREAD) as usize;
+            assert!(hash_table.bucket_num() as f64 * load_factor.get() >= tuple_num as f64 / 2.0);
+            let hash_table = hash_table.finish();
+            (0..tuple_num as u64 / 2).into_par_iter().for_each(|key| {
+                assert_eq!(
+                    hash_table.get_matching_tuples(key).count(),
+                    2,
+                    "key {}",
+                    key
+                );
+            });
+            assert_eq!(hash_table.get_matching_tuples(tuple_num as u64).count(), 0);
+        }
+    }
+
+    proptest! {
+        /// Sequential inserts keep their order per key across rehashes.
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_growable_hash_table_keeps_order(
+            bucket_num in model::bucket_nums(),
+            keys in prop::collection::vec(model::keys(), 0..500),
+        ) {
+            let hash_table =
+                GrowableHashTable::<Vec<Tuple>>::new(bucket_num, load_factor(0.5)).unwrap();
+            let mut inserted = HashMap::<Key, Vec<Tuple>>::new();
+            for (i, &key) in keys.iter().enumerate() {
+                hash_table.insert(Tuple::new(key));
+                inserted.entry(key).or_default().push(Tuple::new(key));
+                // Keys are only probed after the build, but the tuple count must match.
+                prop_assert_eq!(hash_table.tuple_num.load(Ordering::Relaxed), i + 1);
+            }
+            let hash_table = hash_table.finish();
+            for (key, expected) in inserted {
+                prop_assert_eq!(hash_table.get_matching_tuples(key).collect::<Vec<_>>(), expected);
+            }
+        }
+    }
+}
+
+/// Run with `RUSTFLAGS="--cfg loom" cargo test --release --lib loom_`.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::{sync::Arc, thread};
+
+    use super::*;
+
+    /// Two threads insert into a single bucket that holds one tuple, so that the second
+    /// insert doubles the table while the other thread inserts or moves tuples.
+    #[test]
+    fn loom_concurrent_inserts_while_growing() {
+        loom::model(|| {
+            let hash_table = Arc::new(
+                GrowableHashTable::<Vec<Tuple>>::new(1, LoadFactor::new(1.0).unwrap()).unwrap(),
+            );
+            let thread = {
+                let hash_table = hash_table.clone();
+                thread::spawn(move || hash_table.insert(Tuple::new(1)))
+            };
+            hash_table.insert(Tuple::new(2));
+            hash_table.insert(Tuple::new(1));
+            thread.join().unwrap();
+
+            let hash_table = Arc::try_unwrap(hash_table).ok().unwrap().finish();
+            assert_eq!(hash_table.get_matching_tuples(1).count(), 2);
+            assert_eq!(hash_table.get_matching_tuples(2).count(), 1);
+        });
+    }
+}
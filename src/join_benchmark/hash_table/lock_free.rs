@@ -0,0 +1,408 @@
+use std::sync::atomic::Ordering;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    join_benchmark::{
+        bucket_hash,
+        prefetch::{prefetch, HashedTuples, Prefetch, ProbeTable, PROBE_BATCH_SIZE},
+        simd,
+    },
+    sync::{AtomicU32, AtomicUsize, UnsafeCell},
+    tuple::{Key, Tuple},
+};
+
+/// Marks the end of a chain. Chain links are stored as index + 1.
+const END: u32 = 0;
+
+/// A slot of the tuple array, written once by the thread that reserved it. The cell is
+/// loom's in loom builds, so that the loom tests check the writes against the reads.
+struct Slot(UnsafeCell<Tuple>);
+
+// Each slot is written by the single thread that reserved it, before the tuple is linked
+// into its bucket, and read only after all inserts are done.
+unsafe impl Sync for Slot {}
+
+/// A hash table that threads build without locks. Each insert reserves slots of a
+/// preallocated tuple array with an atomic cursor, writes its tuples there and links
+/// them into their buckets by swapping the bucket head with a compare-and-swap. Probes
+/// walk the chains like `BucketChainedHashTable`. Holds at most `u32::MAX - 1` tuples.
+pub struct LockFreeHashTable {
+    tuples: Vec<Slot>,
+    /// The last tuple linked into each bucket.
+    heads: Vec<AtomicU32>,
+    /// The tuple linked into the same bucket before each tuple.
+    next: Vec<AtomicU32>,
+    /// Number of reserved slots.
+    len: AtomicUsize,
+    bucket_num: usize,
+}
+
+impl LockFreeHashTable {
+    /// A table of `bucket_num` buckets with room for `capacity` tuples.
+    pub fn new(bucket_num: usize, capacity: usize) -> Result<LockFreeHashTable> {
+        // Bucket number must be a power of 2 so modding can be optimized to bitwise AND.
+        if !bucket_num.is_power_of_two() {
+            return Err(anyhow!("Bucket number must be a power of 2"));
+        }
+        if capacity >= u32::MAX as usize {
+            return Err(anyhow!("Too many tuples for a lock-free hash table"));
+        }
+        Ok(LockFreeHashTable {
+            tuples: (0..capacity)
+                .map(|_| Slot(UnsafeCell::new(Tuple::new(0))))
+                .collect(),
+            heads: (0..bucket_num).map(|_| AtomicU32::new(END)).collect(),
+            next: (0..capacity).map(|_| AtomicU32::new(END)).collect(),
+            len: AtomicUsize::new(0),
+            bucket_num,
+        })
+    }
+
+    /// Insert a batch of tuples, reserving their slots at once.
+    ///
+    /// # Panics
+    ///
+    /// If the table is full.
+    pub fn insert_batch(&self, tuples: &[Tuple]) {
+        let start = self.len.fetch_add(tuples.len(), Ordering::Relaxed);
+        assert!(
+            start + tuples.len() <= self.tuples.len(),
+            "lock-free hash table is full"
+        );
+        for (idx, tuple) in (start..).zip(tuples) {
+            // SAFETY: The slot was reserved by this thread only, and is not read before
+            // all inserts are done.
+            self.tuples[idx]
+                .0
+                .with_mut(|slot| unsafe { *slot = tuple.clone() });
+            let head = &self.heads[bucket_hash(tuple.key()) as usize & (self.bucket_num - 1)];
+            let mut current = head.load(Ordering::Relaxed);
+            loop {
+                // The tuple is not reachable yet, so its link can be written plainly.
+                self.next[idx].store(current, Ordering::Relaxed);
+                match head.compare_exchange_weak(
+                    current,
+                    idx as u32 + 1,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+    }
+
+    pub fn insert(&self, tuple: Tuple) {
+        self.insert_batch(std::slice::from_ref(&tuple));
+    }
+
+    /// The tuple at a chain link.
+    ///
+    /// The slot is read without synchronization, so this must not be called while tuples
+    /// are being inserted.
+    fn tuple_at(&self, link: u32) -> &Tuple {
+        self.tuples[link as usize - 1]
+            .0
+            .with(|tuple| unsafe { &*tuple })
+    }
+
+    fn next_link(&self, link: u32) -> u32 {
+        self.next[link as usize - 1].load(Ordering::Relaxed)
+    }
+
+    /// Prefetch the tuple at a chain link and its next link.
+    fn prefetch_link(&self, link: u32) {
+        if link != END {
+            prefetch(&self.tuples[link as usize - 1]);
+            prefetch(&self.next[link as usize - 1]);
+        }
+    }
+
+    /// Call `f` on every tuple with the given key in the chain starting at `link`.
+    fn walk(&self, mut link: u32, key: Key, f: &mut impl FnMut(Tuple)) {
+        while link != END {
+            let inner = self.tuple_at(link);
+            if inner.key_match(key) {
+                f(inner.clone());
+            }
+            link = self.next_link(link);
+        }
+    }
+
+    /// Prefetch the bucket heads of a group of tuples, then the first tuples of their
+    /// chains, and then walk the chains.
+    fn probe_group(&self, tuples: &[Tuple], distance: usize, mut f: impl FnMut(Tuple)) {
+        let mut indices = vec![0; distance];
+        let mut links = Vec::with_capacity(distance);
+        for group in tuples.chunks(distance) {
+            let indices = &mut indices[..group.len()];
+            simd::bucket_indices(group, self.bucket_num, indices);
+            indices.iter().for_each(|&idx| prefetch(&self.heads[idx]));
+            links.clear();
+            links.extend(indices.iter().map(|&idx| {
+                let link = self.heads[idx].load(Ordering::Acquire);
+                self.prefetch_link(link);
+                link
+            }));
+            for (tuple, &link) in group.iter().zip(&links) {
+                self.walk(link, tuple.key(), &mut f);
+            }
+        }
+    }
+
+    /// Keep `distance` chain walks in flight, each prefetching the next link of its chain
+    /// before moving on to the next walk.
+    fn probe_amac(&self, tuples: &[Tuple], distance: usize, mut f: impl FnMut(Tuple)) {
+        let mut tuples = HashedTuples::new(tuples, self.bucket_num, distance);
+        let mut start_lookup = || match tuples.next() {
+            Some((tuple, idx)) => {
+                prefetch(&self.heads[idx]);
+                Lookup::Head(tuple.key(), idx)
+            }
+            None => Lookup::Empty,
+        };
+        let mut lookups = (0..distance).map(|_| Lookup::Empty).collect::<Vec<_>>();
+        loop {
+            let mut in_flight = false;
+            for lookup in lookups.iter_mut() {
+                let link = match std::mem::replace(lookup, Lookup::Empty) {
+                    Lookup::Empty => None,
+                    Lookup::Head(key, idx) => Some((key, self.heads[idx].load(Ordering::Acquire))),
+                    Lookup::Chain(key, link) => {
+                        let inner = self.tuple_at(link);
+                        if inner.key_match(key) {
+                            f(inner.clone());
+                        }
+                        Some((key, self.next_link(link)))
+                    }
+                };
+                *lookup = match link {
+                    Some((key, link)) if link != END => {
+                        self.prefetch_link(link);
+                        Lookup::Chain(key, link)
+                    }
+                    _ => start_lookup(),
+                };
+                in_flight |= !matches!(lookup, Lookup::Empty);
+            }
+            if !in_flight {
+                break;
+            }
+        }
+    }
+
+    /// All tuples with the given key, in no particular order.
+    ///
+    /// Like `ConcurrentHashTable::get_matching_tuples`, this must not be called while
+    /// tuples are being inserted.
+    pub fn get_matching_tuples(&self, key: Key) -> impl Iterator<Item = Tuple> + '_ {
+        let mut link =
+            self.heads[bucket_hash(key) as usize & (self.bucket_num - 1)].load(Ordering::Acquire);
+        std::iter::from_fn(move || {
+            while link != END {
+                let tuple = self.tuple_at(link);
+                link = self.next_link(link);
+                if tuple.key_match(key) {
+                    return Some(tuple.clone());
+                }
+            }
+            None
+        })
+    }
+}
+
+/// The state of an in-flight AMAC chain walk.
+enum Lookup {
+    Empty,
+    /// The bucket head is being prefetched.
+    Head(Key, usize),
+    /// The tuple at the link and its next link are being prefetched.
+    Chain(Key, u32),
+}
+
+/// The buckets of a batch of tuples are computed at once. Matches are produced in the
+/// order of the outer tuples, except with AMAC. Must not be probed while tuples are being
+/// inserted.
+impl ProbeTable for LockFreeHashTable {
+    fn probe(&self, tuples: &[Tuple], prefetch: Prefetch, mut f: impl FnMut(Tuple)) {
+        match prefetch {
+            Prefetch::None => {
+                let mut indices = [0; PROBE_BATCH_SIZE];
+                for batch in tuples.chunks(PROBE_BATCH_SIZE) {
+                    let indices = &mut indices[..batch.len()];
+                    simd::bucket_indices(batch, self.bucket_num, indices);
+                    for (tuple, &idx) in batch.iter().zip(indices.iter()) {
+                        let link = self.heads[idx].load(Ordering::Acquire);
+                        self.walk(link, tuple.key(), &mut f);
+                    }
+                }
+            }
+            Prefetch::Group { distance } => self.probe_group(tuples, distance, f),
+            Prefetch::Amac { distance } => self.probe_amac(tuples, distance, f),
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::collections::HashMap;
+
+    use proptest::prelude::*;
+    use rayon::prelude::*;
+
+    use super::*;
+    use crate::join_benchmark::hash_table::model;
+
+    #[test]
+    fn test_lock_free_hash_table() {
+        let hash_table = LockFreeHashTable::new(4, 40).unwrap();
+        hash_table.insert_batch(&(0..32).map(Tuple::new).collect::<Vec<_>>());
+        hash_table.insert(Tuple::new(7));
+        for key in 0..32 {
+            let expected = if key == 7 { 2 } else { 1 };
+            assert_eq!(hash_table.get_matching_tuples(key).count(), expected);
+        }
+        assert_eq!(hash_table.get_matching_tuples(32).count(), 0);
+        assert!(LockFreeHashTable::new(3, 1).is_err());
+
+        let outer = [31, 32, 7].map(Tuple::new);
+        for prefetch in [
+            Prefetch::None,
+            Prefetch::Group { distance: 2 },
+            Prefetch::Amac { distance: 2 },
+        ] {
+            let mut matches = vec![];
+            hash_table.probe(&outer, prefetch, |tuple| matches.push(tuple.key()));
+            if let Prefetch::Amac { .. } = prefetch {
+                matches.sort_unstable();
+                assert_eq!(matches, vec![7, 7, 31]);
+            } else {
+                assert_eq!(matches, vec![31, 7, 7]);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "full")]
+    fn test_lock_free_hash_table_full() {
+        let hash_table = LockFreeHashTable::new(4, 2).unwrap();
+        hash_table.insert_batch(&[1, 2, 3].map(Tuple::new));
+    }
+
+    /// Concurrent inserts into few buckets followed by concurrent probes. Under Miri,
+    /// this checks that the probes do not race with the inserts.
+    #[test]
+    fn test_concurrent_inserts_then_probe() {
+        let hash_table = LockFreeHashTable::new(2, 32).unwrap();
+        std::thread::scope(|s| {
+            for thread in 0..4 {
+                let hash_table = &hash_table;
+                s.spawn(move || {
+                    let tuples = (0..8)
+                        .map(|key| Tuple::new(key + thread))
+                        .collect::<Vec<_>>();
+                    hash_table.insert_batch(&tuples[..4]);
+                    tuples[4..]
+                        .iter()
+                        .for_each(|t| hash_table.insert(t.clone()));
+                });
+            }
+        });
+        std::thread::scope(|s| {
+            for key in 0..11 {
+                let hash_table = &hash_table;
+                s.spawn(move || {
+                    let expected = (0..4).filter(|thread| (*thread..thread + 8).contains(&key));
+                    assert_eq!(
+                        hash_table.get_matching_tuples(key).count(),
+                        expected.count()
+                    );
+                });
+            }
+        });
+    }
+
+    proptest! {
+        /// Tuples with the same key are equal, so the order does not matter to the model.
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_lock_free_hash_table_matches_model(
+            bucket_num in model::bucket_nums(),
+            ops in model::ops(),
+        ) {
+            let mut hash_table = LockFreeHashTable::new(bucket_num, ops.len()).unwrap();
+            model::check_against_model(
+                &mut hash_table,
+                &ops,
+                |table, tuple| table.insert(tuple),
+                |table, key| table.get_matching_tuples(key).collect(),
+            )?;
+        }
+
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_lock_free_hash_table_parallel_inserts(
+            bucket_num in model::bucket_nums(),
+            keys in prop::collection::vec(model::keys(), 0..1000),
+        ) {
+            let hash_table = LockFreeHashTable::new(bucket_num, keys.len()).unwrap();
+            keys.par_chunks(7)
+                .for_each(|keys| {
+                    hash_table.insert_batch(&keys.iter().copied().map(Tuple::new).collect::<Vec<_>>())
+                });
+
+            let mut counts = HashMap::<Key, usize>::new();
+            for &key in &keys {
+                *counts.entry(key).or_default() += 1;
+            }
+            for (key, count) in counts {
+                prop_assert_eq!(
+                    hash_table.get_matching_tuples(key).collect::<Vec<_>>(),
+                    vec![Tuple::new(key); count]
+                );
+            }
+        }
+    }
+}
+
+/// Run with `RUSTFLAGS="--cfg loom" cargo test --release --lib loom_`.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::{sync::Arc, thread};
+
+    use super::*;
+
+    /// Two threads link tuples into the same bucket, so that their compare-and-swaps
+    /// contend and one of them retries.
+    #[test]
+    fn loom_concurrent_links() {
+        loom::model(|| {
+            let hash_table = Arc::new(LockFreeHashTable::new(1, 3).unwrap());
+            let thread = {
+                let hash_table = hash_table.clone();
+                thread::spawn(move || hash_table.insert_batch(&[Tuple::new(1), Tuple::new(2)]))
+            };
+            hash_table.insert(Tuple::new(1));
+            thread.join().unwrap();
+            assert_eq!(hash_table.get_matching_tuples(1).count(), 2);
+            assert_eq!(hash_table.get_matching_tuples(2).count(), 1);
+        });
+    }
+
+    /// A probe that runs while a tuple is linked either misses it or reads the slot
+    /// after it was written, as the bucket head is published with release ordering.
+    #[test]
+    fn loom_probe_reads_written_slots() {
+        loom::model(|| {
+            let hash_table = Arc::new(LockFreeHashTable::new(1, 1).unwrap());
+            let thread = {
+                let hash_table = hash_table.clone();
+                thread::spawn(move || hash_table.get_matching_tuples(1).count())
+            };
+            hash_table.insert(Tuple::new(1));
+            assert!(thread.join().unwrap() <= 1);
+        });
+    }
+}
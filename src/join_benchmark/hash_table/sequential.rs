@@ -1,5 +1,9 @@
 use crate::{
-    join_benchmark::{bucket_hash, hash_bucket::HashBucket},
+    join_benchmark::{
+        bucket_hash,
+        hash_bucket::HashBucket,
+        prefetch::{self, BucketTable, Prefetch, ProbeTable},
+    },
     tuple::{Key, Tuple},
 };
 
@@ -36,15 +40,37 @@ where
         bucket.push(tuple);
     }
 
-    pub fn get_matching_tuples(&self, key: Key) -> Option<Tuple> {
+    /// All tuples with the given key, in insertion order.
+    pub fn get_matching_tuples(&self, key: Key) -> impl Iterator<Item = Tuple> + '_ {
         let bucket = &self.buckets[bucket_hash(key) as usize & (self.bucket_num - 1)];
-        bucket.iter().find(move |t| t.key_match(key)).cloned()
+        bucket.iter().filter(move |t| t.key_match(key)).cloned()
+    }
+}
+
+impl<B: HashBucket> BucketTable for SequentialHashTable<B> {
+    type Bucket = B;
+
+    fn bucket_num(&self) -> usize {
+        self.bucket_num
+    }
+
+    fn bucket_at(&self, idx: usize) -> &B {
+        &self.buckets[idx]
+    }
+}
+
+impl<B: HashBucket> ProbeTable for SequentialHashTable<B> {
+    fn probe(&self, tuples: &[Tuple], prefetch: Prefetch, f: impl FnMut(Tuple)) {
+        prefetch::probe(self, tuples, prefetch, f)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
+    use crate::join_benchmark::hash_table::model;
 
     #[test]
     fn test_sequential_hash_table() {
@@ -52,13 +78,55 @@ mod tests {
         let tuple = Tuple::new(1);
         hash_table.insert(tuple.clone());
 
-        assert_eq!(hash_table.get_matching_tuples(1), Some(tuple));
+        assert_eq!(
+            hash_table.get_matching_tuples(1).collect::<Vec<_>>(),
+            vec![tuple]
+        );
 
-        assert_eq!(hash_table.get_matching_tuples(2), None);
+        assert_eq!(hash_table.get_matching_tuples(2).count(), 0);
 
         let tuple = Tuple::new(2);
         hash_table.insert(tuple.clone());
 
-        assert_eq!(hash_table.get_matching_tuples(2), Some(tuple));
+        assert_eq!(
+            hash_table.get_matching_tuples(2).collect::<Vec<_>>(),
+            vec![tuple]
+        );
+    }
+
+    /// Every tuple of a key matches, and only the tuples of that key.
+    #[test]
+    fn test_sequential_hash_table_duplicate_keys() {
+        let mut hash_table = SequentialHashTable::<Vec<Tuple>>::new(16).unwrap();
+        let tuple = Tuple::new(1);
+        hash_table.insert(tuple.clone());
+        hash_table.insert(tuple.clone());
+        hash_table.insert(Tuple::new(17));
+
+        assert_eq!(
+            hash_table.get_matching_tuples(1).collect::<Vec<_>>(),
+            vec![tuple.clone(), tuple]
+        );
+        assert_eq!(
+            hash_table.get_matching_tuples(17).collect::<Vec<_>>(),
+            vec![Tuple::new(17)]
+        );
+    }
+
+    proptest! {
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_sequential_hash_table_matches_model(
+            bucket_num in model::bucket_nums(),
+            ops in model::ops(),
+        ) {
+            let mut hash_table = SequentialHashTable::<Vec<Tuple>>::new(bucket_num).unwrap();
+            model::check_against_model(
+                &mut hash_table,
+                &ops,
+                |table, tuple| table.insert(tuple),
+                |table, key| table.get_matching_tuples(key).collect(),
+            )?;
+        }
     }
 }
@@ -0,0 +1,201 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use parking_lot::Mutex;
+use rayon::{iter::ParallelIterator, slice::ParallelSlice};
+
+use super::{
+    bloom::{BloomFilter, ProbeFilter},
+    hash_bucket::HashBucket,
+    hash_table::sequential::SequentialHashTable,
+    partition_hash,
+    prefetch::{BucketTable, PROBE_BATCH_SIZE, PROBE_MORSEL_SIZE},
+    simd, thread_chunk_size, BucketSizing, HashJoinBenchmark, JoinOutput, NoOutput, SchedulingType,
+    STATIC_SCHEDULING,
+};
+use crate::{
+    tuple::{DataChunk, Tuple},
+    work::WorkTracker,
+};
+
+/// Which private tables an outer tuple is probed against.
+pub type ProbeRouting = bool;
+pub const BROADCAST_PROBE: ProbeRouting = false;
+pub const ROUTED_PROBE: ProbeRouting = true;
+
+/// Tuples that a thread takes at once when building with dynamic scheduling.
+const MORSEL_SIZE: usize = 1024;
+
+/// Each thread builds a private hash table without any synchronization, and the probe
+/// phase looks up the private tables.
+///
+/// With `BROADCAST_PROBE`, each thread builds its table from its share of the inner
+/// relation, so every outer tuple has to probe all tables. With `ROUTED_PROBE`, every
+/// thread scans the whole inner relation and keeps the tuples whose partition hash
+/// belongs to it, so every outer tuple probes only one table. Either way, work is
+/// duplicated instead of shuffling tuples between threads as `PartitionedHashJoin` does.
+/// The routed build ignores the scheduling method, since each thread scans everything.
+pub struct IndependentHashJoin<const S: SchedulingType, const R: ProbeRouting> {
+    inner: Option<Vec<DataChunk>>,
+    outer: Option<Vec<DataChunk>>,
+    buckets: BucketSizing,
+    /// Build a Bloom filter with this many bits per key shared by all threads, and probe
+    /// only the outer tuples that pass it.
+    bloom_bits_per_key: Option<usize>,
+}
+
+impl<const S: SchedulingType, const R: ProbeRouting> IndependentHashJoin<S, R> {
+    pub fn new(
+        buckets: BucketSizing,
+        bloom_bits_per_key: Option<usize>,
+        inner: Vec<DataChunk>,
+        outer: Vec<DataChunk>,
+    ) -> Self {
+        Self {
+            inner: Some(inner),
+            outer: Some(outer),
+            buckets,
+            bloom_bits_per_key,
+        }
+    }
+
+    /// The table that holds the tuples with the given key in `ROUTED_PROBE`.
+    fn table_idx(tuple: &Tuple, table_num: usize) -> usize {
+        partition_hash(tuple.key()) as usize % table_num
+    }
+
+    /// Probe the tables with the tuples that pass the filter. All tables have the same
+    /// number of buckets, so the bucket of a key is computed once for all tables.
+    fn probe_tuples(
+        (tables, filter): &<Self as HashJoinBenchmark>::BuildOutput,
+        tuples: &[Tuple],
+        output: &mut JoinOutput,
+    ) {
+        ProbeFilter::new(filter.as_ref())
+            .probe(tuples, |tuples| Self::probe_tables(tables, tuples, output));
+    }
+
+    fn probe_tables(
+        tables: &[SequentialHashTable<Vec<Tuple>>],
+        tuples: &[Tuple],
+        output: &mut JoinOutput,
+    ) {
+        let mut indices = [0; PROBE_BATCH_SIZE];
+        for batch in tuples.chunks(PROBE_BATCH_SIZE) {
+            let indices = &mut indices[..batch.len()];
+            simd::bucket_indices(batch, tables[0].bucket_num(), indices);
+            for (tuple, &idx) in batch.iter().zip(indices.iter()) {
+                let tables = if R == ROUTED_PROBE {
+                    let table_idx = Self::table_idx(tuple, tables.len());
+                    &tables[table_idx..table_idx + 1]
+                } else {
+                    tables
+                };
+                for table in tables {
+                    table.bucket_at(idx).for_each_match(tuple.key(), |tuple| {
+                        Self::produce_tuple(tuple);
+                        output.push(tuple);
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl<const S: SchedulingType, const R: ProbeRouting> HashJoinBenchmark
+    for IndependentHashJoin<S, R>
+{
+    type PartitionOutput = NoOutput;
+    type BuildOutput = (Vec<SequentialHashTable<Vec<Tuple>>>, Option<BloomFilter>);
+
+    fn partition(&mut self) -> Self::PartitionOutput {}
+
+    fn build(&mut self, _: Self::PartitionOutput) -> Self::BuildOutput {
+        let inner = self.inner.take().unwrap();
+        let inner_tuple_num = inner.iter().map(Vec::len).sum::<usize>();
+        // All tables have the same number of buckets, sized for an equal share of the
+        // inner tuples.
+        let num_threads = rayon::current_num_threads();
+        let bucket_num = self
+            .buckets
+            .bucket_num(inner_tuple_num.div_ceil(num_threads), num_threads);
+        let morsels = inner
+            .iter()
+            .flat_map(|chunk| chunk.chunks(MORSEL_SIZE))
+            .collect::<Vec<_>>();
+        let next_morsel = AtomicUsize::new(0);
+        let filter = self
+            .bloom_bits_per_key
+            .map(|bits| BloomFilter::new(inner_tuple_num, bits));
+        // Run once on every thread of the pool, so that there is one table per thread.
+        let tables = rayon::broadcast(|ctx| {
+            let mut work = WorkTracker::start();
+            let mut hash_table = SequentialHashTable::<Vec<Tuple>>::new(bucket_num).unwrap();
+            let mut insert = |tuple: &Tuple| {
+                if let Some(filter) = &filter {
+                    filter.insert_concurrent(tuple.key());
+                }
+                hash_table.insert(tuple.clone());
+            };
+            if R == ROUTED_PROBE {
+                for chunk in &inner {
+                    for tuple in chunk {
+                        if Self::table_idx(tuple, ctx.num_threads()) == ctx.index() {
+                            insert(tuple);
+                        }
+                    }
+                    work.add(chunk.len());
+                }
+            } else if S == STATIC_SCHEDULING {
+                // Each thread takes an equal part of every chunk.
+                for chunk in &inner {
+                    let thread_chunk_size = thread_chunk_size(chunk.len(), ctx.num_threads());
+                    if let Some(chunk) = chunk.chunks(thread_chunk_size).nth(ctx.index()) {
+                        chunk.iter().for_each(&mut insert);
+                        work.add(chunk.len());
+                    }
+                }
+            } else {
+                while let Some(morsel) = morsels.get(next_morsel.fetch_add(1, Ordering::Relaxed)) {
+                    morsel.iter().for_each(&mut insert);
+                    work.add(morsel.len());
+                }
+            }
+            hash_table
+        });
+        (tables, filter)
+    }
+
+    fn probe(&mut self, tables: Self::BuildOutput) -> JoinOutput {
+        let output = Mutex::new(JoinOutput::default());
+        for chunk in self.outer.take().unwrap() {
+            if S == STATIC_SCHEDULING {
+                // Divide the chunks into equal parts for each thread.
+                let num_threads = rayon::current_num_threads();
+                let thread_chunk_size = thread_chunk_size(chunk.len(), num_threads);
+                let thread_chunks = chunk.chunks(thread_chunk_size).collect::<Vec<_>>();
+                rayon::scope(|s| {
+                    for chunk in thread_chunks {
+                        s.spawn(|_| {
+                            let mut work = WorkTracker::start();
+                            let mut thread_output = JoinOutput::default();
+                            Self::probe_tuples(&tables, chunk, &mut thread_output);
+                            work.add(chunk.len());
+                            *output.lock() += thread_output;
+                        });
+                    }
+                });
+            } else {
+                *output.lock() += chunk
+                    .par_chunks(PROBE_MORSEL_SIZE)
+                    .map_init(WorkTracker::start, |work, morsel| {
+                        let mut output = JoinOutput::default();
+                        Self::probe_tuples(&tables, morsel, &mut output);
+                        work.add(morsel.len());
+                        output
+                    })
+                    .sum();
+            }
+        }
+        output.into_inner()
+    }
+}
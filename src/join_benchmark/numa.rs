@@ -0,0 +1,272 @@
+//! NUMA topology and node-local scheduling of partitions.
+//!
+//! Each of the `len` items of a phase, e.g. partitions, has a home node: the threads of
+//! the rayon pool are spread over the nodes in contiguous blocks and pinned to the CPUs
+//! of their node, and each thread owns an equal contiguous range of items, as with
+//! static scheduling. Memory that a thread allocates and touches first is placed on its
+//! node by the kernel, so items allocated by their owners live on their home nodes, and
+//! are then processed by threads of that node first.
+
+use std::{
+    ops::Range,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use super::thread_chunk_size;
+
+/// The CPUs of the NUMA nodes of the machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    /// The CPUs of each node that has any.
+    nodes: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    /// The nodes with CPUs of the machine, or `None` if there are fewer than two or they
+    /// cannot be read, in which case placement would make no difference.
+    pub fn detect() -> Option<Topology> {
+        Self::read(Path::new("/sys/devices/system/node"))
+            .ok()
+            .filter(|topology| topology.node_num() > 1)
+    }
+
+    fn read(dir: &Path) -> Result<Topology> {
+        let online = std::fs::read_to_string(dir.join("online"))?;
+        let mut nodes = Vec::new();
+        for node in parse_cpu_list(&online)? {
+            let path = dir.join(format!("node{}", node)).join("cpulist");
+            let cpus = std::fs::read_to_string(&path)
+                .with_context(|| format!("cannot read {}", path.display()))?;
+            let cpus = parse_cpu_list(&cpus)?;
+            // Memory-only nodes have no threads to process items.
+            if !cpus.is_empty() {
+                nodes.push(cpus);
+            }
+        }
+        Ok(Topology::new(nodes))
+    }
+
+    pub fn new(nodes: Vec<Vec<usize>>) -> Topology {
+        Topology { nodes }
+    }
+
+    pub fn node_num(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The node of thread `thread` of `thread_num`.
+    fn node_of_thread(&self, thread: usize, thread_num: usize) -> usize {
+        thread * self.nodes.len() / thread_num
+    }
+
+    /// Compute `f` for each of `len` items on the threads of the current rayon pool,
+    /// while each thread is pinned to its node. Without `steal`, each thread computes its
+    /// own range of items. With it, the threads of a node share the items of the node,
+    /// and then help the other nodes in turn. Returns the results in item order.
+    pub fn map_items<R: Send>(
+        &self,
+        len: usize,
+        steal: bool,
+        f: impl Fn(usize) -> R + Sync,
+    ) -> Vec<R> {
+        let thread_num = rayon::current_num_threads();
+        let chunk_size = thread_chunk_size(len, thread_num);
+        let thread_items =
+            |thread: usize| (thread * chunk_size).min(len)..((thread + 1) * chunk_size).min(len);
+        let node_items = (0..self.nodes.len())
+            .map(|node| {
+                let mut threads = (0..thread_num)
+                    .filter(|&thread| self.node_of_thread(thread, thread_num) == node);
+                match (threads.next(), threads.next_back()) {
+                    (Some(first), last) => {
+                        thread_items(first).start..thread_items(last.unwrap_or(first)).end
+                    }
+                    (None, _) => 0..0,
+                }
+            })
+            .collect::<Vec<Range<usize>>>();
+        let next_items = node_items
+            .iter()
+            .map(|items| AtomicUsize::new(items.start))
+            .collect::<Vec<_>>();
+
+        let thread_results = rayon::broadcast(|context| {
+            let node = self.node_of_thread(context.index(), context.num_threads());
+            let _pinned = Pinned::new(&self.nodes[node]);
+            let mut results = Vec::new();
+            if steal {
+                for other in (0..self.nodes.len()).map(|i| (node + i) % self.nodes.len()) {
+                    loop {
+                        let item = next_items[other].fetch_add(1, Ordering::Relaxed);
+                        if item >= node_items[other].end {
+                            break;
+                        }
+                        results.push((item, f(item)));
+                    }
+                }
+            } else {
+                results.extend(thread_items(context.index()).map(|item| (item, f(item))));
+            }
+            results
+        });
+
+        let mut results = (0..len).map(|_| None).collect::<Vec<_>>();
+        for (item, result) in thread_results.into_iter().flatten() {
+            results[item] = Some(result);
+        }
+        results
+            .into_iter()
+            .map(|result| result.expect("every item is computed once"))
+            .collect()
+    }
+}
+
+/// Restricts the current thread to the CPUs of a node until dropped, when the previous
+/// affinity of the thread is restored, so that the threads of the pool are only pinned
+/// while they compute items.
+struct Pinned {
+    previous: Option<libc::cpu_set_t>,
+}
+
+impl Pinned {
+    /// Pinning is best effort, as the CPUs may be outside of the cgroup of the process.
+    fn new(cpus: &[usize]) -> Pinned {
+        // SAFETY: `cpu_set_t` is a plain bit set, for which all zeros is the empty set.
+        unsafe {
+            let mut previous: libc::cpu_set_t = std::mem::zeroed();
+            let saved =
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut previous)
+                    == 0;
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            for &cpu in cpus.iter().filter(|&&cpu| cpu < libc::CPU_SETSIZE as usize) {
+                libc::CPU_SET(cpu, &mut set);
+            }
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set);
+            Pinned {
+                previous: saved.then_some(previous),
+            }
+        }
+    }
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        if let Some(previous) = &self.previous {
+            // SAFETY: `previous` is the affinity read in `new`.
+            unsafe {
+                libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), previous);
+            }
+        }
+    }
+}
+
+/// Parse a list of CPUs or nodes in the kernel's format, e.g. "0-3,8,10-11".
+fn parse_cpu_list(s: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for item in s.trim().split(',').filter(|item| !item.is_empty()) {
+        match item.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (start.parse::<usize>()?, end.parse::<usize>()?);
+                if start > end {
+                    return Err(anyhow!("invalid CPU range: {}", item));
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(item.parse()?),
+        }
+    }
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n").unwrap(),
+            [0, 1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(parse_cpu_list("5").unwrap(), [5]);
+        assert!(parse_cpu_list("\n").unwrap().is_empty());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_topology() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("online"), "0-2\n").unwrap();
+        for (node, cpus) in [(0, "0-1,4-5"), (1, "2-3,6-7"), (2, "")] {
+            let node_dir = dir.path().join(format!("node{}", node));
+            std::fs::create_dir(&node_dir).unwrap();
+            std::fs::write(node_dir.join("cpulist"), format!("{}\n", cpus)).unwrap();
+        }
+        assert_eq!(
+            Topology::read(dir.path()).unwrap(),
+            Topology::new(vec![vec![0, 1, 4, 5], vec![2, 3, 6, 7]])
+        );
+        assert!(Topology::read(&dir.path().join("missing")).is_err());
+    }
+
+    fn current_cpus() -> Vec<usize> {
+        // SAFETY: `cpu_set_t` is a plain bit set, for which all zeros is the empty set.
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+            (0..libc::CPU_SETSIZE as usize)
+                .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+                .collect()
+        }
+    }
+
+    /// Threads are pinned to their node while they compute items, and get their previous
+    /// affinity back afterwards.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_map_items_restores_affinity() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let before = pool.broadcast(|_| current_cpus());
+        let node = vec![before[0][0]];
+        let topology = Topology::new(vec![node.clone(), node.clone()]);
+        let during = pool.install(|| topology.map_items(2, false, |_| current_cpus()));
+        assert_eq!(during, [node.clone(), node]);
+        assert_eq!(pool.broadcast(|_| current_cpus()), before);
+    }
+
+    /// Every item is computed once, on a thread of its home node unless stolen. Both
+    /// nodes have the CPUs of the machine, so that pinning works anywhere.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_map_items() {
+        let cpus =
+            (0..std::thread::available_parallelism().map_or(1, |n| n.get())).collect::<Vec<_>>();
+        let topology = Topology::new(vec![cpus.clone(), cpus]);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        for len in [0, 3, 10, 64] {
+            for steal in [false, true] {
+                let threads = pool.install(|| {
+                    topology.map_items(len, steal, |_| rayon::current_thread_index().unwrap())
+                });
+                assert_eq!(threads.len(), len);
+                if !steal {
+                    let chunk_size = thread_chunk_size(len, 4);
+                    for (item, thread) in threads.into_iter().enumerate() {
+                        assert_eq!(thread, item / chunk_size);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,33 +1,68 @@
+use parking_lot::Mutex;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
-use crate::tuple::{DataChunk, Tuple};
+use crate::{
+    tuple::{DataChunk, Tuple},
+    work::WorkTracker,
+};
 
 use super::{
-    hash_table::sequential::SequentialHashTable, partition_hash, HashJoinBenchmark, SchedulingType,
-    STATIC_SCHEDULING,
+    bloom::{self, BloomFilter, ProbeFilter},
+    hash_table::sequential::SequentialHashTable,
+    numa::Topology,
+    partition_hash, prefetch, thread_chunk_size, BucketSizing, HashJoinBenchmark, JoinOutput,
+    SchedulingType, DYNAMIC_SCHEDULING, STATIC_SCHEDULING,
 };
 
 #[derive(Clone)]
 pub struct Partition {
-    inner_buffer: boxcar::Vec<Tuple>,
-    outer_buffer: boxcar::Vec<Tuple>,
-    hash_table: SequentialHashTable<Vec<Tuple>>,
+    pub(super) inner_buffer: boxcar::Vec<Tuple>,
+    pub(super) outer_buffer: boxcar::Vec<Tuple>,
 }
 
 impl Partition {
-    fn new(bucket_num: usize) -> Self {
+    pub(super) fn new() -> Self {
         Self {
             inner_buffer: boxcar::Vec::new(),
             outer_buffer: boxcar::Vec::new(),
-            hash_table: SequentialHashTable::new(bucket_num).unwrap(),
         }
     }
 
-    /// Move the tuples from the inner relation buffer to the hash table.
-    fn populate_hash_table(&mut self) {
+    /// A partition whose buffers are allocated up front, so that their memory is placed
+    /// on the NUMA node of the calling thread. `boxcar` initializes the flag of every
+    /// entry of the buckets it allocates, which faults their pages in on this thread
+    /// before any tuple is pushed.
+    fn with_capacity(inner_capacity: usize, outer_capacity: usize) -> Self {
+        Self {
+            inner_buffer: boxcar::Vec::with_capacity(inner_capacity),
+            outer_buffer: boxcar::Vec::with_capacity(outer_capacity),
+        }
+    }
+
+    /// Move the tuples from the inner relation buffer to a hash table sized by `buckets`
+    /// as one of `partition_num` tables, and build the Bloom filter of the partition if
+    /// `bloom_bits_per_key` is given.
+    fn populate_hash_table(
+        mut self,
+        buckets: BucketSizing,
+        partition_num: usize,
+        bloom_bits_per_key: Option<usize>,
+    ) -> ProbePartition {
+        let filter = bloom::build(
+            bloom_bits_per_key,
+            self.inner_buffer.count(),
+            self.inner_buffer.iter().map(|(_, tuple)| tuple),
+        );
+        let bucket_num = buckets.bucket_num(self.inner_buffer.count(), partition_num);
+        let mut hash_table = SequentialHashTable::new(bucket_num).unwrap();
         // Insert all tuples in the buffer into the hash table.
         for tuple in std::mem::take(&mut self.inner_buffer).into_iter() {
-            self.hash_table.insert(tuple.clone());
+            hash_table.insert(tuple.clone());
+        }
+        ProbePartition {
+            outer_buffer: self.outer_buffer,
+            hash_table,
+            filter,
         }
     }
 }
@@ -35,38 +70,119 @@ impl Partition {
 pub struct ProbePartition {
     outer_buffer: boxcar::Vec<Tuple>,
     hash_table: SequentialHashTable<Vec<Tuple>>,
+    filter: Option<BloomFilter>,
 }
 
-impl From<Partition> for ProbePartition {
-    fn from(partition: Partition) -> Self {
-        Self {
-            outer_buffer: partition.outer_buffer,
-            hash_table: partition.hash_table,
-        }
+impl ProbePartition {
+    /// Probe the hash table with the outer tuples that pass the Bloom filter, if any.
+    fn probe(&self, f: impl FnMut(Tuple)) {
+        let mut filter = ProbeFilter::new(self.filter.as_ref());
+        let outer = self
+            .outer_buffer
+            .iter()
+            .map(|(_, t)| t)
+            .filter(|t| filter.pass(t));
+        prefetch::probe_iter(&self.hash_table, outer, f);
+    }
+}
+
+/// Push every tuple of `chunk` to the buffer of its partition, selected by `buffer`.
+pub(super) fn partition_chunk<const S: SchedulingType>(
+    chunk: DataChunk,
+    partitions: &[Partition],
+    buffer: fn(&Partition) -> &boxcar::Vec<Tuple>,
+) {
+    let partition_num = partitions.len();
+    if S == STATIC_SCHEDULING {
+        let num_threads = rayon::current_num_threads();
+        let thread_chunk_size = thread_chunk_size(chunk.len(), num_threads);
+        let thread_chunks = chunk.chunks(thread_chunk_size).collect::<Vec<_>>();
+        rayon::scope(|s| {
+            for chunk in thread_chunks.into_iter() {
+                s.spawn(|_| {
+                    let mut work = WorkTracker::start();
+                    chunk.iter().for_each(|tuple| {
+                        let partition_idx =
+                            partition_hash(tuple.key()) as usize & (partition_num - 1);
+                        buffer(&partitions[partition_idx]).push(tuple.clone());
+                    });
+                    work.add(chunk.len());
+                });
+            }
+        });
+    } else {
+        chunk
+            .into_par_iter()
+            .for_each_init(WorkTracker::start, |work, tuple| {
+                let partition_idx = partition_hash(tuple.key()) as usize & (partition_num - 1);
+                buffer(&partitions[partition_idx]).push(tuple);
+                work.add(1);
+            });
     }
 }
 
 pub struct PartitionedHashJoin<const S: SchedulingType> {
     inner: Option<Vec<DataChunk>>,
     outer: Option<Vec<DataChunk>>,
-    bucket_num: usize,
+    buckets: BucketSizing,
     partition_num: usize,
+    /// Build a Bloom filter with this many bits per key for each partition and probe only
+    /// the outer tuples that pass it.
+    bloom_bits_per_key: Option<usize>,
+    /// Place each partition on the node of the threads that own it, and process
+    /// partitions on their own nodes first.
+    numa: Option<Topology>,
 }
 
 impl<const S: SchedulingType> PartitionedHashJoin<S> {
     pub fn new(
-        bucket_num: usize,
+        buckets: BucketSizing,
         partition_num: usize,
+        bloom_bits_per_key: Option<usize>,
+        numa: Option<Topology>,
         inner: Vec<DataChunk>,
         outer: Vec<DataChunk>,
     ) -> Self {
         Self {
             inner: Some(inner),
             outer: Some(outer),
-            bucket_num,
+            buckets,
             partition_num,
+            bloom_bits_per_key,
+            numa,
         }
     }
+
+    /// Build the hash table of each partition on a thread of its node.
+    fn build_on_nodes(&self, numa: &Topology, partitions: Vec<Partition>) -> Vec<ProbePartition> {
+        let partitions = partitions
+            .into_iter()
+            .map(|p| Mutex::new(Some(p)))
+            .collect::<Vec<_>>();
+        numa.map_items(partitions.len(), S == DYNAMIC_SCHEDULING, |i| {
+            let p = partitions[i].lock().take().unwrap();
+            let mut work = WorkTracker::start();
+            work.add(p.inner_buffer.count());
+            p.populate_hash_table(self.buckets, self.partition_num, self.bloom_bits_per_key)
+        })
+    }
+
+    /// Probe each partition on a thread of its node.
+    fn probe_on_nodes(numa: &Topology, partitions: Vec<ProbePartition>) -> JoinOutput {
+        numa.map_items(partitions.len(), S == DYNAMIC_SCHEDULING, |i| {
+            let partition = &partitions[i];
+            let mut work = WorkTracker::start();
+            work.add(partition.outer_buffer.count());
+            let mut output = JoinOutput::default();
+            partition.probe(|tuple| {
+                Self::produce_tuple(&tuple);
+                output.push(&tuple);
+            });
+            output
+        })
+        .into_iter()
+        .sum()
+    }
 }
 
 impl<const S: SchedulingType> HashJoinBenchmark for PartitionedHashJoin<S> {
@@ -74,103 +190,280 @@ impl<const S: SchedulingType> HashJoinBenchmark for PartitionedHashJoin<S> {
     type BuildOutput = Vec<ProbePartition>;
 
     fn partition(&mut self) -> Self::PartitionOutput {
-        let partitions =
-            vec![Partition::new(self.bucket_num / self.partition_num); self.partition_num];
-        // Partition inner table.
-        for chunk in self.inner.take().unwrap() {
-            if S == STATIC_SCHEDULING {
-                let num_threads = rayon::current_num_threads();
-                let thread_chunk_size = chunk.len() / num_threads;
-                let thread_chunks = chunk.chunks(thread_chunk_size).collect::<Vec<_>>();
-                rayon::scope(|s| {
-                    for chunk in thread_chunks.into_iter() {
-                        s.spawn(|_| {
-                            chunk.iter().for_each(|tuple| {
-                                let partition_idx =
-                                    partition_hash(tuple.key()) as usize & (self.partition_num - 1);
-                                partitions[partition_idx].inner_buffer.push(tuple.clone());
-                            });
-                        });
-                    }
-                });
-            } else {
-                chunk.into_par_iter().for_each(|tuple| {
-                    let partition_idx =
-                        partition_hash(tuple.key()) as usize & (self.partition_num - 1);
-                    partitions[partition_idx as usize].inner_buffer.push(tuple);
-                });
+        let partitions = match &self.numa {
+            // Buffers beyond the expected partition sizes are allocated by the threads
+            // that fill them.
+            Some(numa) => {
+                let tuple_num = |chunks: &Option<Vec<DataChunk>>| {
+                    chunks.iter().flatten().map(Vec::len).sum::<usize>()
+                };
+                let inner_capacity = tuple_num(&self.inner).div_ceil(self.partition_num);
+                let outer_capacity = tuple_num(&self.outer).div_ceil(self.partition_num);
+                numa.map_items(self.partition_num, false, |_| {
+                    Partition::with_capacity(inner_capacity, outer_capacity)
+                })
             }
+            None => vec![Partition::new(); self.partition_num],
+        };
+        for chunk in self.inner.take().unwrap() {
+            partition_chunk::<S>(chunk, &partitions, |p| &p.inner_buffer);
         }
-        // Partition outer table.
         for chunk in self.outer.take().unwrap() {
-            if S == STATIC_SCHEDULING {
-                let num_threads = rayon::current_num_threads();
-                let thread_chunk_size = chunk.len() / num_threads;
-                let thread_chunks = chunk.chunks(thread_chunk_size).collect::<Vec<_>>();
-                rayon::scope(|s| {
-                    for chunk in thread_chunks.into_iter() {
-                        s.spawn(|_| {
-                            chunk.iter().for_each(|tuple| {
-                                let partition_idx =
-                                    partition_hash(tuple.key()) as usize & (self.partition_num - 1);
-                                partitions[partition_idx].outer_buffer.push(tuple.clone());
-                            });
-                        });
-                    }
-                });
-            } else {
-                chunk.into_par_iter().for_each(|tuple| {
-                    let partition_idx =
-                        partition_hash(tuple.key()) as usize & (self.partition_num - 1);
-                    partitions[partition_idx as usize].outer_buffer.push(tuple);
-                });
-            }
+            partition_chunk::<S>(chunk, &partitions, |p| &p.outer_buffer);
         }
         partitions
     }
 
     fn build(&mut self, partitions: Self::PartitionOutput) -> Self::BuildOutput {
+        if let Some(numa) = &self.numa {
+            return self.build_on_nodes(numa, partitions);
+        }
         // Static scheduling is hard to implement because Rust does not allow multiple
         // threads to modify disjoint parts of the same vector. But the build phase is
         // very cheap, and inner relation is uniform, so the scheduling method should
         // not matter.
         partitions
             .into_par_iter()
-            .map(|mut p| {
-                p.populate_hash_table();
-                p.into()
+            .map_init(WorkTracker::start, |work, p| {
+                work.add(p.inner_buffer.count());
+                p.populate_hash_table(self.buckets, self.partition_num, self.bloom_bits_per_key)
             })
             .collect()
     }
 
-    fn probe(&mut self, partitions: Self::BuildOutput) {
-        if S == STATIC_SCHEDULING {
+    fn probe(&mut self, partitions: Self::BuildOutput) -> JoinOutput {
+        if let Some(numa) = &self.numa {
+            Self::probe_on_nodes(numa, partitions)
+        } else if S == STATIC_SCHEDULING {
+            let output = Mutex::new(JoinOutput::default());
             let num_threads = rayon::current_num_threads();
-            let thread_partition_size = partitions.len() / num_threads;
+            let thread_partition_size = thread_chunk_size(partitions.len(), num_threads);
             let thread_partitions = partitions.chunks(thread_partition_size).collect::<Vec<_>>();
             rayon::scope(|s| {
                 for partitions in thread_partitions.into_iter() {
                     s.spawn(|_| {
+                        let mut work = WorkTracker::start();
+                        let mut thread_output = JoinOutput::default();
                         partitions.iter().for_each(|partition| {
-                            for (_, tuple) in partition.outer_buffer.iter() {
-                                partition
-                                    .hash_table
-                                    .get_matching_tuples(tuple.key())
-                                    .inspect(Self::produce_tuple);
-                            }
+                            work.add(partition.outer_buffer.count());
+                            partition.probe(|tuple| {
+                                Self::produce_tuple(&tuple);
+                                thread_output.push(&tuple);
+                            });
                         });
+                        *output.lock() += thread_output;
                     });
                 }
             });
+            output.into_inner()
         } else {
-            partitions.into_par_iter().for_each(|partition| {
-                for tuple in partition.outer_buffer.into_iter() {
-                    partition
-                        .hash_table
-                        .get_matching_tuples(tuple.key())
-                        .inspect(Self::produce_tuple);
+            partitions
+                .into_par_iter()
+                .map_init(WorkTracker::start, |work, partition| {
+                    work.add(partition.outer_buffer.count());
+                    let mut output = JoinOutput::default();
+                    partition.probe(|tuple| {
+                        Self::produce_tuple(&tuple);
+                        output.push(&tuple);
+                    });
+                    output
+                })
+                .sum()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{
+        join_benchmark::Instrumentation,
+        tuple::{Distribution, TupleGenerator},
+        verify,
+    };
+
+    fn relation() -> impl Strategy<Value = Vec<DataChunk>> {
+        let tuple = prop_oneof![4 => 0..64u64, 1 => any::<u64>()].prop_map(Tuple::new);
+        prop::collection::vec(prop::collection::vec(tuple, 0..100), 0..8)
+    }
+
+    /// Keys of a relation or buffer, sorted so that multisets can be compared.
+    fn sorted_keys<'a>(tuples: impl IntoIterator<Item = &'a Tuple>) -> Vec<u64> {
+        let mut keys = tuples.into_iter().map(Tuple::key).collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    fn check_partitions<const S: SchedulingType>(
+        partition_num: usize,
+        inner: Vec<DataChunk>,
+        outer: Vec<DataChunk>,
+    ) -> Result<(), TestCaseError> {
+        let expected_inner = sorted_keys(inner.iter().flatten());
+        let expected_outer = sorted_keys(outer.iter().flatten());
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let partitions = pool.install(|| {
+            PartitionedHashJoin::<S>::new(
+                BucketSizing::default(),
+                partition_num,
+                None,
+                None,
+                inner,
+                outer,
+            )
+            .partition()
+        });
+
+        prop_assert_eq!(partitions.len(), partition_num);
+        for (i, partition) in partitions.iter().enumerate() {
+            let buffers = partition.inner_buffer.iter().chain(&partition.outer_buffer);
+            for (_, tuple) in buffers {
+                prop_assert_eq!(
+                    partition_hash(tuple.key()) as usize & (partition_num - 1),
+                    i
+                );
+            }
+        }
+        let inner_keys = sorted_keys(
+            partitions
+                .iter()
+                .flat_map(|p| p.inner_buffer.iter())
+                .map(|(_, t)| t),
+        );
+        let outer_keys = sorted_keys(
+            partitions
+                .iter()
+                .flat_map(|p| p.outer_buffer.iter())
+                .map(|(_, t)| t),
+        );
+        prop_assert_eq!(inner_keys, expected_inner);
+        prop_assert_eq!(outer_keys, expected_outer);
+        Ok(())
+    }
+
+    /// Concurrent pushes to the partition buffers keep every tuple. Under Miri, this
+    /// checks `boxcar::Vec` for data races, which loom cannot, as boxcar uses the atomics
+    /// of `std`.
+    #[test]
+    fn test_concurrent_buffer_pushes() {
+        let buffer = boxcar::Vec::new();
+        std::thread::scope(|s| {
+            for thread in 0..4 {
+                let buffer = &buffer;
+                s.spawn(move || {
+                    for i in 0..16 {
+                        buffer.push(Tuple::new(thread * 16 + i));
+                    }
+                });
+            }
+        });
+        assert_eq!(
+            sorted_keys(buffer.iter().map(|(_, t)| t)),
+            (0..64).collect::<Vec<_>>()
+        );
+    }
+
+    /// The minor page faults of the calling thread so far.
+    fn thread_page_faults() -> i64 {
+        let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+        assert_eq!(
+            unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) },
+            0
+        );
+        usage.ru_minflt
+    }
+
+    /// The buffers of a preallocated partition are faulted in by the thread that
+    /// allocates them, so that NUMA placement does not depend on the threads that push.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_with_capacity_faults_buffers_in() {
+        let capacity = 1 << 20;
+        let before = thread_page_faults();
+        let partition = Partition::with_capacity(capacity, capacity);
+        let faults = thread_page_faults() - before;
+        let pages = 2 * capacity * std::mem::size_of::<Tuple>() / 4096;
+        // Transparent huge pages fault in 512 pages at once.
+        assert!(faults as usize >= pages / 512, "{} faults", faults);
+
+        let faults = std::thread::scope(|s| {
+            s.spawn(|| {
+                let before = thread_page_faults();
+                for i in 0..capacity as u64 {
+                    partition.inner_buffer.push(Tuple::new(i));
                 }
+                thread_page_faults() - before
+            })
+            .join()
+            .unwrap()
+        });
+        assert!((faults as usize) < pages / 512, "{} faults", faults);
+    }
+
+    fn numa_join<const S: SchedulingType>(
+        topology: &Topology,
+        bloom_bits_per_key: Option<usize>,
+        inner: &[DataChunk],
+        outer: &[DataChunk],
+    ) -> JoinOutput {
+        PartitionedHashJoin::<S>::new(
+            BucketSizing::default(),
+            16,
+            bloom_bits_per_key,
+            Some(topology.clone()),
+            inner.to_vec(),
+            outer.to_vec(),
+        )
+        .run(&Instrumentation::default())
+        .output
+    }
+
+    /// Joins with partitions placed on two nodes, which both have all CPUs of the machine
+    /// so that pinning works anywhere, match the reference join.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_numa_placement() {
+        let cpus =
+            (0..std::thread::available_parallelism().map_or(1, |n| n.get())).collect::<Vec<_>>();
+        let topology = Topology::new(vec![cpus.clone(), cpus]);
+        let (inner, outer) = TupleGenerator::new(2048, 4, 256).generate(Distribution::HighSkew);
+        let expected = verify::reference_join(&inner, &outer);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        for bloom_bits_per_key in [None, Some(8)] {
+            pool.install(|| {
+                let output =
+                    numa_join::<STATIC_SCHEDULING>(&topology, bloom_bits_per_key, &inner, &outer);
+                verify::verify(&expected, &output).unwrap();
+                let output =
+                    numa_join::<DYNAMIC_SCHEDULING>(&topology, bloom_bits_per_key, &inner, &outer);
+                verify::verify(&expected, &output).unwrap();
             });
         }
     }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        /// Every input tuple ends up in exactly one partition, the one given by
+        /// `partition_hash`.
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_partition_conserves_tuples(
+            partition_exp in 0..5u32,
+            inner in relation(),
+            outer in relation(),
+        ) {
+            let partition_num = 1 << partition_exp;
+            check_partitions::<STATIC_SCHEDULING>(partition_num, inner.clone(), outer.clone())?;
+            check_partitions::<DYNAMIC_SCHEDULING>(partition_num, inner, outer)?;
+        }
+    }
 }
@@ -0,0 +1,320 @@
+//! Probe loops that prefetch buckets to hide cache misses.
+//!
+//! A lookup in a table of `Vec` buckets makes two dependent memory accesses: the bucket
+//! itself, which holds the pointer to its tuples, and then the tuples. Group prefetching
+//! hashes a group of outer tuples and prefetches their buckets, then prefetches their
+//! tuples, and then does the lookups. Asynchronous memory access chaining (AMAC) keeps a
+//! ring of in-flight lookups, each a small state machine that issues one prefetch per
+//! step and moves on to the next lookup instead of waiting for it.
+//!
+//! The buckets of a batch of tuples are computed at once with `simd::bucket_indices`:
+//! a batch without prefetching, a group with group prefetching, and the next `distance`
+//! tuples whenever AMAC has started lookups for all tuples hashed so far.
+
+use std::{borrow::Borrow, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use super::{hash_bucket::HashBucket, simd};
+use crate::tuple::{Key, Tuple};
+
+/// Outer tuples probed by one task in dynamic scheduling, so that each task has batches
+/// to hash and lookups to overlap.
+pub(super) const PROBE_MORSEL_SIZE: usize = 1024;
+/// Outer tuples whose buckets are computed at once without prefetching.
+pub(super) const PROBE_BATCH_SIZE: usize = 64;
+
+/// How the probe phase prefetches buckets.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrefetchMode {
+    #[default]
+    None,
+    Group,
+    Amac,
+}
+
+/// Prefetching in the probe phase and the number of lookups it overlaps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Prefetch {
+    #[default]
+    None,
+    /// Prefetch the buckets of groups of `distance` tuples at once.
+    Group { distance: usize },
+    /// Keep `distance` lookups in flight.
+    Amac { distance: usize },
+}
+
+impl Prefetch {
+    /// The distance is ignored without prefetching, and must be positive otherwise.
+    pub fn new(mode: PrefetchMode, distance: usize) -> Self {
+        match mode {
+            PrefetchMode::None => Prefetch::None,
+            PrefetchMode::Group => Prefetch::Group { distance },
+            PrefetchMode::Amac => Prefetch::Amac { distance },
+        }
+    }
+
+    pub fn mode(self) -> PrefetchMode {
+        match self {
+            Prefetch::None => PrefetchMode::None,
+            Prefetch::Group { .. } => PrefetchMode::Group,
+            Prefetch::Amac { .. } => PrefetchMode::Amac,
+        }
+    }
+
+    pub fn distance(self) -> Option<usize> {
+        match self {
+            Prefetch::None => None,
+            Prefetch::Group { distance } | Prefetch::Amac { distance } => Some(distance),
+        }
+    }
+}
+
+impl fmt::Display for Prefetch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Prefetch::None => write!(f, "no prefetching"),
+            Prefetch::Group { distance } => write!(f, "group prefetching of {}", distance),
+            Prefetch::Amac { distance } => write!(f, "AMAC with {} lookups in flight", distance),
+        }
+    }
+}
+
+/// A hash table whose bucket for a key can be located without accessing it.
+pub(super) trait BucketTable {
+    type Bucket: HashBucket;
+
+    fn bucket_num(&self) -> usize;
+
+    /// The bucket with the given index. Only its address is computed.
+    fn bucket_at(&self, idx: usize) -> &Self::Bucket;
+}
+
+/// A hash table that can be probed with batches of outer tuples.
+pub(in crate::join_benchmark) trait ProbeTable {
+    /// Call `f` on every tuple matching each of `tuples`.
+    fn probe(&self, tuples: &[Tuple], prefetch: Prefetch, f: impl FnMut(Tuple));
+}
+
+/// Hint the CPU to load the cache line at `value` into all cache levels.
+pub(in crate::join_benchmark) fn prefetch<T>(value: &T) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        // Prefetching never faults, whatever the address.
+        #[allow(unused_unsafe)]
+        unsafe {
+            _mm_prefetch::<_MM_HINT_T0>(value as *const T as *const i8)
+        };
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = value;
+}
+
+/// Prefetch the first tuple of the bucket. Reads the bucket.
+fn prefetch_tuples<B: HashBucket>(bucket: &B) {
+    if let Some(tuple) = bucket.iter().next() {
+        prefetch(tuple);
+    }
+}
+
+fn lookup<B: HashBucket>(bucket: &B, key: Key, f: &mut impl FnMut(Tuple)) {
+    bucket.for_each_match(key, |tuple| f(tuple.clone()));
+}
+
+/// Probe `table` without prefetching with tuples taken from an iterator, e.g. of a
+/// buffer that is not contiguous. Batches hold references to the tuples, so that the
+/// tuples are not copied.
+pub(super) fn probe_iter<'a, T: BucketTable>(
+    table: &T,
+    tuples: impl IntoIterator<Item = &'a Tuple>,
+    mut f: impl FnMut(Tuple),
+) {
+    let mut batch = Vec::with_capacity(PROBE_BATCH_SIZE);
+    let mut tuples = tuples.into_iter().peekable();
+    while tuples.peek().is_some() {
+        batch.clear();
+        batch.extend(tuples.by_ref().take(PROBE_BATCH_SIZE));
+        probe_batch(table, &batch, &mut f);
+    }
+}
+
+/// Probe `table` with a batch of at most `PROBE_BATCH_SIZE` tuples, without prefetching.
+fn probe_batch<T: BucketTable>(table: &T, batch: &[impl Borrow<Tuple>], f: &mut impl FnMut(Tuple)) {
+    let mut indices = [0; PROBE_BATCH_SIZE];
+    let indices = &mut indices[..batch.len()];
+    simd::bucket_indices(batch, table.bucket_num(), indices);
+    for (tuple, &idx) in batch.iter().zip(indices.iter()) {
+        lookup(table.bucket_at(idx), tuple.borrow().key(), f);
+    }
+}
+
+/// Probe `table` with every tuple of `tuples` and call `f` on every matching tuple. The
+/// matches are produced in the order of `tuples`, except with AMAC.
+pub(super) fn probe<T: BucketTable>(
+    table: &T,
+    tuples: &[Tuple],
+    prefetch: Prefetch,
+    mut f: impl FnMut(Tuple),
+) {
+    match prefetch {
+        Prefetch::None => tuples
+            .chunks(PROBE_BATCH_SIZE)
+            .for_each(|batch| probe_batch(table, batch, &mut f)),
+        Prefetch::Group { distance } => probe_group(table, tuples, distance, f),
+        Prefetch::Amac { distance } => probe_amac(table, tuples, distance, f),
+    }
+}
+
+fn probe_group<T: BucketTable>(
+    table: &T,
+    tuples: &[Tuple],
+    distance: usize,
+    mut f: impl FnMut(Tuple),
+) {
+    let mut indices = vec![0; distance];
+    let mut buckets = Vec::with_capacity(distance);
+    for group in tuples.chunks(distance) {
+        let indices = &mut indices[..group.len()];
+        simd::bucket_indices(group, table.bucket_num(), indices);
+        buckets.clear();
+        buckets.extend(indices.iter().map(|&idx| {
+            let bucket = table.bucket_at(idx);
+            prefetch(bucket);
+            bucket
+        }));
+        buckets.iter().for_each(|bucket| prefetch_tuples(*bucket));
+        for (tuple, bucket) in group.iter().zip(&buckets) {
+            lookup(*bucket, tuple.key(), &mut f);
+        }
+    }
+}
+
+/// The tuples of AMAC lookups with their bucket indices. The buckets of the next
+/// `distance` tuples are computed at once whenever the previous ones are used up.
+pub(in crate::join_benchmark) struct HashedTuples<'a> {
+    windows: std::slice::Chunks<'a, Tuple>,
+    /// The tuples whose buckets were computed last, and the next one to start.
+    window: &'a [Tuple],
+    indices: Vec<usize>,
+    next: usize,
+    bucket_num: usize,
+}
+
+impl<'a> HashedTuples<'a> {
+    pub(in crate::join_benchmark) fn new(
+        tuples: &'a [Tuple],
+        bucket_num: usize,
+        distance: usize,
+    ) -> Self {
+        Self {
+            windows: tuples.chunks(distance),
+            window: &[],
+            indices: vec![0; distance],
+            next: 0,
+            bucket_num,
+        }
+    }
+}
+
+impl<'a> Iterator for HashedTuples<'a> {
+    type Item = (&'a Tuple, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.window.len() {
+            self.window = self.windows.next()?;
+            self.next = 0;
+            let indices = &mut self.indices[..self.window.len()];
+            simd::bucket_indices(self.window, self.bucket_num, indices);
+        }
+        self.next += 1;
+        Some((&self.window[self.next - 1], self.indices[self.next - 1]))
+    }
+}
+
+/// The state of an in-flight AMAC lookup.
+enum Lookup<'a, B> {
+    Empty,
+    /// The bucket is being prefetched.
+    Bucket(Key, &'a B),
+    /// The tuples of the bucket are being prefetched.
+    Tuples(Key, &'a B),
+}
+
+fn probe_amac<T: BucketTable>(
+    table: &T,
+    tuples: &[Tuple],
+    distance: usize,
+    mut f: impl FnMut(Tuple),
+) {
+    let mut tuples = HashedTuples::new(tuples, table.bucket_num(), distance);
+    let mut start_lookup = || match tuples.next() {
+        Some((tuple, idx)) => {
+            let bucket = table.bucket_at(idx);
+            prefetch(bucket);
+            Lookup::Bucket(tuple.key(), bucket)
+        }
+        None => Lookup::Empty,
+    };
+    let mut lookups = (0..distance).map(|_| Lookup::Empty).collect::<Vec<_>>();
+    loop {
+        let mut in_flight = false;
+        for lookup_state in lookups.iter_mut() {
+            *lookup_state = match std::mem::replace(lookup_state, Lookup::Empty) {
+                Lookup::Empty => start_lookup(),
+                Lookup::Bucket(key, bucket) => {
+                    prefetch_tuples(bucket);
+                    Lookup::Tuples(key, bucket)
+                }
+                Lookup::Tuples(key, bucket) => {
+                    lookup(bucket, key, &mut f);
+                    start_lookup()
+                }
+            };
+            in_flight |= !matches!(lookup_state, Lookup::Empty);
+        }
+        if !in_flight {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::join_benchmark::hash_table::{model, sequential::SequentialHashTable};
+
+    proptest! {
+        /// Every prefetching probe finds the same matches as the plain one.
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_prefetching_probes_find_all_matches(
+            bucket_num in model::bucket_nums(),
+            inner in prop::collection::vec(model::keys(), 0..100),
+            outer in prop::collection::vec(model::keys(), 0..100),
+            distance in 1..20usize,
+        ) {
+            let mut table = SequentialHashTable::<Vec<Tuple>>::new(bucket_num).unwrap();
+            inner.into_iter().for_each(|key| table.insert(Tuple::new(key)));
+            let outer = outer.into_iter().map(Tuple::new).collect::<Vec<_>>();
+            let matches = |prefetch| {
+                let mut keys = vec![];
+                probe(&table, &outer, prefetch, |tuple| keys.push(tuple.key()));
+                keys
+            };
+
+            let expected = matches(Prefetch::None);
+            prop_assert_eq!(matches(Prefetch::Group { distance }), expected.clone());
+            let mut amac = matches(Prefetch::Amac { distance });
+            let mut expected = expected;
+            amac.sort_unstable();
+            expected.sort_unstable();
+            prop_assert_eq!(amac, expected);
+        }
+    }
+}
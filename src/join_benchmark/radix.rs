@@ -0,0 +1,242 @@
+use parking_lot::Mutex;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    tuple::{DataChunk, Tuple},
+    work::WorkTracker,
+};
+
+use super::{
+    bloom::{self, BloomFilter, ProbeFilter},
+    hash_table::bucket_chained::BucketChainedHashTable,
+    partition_hash, thread_chunk_size, BucketSizing, HashJoinBenchmark, JoinOutput, SchedulingType,
+    STATIC_SCHEDULING,
+};
+
+/// Maximum number of partition hash bits used in one pass. Writing to more partitions at
+/// once than there are TLB entries and cache lines makes partitioning slower than doing
+/// a second pass.
+const MAX_RADIX_BITS_PER_PASS: u32 = 8;
+
+/// Split the partition hash bits into passes of at most `MAX_RADIX_BITS_PER_PASS` bits,
+/// as equal as possible.
+fn radix_passes(partition_num: usize) -> Vec<u32> {
+    let bits = partition_num.trailing_zeros();
+    let pass_num = bits.div_ceil(MAX_RADIX_BITS_PER_PASS);
+    (0..pass_num)
+        .map(|i| bits / pass_num + u32::from(i < bits % pass_num))
+        .collect()
+}
+
+/// Scatter the tuples of `pieces` into `1 << bits` partitions by the partition hash bits
+/// starting at `shift`. A histogram is computed first, so that every partition is
+/// allocated once with its exact size.
+fn scatter(pieces: &[&[Tuple]], shift: u32, bits: u32) -> Vec<DataChunk> {
+    let mask = (1 << bits) - 1;
+    let partition_idx = |tuple: &Tuple| (partition_hash(tuple.key()) >> shift) as usize & mask;
+    let mut histogram = vec![0; 1 << bits];
+    for tuple in pieces.iter().copied().flatten() {
+        histogram[partition_idx(tuple)] += 1;
+    }
+    let mut partitions = histogram
+        .into_iter()
+        .map(Vec::with_capacity)
+        .collect::<Vec<_>>();
+    for tuple in pieces.iter().copied().flatten() {
+        partitions[partition_idx(tuple)].push(tuple.clone());
+    }
+    partitions
+}
+
+/// Partition `pieces` with the remaining `passes`, starting at hash bit `shift`.
+fn radix_partition(pieces: &[&[Tuple]], shift: u32, passes: &[u32]) -> Vec<DataChunk> {
+    let Some((&bits, passes)) = passes.split_first() else {
+        return vec![pieces.concat()];
+    };
+    let partitions = scatter(pieces, shift, bits);
+    if passes.is_empty() {
+        return partitions;
+    }
+    partitions
+        .iter()
+        .flat_map(|partition| radix_partition(&[partition], shift + bits, passes))
+        .collect()
+}
+
+/// Apply `f` to every item in parallel and return the results in order. In static
+/// scheduling, each thread maps an equal share of consecutive items.
+fn map_scheduled<const S: SchedulingType, T: Send, R: Send>(
+    items: Vec<T>,
+    f: impl Fn(&mut WorkTracker, T) -> R + Send + Sync,
+) -> Vec<R> {
+    if S == STATIC_SCHEDULING {
+        let num_threads = rayon::current_num_threads();
+        let thread_chunk_size = thread_chunk_size(items.len(), num_threads);
+        let mut items = items.into_iter();
+        let thread_items = std::iter::from_fn(|| {
+            let chunk = items.by_ref().take(thread_chunk_size).collect::<Vec<_>>();
+            (!chunk.is_empty()).then_some(chunk)
+        })
+        .collect::<Vec<_>>();
+        let results = Mutex::new(Vec::with_capacity(thread_items.len()));
+        rayon::scope(|s| {
+            for (i, items) in thread_items.into_iter().enumerate() {
+                let (results, f) = (&results, &f);
+                s.spawn(move |_| {
+                    let mut work = WorkTracker::start();
+                    let thread_results = items
+                        .into_iter()
+                        .map(|item| f(&mut work, item))
+                        .collect::<Vec<_>>();
+                    results.lock().push((i, thread_results));
+                });
+            }
+        });
+        let mut results = results.into_inner();
+        results.sort_unstable_by_key(|(i, _)| *i);
+        results
+            .into_iter()
+            .flat_map(|(_, results)| results)
+            .collect()
+    } else {
+        items
+            .into_par_iter()
+            .map_init(WorkTracker::start, f)
+            .collect()
+    }
+}
+
+/// Radix hash join in the style of Kim et al. and Balkesen et al.
+///
+/// Both relations are radix-partitioned on the bits of `partition_hash` into
+/// `partition_num` partitions, which should be small enough for a partition's hash
+/// table to fit in cache. The partitioning takes multiple passes if there are more than
+/// `MAX_RADIX_BITS_PER_PASS` bits. The first pass runs in parallel over the input
+/// chunks, and the later passes over the partitions of the first pass. Each partition
+/// is joined with a `BucketChainedHashTable`.
+pub struct RadixHashJoin<const S: SchedulingType> {
+    inner: Option<Vec<DataChunk>>,
+    outer: Option<Vec<DataChunk>>,
+    buckets: BucketSizing,
+    partition_num: usize,
+    /// Build a Bloom filter with this many bits per key for each partition and probe only
+    /// the outer tuples that pass it.
+    bloom_bits_per_key: Option<usize>,
+}
+
+impl<const S: SchedulingType> RadixHashJoin<S> {
+    pub fn new(
+        buckets: BucketSizing,
+        partition_num: usize,
+        bloom_bits_per_key: Option<usize>,
+        inner: Vec<DataChunk>,
+        outer: Vec<DataChunk>,
+    ) -> Self {
+        Self {
+            inner: Some(inner),
+            outer: Some(outer),
+            buckets,
+            partition_num,
+            bloom_bits_per_key,
+        }
+    }
+
+    /// Partition a relation into `partition_num` partitions. Both relations are
+    /// partitioned in the same order, so that partitions with the same index match.
+    fn partition_relation(&self, chunks: Vec<DataChunk>) -> Vec<DataChunk> {
+        let passes = radix_passes(self.partition_num);
+        let Some((&bits, passes)) = passes.split_first() else {
+            return vec![chunks.concat()];
+        };
+        let scattered = map_scheduled::<S, _, _>(chunks, |work, chunk| {
+            work.add(chunk.len());
+            scatter(&[&chunk], 0, bits)
+        });
+        map_scheduled::<S, _, _>((0..1 << bits).collect(), |work, i| {
+            let pieces = scattered
+                .iter()
+                .map(|partitions| partitions[i].as_slice())
+                .collect::<Vec<_>>();
+            work.add(pieces.iter().map(|piece| piece.len()).sum());
+            radix_partition(&pieces, bits, passes)
+        })
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+impl<const S: SchedulingType> HashJoinBenchmark for RadixHashJoin<S> {
+    type PartitionOutput = (Vec<DataChunk>, Vec<DataChunk>);
+    type BuildOutput = Vec<(BucketChainedHashTable, Option<BloomFilter>, DataChunk)>;
+
+    fn partition(&mut self) -> Self::PartitionOutput {
+        let (inner, outer) = (self.inner.take().unwrap(), self.outer.take().unwrap());
+        let inner = self.partition_relation(inner);
+        let outer = self.partition_relation(outer);
+        (inner, outer)
+    }
+
+    fn build(&mut self, (inner, outer): Self::PartitionOutput) -> Self::BuildOutput {
+        let (buckets, partition_num) = (self.buckets, self.partition_num);
+        let bloom_bits_per_key = self.bloom_bits_per_key;
+        map_scheduled::<S, _, _>(
+            inner.into_iter().zip(outer).collect(),
+            |work, (inner, outer)| {
+                work.add(inner.len());
+                let filter = bloom::build(bloom_bits_per_key, inner.len(), &inner);
+                let bucket_num = buckets.bucket_num(inner.len(), partition_num);
+                let hash_table = BucketChainedHashTable::from_tuples(bucket_num, inner).unwrap();
+                (hash_table, filter, outer)
+            },
+        )
+    }
+
+    fn probe(&mut self, partitions: Self::BuildOutput) -> JoinOutput {
+        map_scheduled::<S, _, _>(partitions, |work, (hash_table, filter, outer)| {
+            work.add(outer.len());
+            let mut output = JoinOutput::default();
+            ProbeFilter::new(filter.as_ref()).probe(&outer, |tuples| {
+                hash_table.probe(tuples, |tuple| {
+                    Self::produce_tuple(tuple);
+                    output.push(tuple);
+                })
+            });
+            output
+        })
+        .into_iter()
+        .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_radix_passes() {
+        assert_eq!(radix_passes(1), Vec::<u32>::new());
+        assert_eq!(radix_passes(1 << 8), vec![8]);
+        assert_eq!(radix_passes(1 << 9), vec![5, 4]);
+        assert_eq!(radix_passes(1 << 14), vec![7, 7]);
+        assert_eq!(radix_passes(1 << 17), vec![6, 6, 5]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_radix_partition() {
+        let tuples = (0..4096).map(Tuple::new).collect::<Vec<_>>();
+        let partitions = radix_partition(&[&tuples[..1000], &tuples[1000..]], 0, &[3, 2]);
+        assert_eq!(partitions.len(), 32);
+        let mut keys = vec![];
+        for partition in &partitions {
+            let hash = partition_hash(partition[0].key()) & 31;
+            assert!(partition
+                .iter()
+                .all(|tuple| partition_hash(tuple.key()) & 31 == hash));
+            keys.extend(partition.iter().map(Tuple::key));
+        }
+        keys.sort_unstable();
+        assert_eq!(keys, (0..4096).collect::<Vec<_>>());
+    }
+}
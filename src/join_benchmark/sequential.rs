@@ -1,48 +1,105 @@
 use crate::tuple::{DataChunk, Tuple};
 
-use super::{hash_table::sequential::SequentialHashTable, HashJoinBenchmark, NoOutput};
+use super::{
+    bloom::{self, BloomFilter, ProbeFilter},
+    hash_table::{
+        dense::{DenseTable, JoinTable, KeyRange},
+        sequential::SequentialHashTable,
+    },
+    prefetch::Prefetch,
+    BucketSizing, HashJoinBenchmark, JoinOutput, NoOutput, TableKind,
+};
 
 /// Sequential hash join builds a single hash table and probe it sequentially.
 /// It's meant to provide a baseline and reference implementation.
 pub struct SequentialHashJoin {
     inner: Option<Vec<DataChunk>>,
     outer: Option<Vec<DataChunk>>,
-    bucket_num: usize,
+    buckets: BucketSizing,
+    prefetch: Prefetch,
+    /// Build a Bloom filter with this many bits per key and probe only the outer tuples
+    /// that pass it.
+    bloom_bits_per_key: Option<usize>,
+    /// Build a `DenseTable` instead of a hash table if the inner keys are dense.
+    dense_tables: bool,
+    table_kind: Option<TableKind>,
 }
 
 impl SequentialHashJoin {
-    pub fn new(bucket_num: usize, inner: Vec<DataChunk>, outer: Vec<DataChunk>) -> Self {
+    pub fn new(
+        buckets: BucketSizing,
+        prefetch: Prefetch,
+        bloom_bits_per_key: Option<usize>,
+        dense_tables: bool,
+        inner: Vec<DataChunk>,
+        outer: Vec<DataChunk>,
+    ) -> Self {
         Self {
             inner: Some(inner),
             outer: Some(outer),
-            bucket_num,
+            buckets,
+            prefetch,
+            bloom_bits_per_key,
+            dense_tables,
+            table_kind: None,
         }
     }
 }
 
 impl HashJoinBenchmark for SequentialHashJoin {
     type PartitionOutput = NoOutput;
-    type BuildOutput = SequentialHashTable<Vec<Tuple>>;
+    type BuildOutput = (
+        JoinTable<SequentialHashTable<Vec<Tuple>>>,
+        Option<BloomFilter>,
+    );
 
     fn partition(&mut self) -> Self::PartitionOutput {}
 
     fn build(&mut self, _: NoOutput) -> Self::BuildOutput {
-        let mut hash_table = SequentialHashTable::new(self.bucket_num).unwrap();
-        for chunk in self.inner.take().unwrap() {
-            for tuple in chunk {
-                hash_table.insert(tuple);
+        let inner = self.inner.take().unwrap();
+        let inner_tuple_num = inner.iter().map(Vec::len).sum();
+        let filter = bloom::build(
+            self.bloom_bits_per_key,
+            inner_tuple_num,
+            inner.iter().flatten(),
+        );
+        let range = if self.dense_tables {
+            KeyRange::of(&inner).filter(KeyRange::is_dense)
+        } else {
+            None
+        };
+        let table = match range {
+            Some(range) => JoinTable::Dense(DenseTable::new(range, &inner).unwrap()),
+            None => {
+                let bucket_num = self.buckets.bucket_num(inner_tuple_num, 1);
+                let mut hash_table = SequentialHashTable::new(bucket_num).unwrap();
+                for chunk in inner {
+                    for tuple in chunk {
+                        hash_table.insert(tuple);
+                    }
+                }
+                JoinTable::Hash(hash_table)
             }
-        }
-        hash_table
+        };
+        self.table_kind = Some(table.kind());
+        (table, filter)
     }
 
-    fn probe(&mut self, hash_table: Self::BuildOutput) {
+    fn probe(&mut self, (table, filter): Self::BuildOutput) -> JoinOutput {
+        let mut output = JoinOutput::default();
+        let mut filter = ProbeFilter::new(filter.as_ref());
         for chunk in self.outer.take().unwrap() {
-            for tuple in chunk.iter() {
-                hash_table
-                    .get_matching_tuples(tuple.key())
-                    .inspect(Self::produce_tuple);
-            }
+            filter.probe(&chunk, |tuples| {
+                table.probe(tuples, self.prefetch, |tuple| {
+                    Self::produce_tuple(&tuple);
+                    output.push(&tuple);
+                })
+            });
         }
+        output
+    }
+
+    fn table_kind(&self) -> Option<TableKind> {
+        self.table_kind
     }
 }
@@ -1,86 +1,226 @@
-use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use std::fmt;
+
+use parking_lot::Mutex;
+use rayon::{iter::ParallelIterator, slice::ParallelSlice};
+use serde::{Deserialize, Serialize};
 
 use super::{
-    hash_table::concurrent::ConcurrentHashTable, HashJoinBenchmark, NoOutput, SchedulingType,
-    STATIC_SCHEDULING,
+    bloom::{BloomFilter, ProbeFilter},
+    for_each_batch, for_each_tuple,
+    hash_table::{
+        concurrent::ConcurrentHashTable,
+        dense::{DenseTable, JoinTable, KeyRange},
+        growable::{GrowableHashTable, INITIAL_BUCKET_NUM},
+        lock_free::LockFreeHashTable,
+    },
+    prefetch::{Prefetch, ProbeTable, PROBE_MORSEL_SIZE},
+    thread_chunk_size, BucketSizing, HashJoinBenchmark, JoinOutput, LoadFactor, NoOutput,
+    SchedulingType, TableKind, STATIC_SCHEDULING,
+};
+use crate::{
+    tuple::{DataChunk, Tuple},
+    work::WorkTracker,
 };
-use crate::tuple::{DataChunk, Tuple};
+
+/// The concurrent hash table that the shared algorithm builds.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum SharedTable {
+    /// Sized for the inner relation before the build phase.
+    #[default]
+    Fixed,
+    /// Starts small and doubles while the inner relation is inserted, as if its size
+    /// were unknown.
+    Growable,
+    /// Links tuples into buckets with compare-and-swap instead of locking them.
+    LockFree,
+}
+
+impl fmt::Display for SharedTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SharedTable::Fixed => write!(f, "fixed"),
+            SharedTable::Growable => write!(f, "growable"),
+            SharedTable::LockFree => write!(f, "lock-free"),
+        }
+    }
+}
+
+/// The hash table built by the shared algorithm.
+pub enum SharedHashTable {
+    Locked(ConcurrentHashTable<Vec<Tuple>>),
+    LockFree(LockFreeHashTable),
+}
+
+impl ProbeTable for SharedHashTable {
+    fn probe(&self, tuples: &[Tuple], prefetch: Prefetch, f: impl FnMut(Tuple)) {
+        match self {
+            SharedHashTable::Locked(table) => table.probe(tuples, prefetch, f),
+            SharedHashTable::LockFree(table) => table.probe(tuples, prefetch, f),
+        }
+    }
+}
 
 /// All threads build a single shared hash table and probe it concurrently.
 pub struct SharedHashJoin<const S: SchedulingType> {
     inner: Option<Vec<DataChunk>>,
     outer: Option<Vec<DataChunk>>,
-    bucket_num: usize,
+    buckets: BucketSizing,
+    shared_table: SharedTable,
+    prefetch: Prefetch,
+    /// Build a Bloom filter with this many bits per key and probe only the outer tuples
+    /// that pass it.
+    bloom_bits_per_key: Option<usize>,
+    /// Build a `DenseTable` instead of a hash table if the inner keys are dense.
+    dense_tables: bool,
+    table_kind: Option<TableKind>,
 }
 
 impl<const S: SchedulingType> SharedHashJoin<S> {
-    pub fn new(bucket_num: usize, inner: Vec<DataChunk>, outer: Vec<DataChunk>) -> Self {
+    pub fn new(
+        buckets: BucketSizing,
+        shared_table: SharedTable,
+        prefetch: Prefetch,
+        bloom_bits_per_key: Option<usize>,
+        dense_tables: bool,
+        inner: Vec<DataChunk>,
+        outer: Vec<DataChunk>,
+    ) -> Self {
         Self {
             inner: Some(inner),
             outer: Some(outer),
-            bucket_num,
+            buckets,
+            shared_table,
+            prefetch,
+            bloom_bits_per_key,
+            dense_tables,
+            table_kind: None,
         }
     }
 }
 
 impl<const S: SchedulingType> HashJoinBenchmark for SharedHashJoin<S> {
     type PartitionOutput = NoOutput;
-    type BuildOutput = ConcurrentHashTable<Vec<Tuple>>;
+    type BuildOutput = (JoinTable<SharedHashTable>, Option<BloomFilter>);
 
     fn partition(&mut self) -> Self::PartitionOutput {}
 
     fn build(&mut self, _: Self::PartitionOutput) -> Self::BuildOutput {
-        let hash_table = ConcurrentHashTable::new(self.bucket_num).unwrap();
-        for chunk in self.inner.take().unwrap() {
-            if S == STATIC_SCHEDULING {
-                // Divide the chunks into equal parts for each thread.
-                let num_threads = rayon::current_num_threads();
-                let thread_chunk_size = chunk.len() / num_threads;
-                let thread_chunks = chunk.chunks(thread_chunk_size).collect::<Vec<_>>();
-                rayon::scope(|s| {
-                    for chunk in thread_chunks {
-                        s.spawn(|_| {
-                            // Cloning the tuple is the same costly as passing the reference.
-                            chunk
-                                .iter()
-                                .for_each(|tuple| hash_table.insert(tuple.clone()));
-                        });
-                    }
-                });
-            } else {
-                chunk
-                    .into_par_iter()
-                    .for_each(|tuple| hash_table.insert(tuple));
+        let inner = self.inner.take().unwrap();
+        let inner_tuple_num = inner.iter().map(Vec::len).sum();
+        let filter = self
+            .bloom_bits_per_key
+            .map(|bits| BloomFilter::new(inner_tuple_num, bits));
+        let insert_into_filter = |tuple: &Tuple| {
+            if let Some(filter) = &filter {
+                filter.insert_concurrent(tuple.key());
             }
-        }
-        hash_table
+        };
+        let range = if self.dense_tables {
+            KeyRange::par_of(&inner).filter(KeyRange::is_dense)
+        } else {
+            None
+        };
+        let table = match range {
+            Some(range) => {
+                if filter.is_some() {
+                    for_each_tuple::<S>(&inner, insert_into_filter);
+                }
+                JoinTable::Dense(DenseTable::par_new::<S>(range, &inner).unwrap())
+            }
+            None => match self.shared_table {
+                SharedTable::Fixed => {
+                    let bucket_num = self.buckets.bucket_num(inner_tuple_num, 1);
+                    let hash_table = ConcurrentHashTable::new(bucket_num).unwrap();
+                    for_each_tuple::<S>(&inner, |tuple| {
+                        insert_into_filter(tuple);
+                        // Cloning the tuple is the same costly as passing the reference.
+                        hash_table.insert(tuple.clone());
+                    });
+                    JoinTable::Hash(SharedHashTable::Locked(hash_table))
+                }
+                SharedTable::Growable => {
+                    // Start small as if the inner relation were streamed, unless the bucket
+                    // number is fixed.
+                    let (bucket_num, load_factor) = match self.buckets {
+                        BucketSizing::Fixed(_) => {
+                            (self.buckets.bucket_num(0, 1), LoadFactor::default())
+                        }
+                        BucketSizing::LoadFactor(load_factor) => (INITIAL_BUCKET_NUM, load_factor),
+                    };
+                    let hash_table = GrowableHashTable::new(bucket_num, load_factor).unwrap();
+                    for_each_tuple::<S>(&inner, |tuple| {
+                        insert_into_filter(tuple);
+                        hash_table.insert(tuple.clone());
+                    });
+                    JoinTable::Hash(SharedHashTable::Locked(hash_table.finish()))
+                }
+                SharedTable::LockFree => {
+                    let bucket_num = self.buckets.bucket_num(inner_tuple_num, 1);
+                    let hash_table = LockFreeHashTable::new(bucket_num, inner_tuple_num).unwrap();
+                    // Slots are reserved for a batch at once, so that threads do not
+                    // contend for the cursor on every tuple.
+                    for_each_batch::<S>(&inner, |tuples| {
+                        tuples.iter().for_each(insert_into_filter);
+                        hash_table.insert_batch(tuples);
+                    });
+                    JoinTable::Hash(SharedHashTable::LockFree(hash_table))
+                }
+            },
+        };
+        self.table_kind = Some(table.kind());
+        (table, filter)
     }
 
-    fn probe(&mut self, hash_table: Self::BuildOutput) {
+    fn probe(&mut self, (table, filter): Self::BuildOutput) -> JoinOutput {
+        let output = Mutex::new(JoinOutput::default());
         for chunk in self.outer.take().unwrap() {
             if S == STATIC_SCHEDULING {
                 // Divide the chunks into equal parts for each thread.
                 let num_threads = rayon::current_num_threads();
-                let thread_chunk_size = chunk.len() / num_threads;
+                let thread_chunk_size = thread_chunk_size(chunk.len(), num_threads);
                 let thread_chunks = chunk.chunks(thread_chunk_size).collect::<Vec<_>>();
                 rayon::scope(|s| {
                     for chunk in thread_chunks {
                         s.spawn(|_| {
-                            chunk.iter().for_each(|tuple| {
-                                hash_table
-                                    .get_matching_tuples(tuple.key())
-                                    .inspect(Self::produce_tuple);
+                            let mut work = WorkTracker::start();
+                            let mut thread_output = JoinOutput::default();
+                            ProbeFilter::new(filter.as_ref()).probe(chunk, |tuples| {
+                                table.probe(tuples, self.prefetch, |tuple| {
+                                    Self::produce_tuple(&tuple);
+                                    thread_output.push(&tuple);
+                                })
                             });
+                            work.add(chunk.len());
+                            *output.lock() += thread_output;
                         });
                     }
                 });
             } else {
-                chunk.par_iter().for_each(|tuple| {
-                    hash_table
-                        .get_matching_tuples(tuple.key())
-                        .inspect(Self::produce_tuple);
-                });
+                // Keys are hashed in batches and lookups can only overlap within a task, so
+                // tasks take morsels of tuples.
+                *output.lock() += chunk
+                    .par_chunks(PROBE_MORSEL_SIZE)
+                    .map_init(WorkTracker::start, |work, morsel| {
+                        let mut output = JoinOutput::default();
+                        ProbeFilter::new(filter.as_ref()).probe(morsel, |tuples| {
+                            table.probe(tuples, self.prefetch, |tuple| {
+                                Self::produce_tuple(&tuple);
+                                output.push(&tuple);
+                            })
+                        });
+                        work.add(morsel.len());
+                        output
+                    })
+                    .sum();
             }
         }
+        output.into_inner()
+    }
+
+    fn table_kind(&self) -> Option<TableKind> {
+        self.table_kind
     }
 }
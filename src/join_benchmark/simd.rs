@@ -0,0 +1,328 @@
+//! Batched bucket hashing and key comparisons with SIMD.
+//!
+//! `bucket_hash` runs the XXH3 path for inputs of 4 to 8 bytes, which for an 8-byte key
+//! is a rotation, an XOR with a constant derived from the seed, and an avalanche of
+//! rotations, shifts and two 64-bit multiplications. This module computes exactly the
+//! same hashes for several keys at once. The AVX2 and SSE4.1 paths are selected at
+//! runtime, with a scalar fallback on other CPUs and architectures. Neither has a 64-bit
+//! multiplication, so it is assembled from 32-bit ones.
+
+use std::borrow::Borrow;
+
+use crate::tuple::{Key, Tuple};
+
+use super::BUCKET_SEED;
+
+/// Bytes 8 to 16 and 16 to 24 of the default XXH3 secret.
+const SECRET_8: u64 = 0x1cad_21f7_2c81_017c;
+const SECRET_16: u64 = 0xdb97_9083_e96d_d4de;
+const PRIME_MX2: u64 = 0x9fb2_1c65_1e98_df25;
+const KEY_LEN: u64 = std::mem::size_of::<Key>() as u64;
+
+/// The constant XXH3 XORs into an input of 4 to 8 bytes.
+const fn bitflip(seed: u64) -> u64 {
+    let seed = seed ^ (((seed as u32).swap_bytes() as u64) << 32);
+    (SECRET_8 ^ SECRET_16).wrapping_sub(seed)
+}
+
+const BUCKET_BITFLIP: u64 = bitflip(BUCKET_SEED);
+
+/// `bucket_hash`, written out for 8-byte keys.
+fn hash(key: Key) -> u64 {
+    let mut h = key.rotate_left(32) ^ BUCKET_BITFLIP;
+    h ^= h.rotate_left(49) ^ h.rotate_left(24);
+    h = h.wrapping_mul(PRIME_MX2);
+    h ^= (h >> 35).wrapping_add(KEY_LEN);
+    h = h.wrapping_mul(PRIME_MX2);
+    h ^ (h >> 28)
+}
+
+/// Write the bucket index of each tuple in a table of `bucket_num` buckets to `indices`,
+/// the same as `bucket_hash(key) as usize & (bucket_num - 1)`. The tuples may also be
+/// given by reference, so that tuples that are not contiguous need not be copied.
+pub fn bucket_indices<T: Borrow<Tuple>>(tuples: &[T], bucket_num: usize, indices: &mut [usize]) {
+    assert_eq!(tuples.len(), indices.len());
+    let mask = bucket_num as u64 - 1;
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: The CPU supports AVX2.
+            return unsafe { x86::bucket_indices_avx2(tuples, mask, indices) };
+        }
+        if is_x86_feature_detected!("sse4.1") {
+            // SAFETY: The CPU supports SSE4.1.
+            return unsafe { x86::bucket_indices_sse41(tuples, mask, indices) };
+        }
+    }
+    bucket_indices_scalar(tuples, mask, indices);
+}
+
+fn bucket_indices_scalar<T: Borrow<Tuple>>(tuples: &[T], mask: u64, indices: &mut [usize]) {
+    for (tuple, index) in tuples.iter().zip(indices) {
+        *index = (hash(tuple.borrow().key()) & mask) as usize;
+    }
+}
+
+/// Call `f` on every tuple with the given key.
+pub fn for_each_match(tuples: &[Tuple], key: Key, f: impl FnMut(&Tuple)) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: The CPU supports AVX2.
+            return unsafe { x86::for_each_match_avx2(tuples, key, f) };
+        }
+        if is_x86_feature_detected!("sse4.1") {
+            // SAFETY: The CPU supports SSE4.1.
+            return unsafe { x86::for_each_match_sse41(tuples, key, f) };
+        }
+    }
+    for_each_match_scalar(tuples, key, f);
+}
+
+fn for_each_match_scalar(tuples: &[Tuple], key: Key, mut f: impl FnMut(&Tuple)) {
+    tuples.iter().filter(|t| t.key_match(key)).for_each(&mut f);
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::{arch::x86_64::*, borrow::Borrow};
+
+    use super::{bucket_indices_scalar, for_each_match_scalar, BUCKET_BITFLIP, KEY_LEN, PRIME_MX2};
+    use crate::tuple::{Key, Tuple};
+
+    /// Generates the hash of 64-bit lanes for one instruction set. The keys are loaded
+    /// one by one, because the padding of `Tuple` must not be read.
+    macro_rules! lane_hash {
+        ($name:ident, $feature:literal, $vec:ty, $set1:ident, $xor:ident, $or:ident,
+         $add:ident, $slli:ident, $srli:ident, $mul_epu32:ident) => {
+            #[target_feature(enable = $feature)]
+            unsafe fn $name(keys: $vec) -> $vec {
+                #[target_feature(enable = $feature)]
+                unsafe fn rotate_left<const L: i32, const R: i32>(x: $vec) -> $vec {
+                    $or($slli::<L>(x), $srli::<R>(x))
+                }
+                /// The low 64 bits of the lane-wise product.
+                #[target_feature(enable = $feature)]
+                unsafe fn mul64(a: $vec, b: $vec) -> $vec {
+                    let low = $mul_epu32(a, b);
+                    let cross = $add($mul_epu32($srli::<32>(a), b), $mul_epu32(a, $srli::<32>(b)));
+                    $add(low, $slli::<32>(cross))
+                }
+
+                let prime = $set1(PRIME_MX2 as i64);
+                let mut h = $xor(rotate_left::<32, 32>(keys), $set1(BUCKET_BITFLIP as i64));
+                h = $xor(h, $xor(rotate_left::<49, 15>(h), rotate_left::<24, 40>(h)));
+                h = mul64(h, prime);
+                h = $xor(h, $add($srli::<35>(h), $set1(KEY_LEN as i64)));
+                h = mul64(h, prime);
+                $xor(h, $srli::<28>(h))
+            }
+        };
+    }
+
+    lane_hash!(
+        hash_avx2,
+        "avx2",
+        __m256i,
+        _mm256_set1_epi64x,
+        _mm256_xor_si256,
+        _mm256_or_si256,
+        _mm256_add_epi64,
+        _mm256_slli_epi64,
+        _mm256_srli_epi64,
+        _mm256_mul_epu32
+    );
+
+    lane_hash!(
+        hash_sse41,
+        "sse4.1",
+        __m128i,
+        _mm_set1_epi64x,
+        _mm_xor_si128,
+        _mm_or_si128,
+        _mm_add_epi64,
+        _mm_slli_epi64,
+        _mm_srli_epi64,
+        _mm_mul_epu32
+    );
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn load_keys_avx2<T: Borrow<Tuple>>(tuples: &[T]) -> __m256i {
+        _mm256_set_epi64x(
+            tuples[3].borrow().key() as i64,
+            tuples[2].borrow().key() as i64,
+            tuples[1].borrow().key() as i64,
+            tuples[0].borrow().key() as i64,
+        )
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn load_keys_sse41<T: Borrow<Tuple>>(tuples: &[T]) -> __m128i {
+        _mm_set_epi64x(
+            tuples[1].borrow().key() as i64,
+            tuples[0].borrow().key() as i64,
+        )
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn bucket_indices_avx2<T: Borrow<Tuple>>(
+        tuples: &[T],
+        mask: u64,
+        indices: &mut [usize],
+    ) {
+        let tuple_chunks = tuples.chunks_exact(4);
+        let (tuple_rest, index_rest) = (tuple_chunks.remainder(), tuples.len() / 4 * 4);
+        let mask_vec = _mm256_set1_epi64x(mask as i64);
+        for (tuples, indices) in tuple_chunks.zip(indices.chunks_exact_mut(4)) {
+            let h = _mm256_and_si256(hash_avx2(load_keys_avx2(tuples)), mask_vec);
+            _mm256_storeu_si256(indices.as_mut_ptr() as *mut __m256i, h);
+        }
+        bucket_indices_scalar(tuple_rest, mask, &mut indices[index_rest..]);
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    pub(super) unsafe fn bucket_indices_sse41<T: Borrow<Tuple>>(
+        tuples: &[T],
+        mask: u64,
+        indices: &mut [usize],
+    ) {
+        let tuple_chunks = tuples.chunks_exact(2);
+        let (tuple_rest, index_rest) = (tuple_chunks.remainder(), tuples.len() / 2 * 2);
+        let mask_vec = _mm_set1_epi64x(mask as i64);
+        for (tuples, indices) in tuple_chunks.zip(indices.chunks_exact_mut(2)) {
+            let h = _mm_and_si128(hash_sse41(load_keys_sse41(tuples)), mask_vec);
+            _mm_storeu_si128(indices.as_mut_ptr() as *mut __m128i, h);
+        }
+        bucket_indices_scalar(tuple_rest, mask, &mut indices[index_rest..]);
+    }
+
+    /// Call `f` on the tuples whose bit is set in `mask`.
+    fn for_each_set_bit(tuples: &[Tuple], mut mask: i32, f: &mut impl FnMut(&Tuple)) {
+        while mask != 0 {
+            f(&tuples[mask.trailing_zeros() as usize]);
+            mask &= mask - 1;
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn for_each_match_avx2(
+        tuples: &[Tuple],
+        key: Key,
+        mut f: impl FnMut(&Tuple),
+    ) {
+        let key_vec = _mm256_set1_epi64x(key as i64);
+        let chunks = tuples.chunks_exact(4);
+        let rest = chunks.remainder();
+        for chunk in chunks {
+            let eq = _mm256_cmpeq_epi64(load_keys_avx2(chunk), key_vec);
+            for_each_set_bit(chunk, _mm256_movemask_pd(_mm256_castsi256_pd(eq)), &mut f);
+        }
+        for_each_match_scalar(rest, key, f);
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    pub(super) unsafe fn for_each_match_sse41(
+        tuples: &[Tuple],
+        key: Key,
+        mut f: impl FnMut(&Tuple),
+    ) {
+        let key_vec = _mm_set1_epi64x(key as i64);
+        let chunks = tuples.chunks_exact(2);
+        let rest = chunks.remainder();
+        for chunk in chunks {
+            let eq = _mm_cmpeq_epi64(load_keys_sse41(chunk), key_vec);
+            for_each_set_bit(chunk, _mm_movemask_pd(_mm_castsi128_pd(eq)), &mut f);
+        }
+        for_each_match_scalar(rest, key, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::join_benchmark::bucket_hash;
+
+    fn tuples() -> impl Strategy<Value = Vec<Tuple>> {
+        prop::collection::vec(
+            prop_oneof![4 => 0..8u64, 1 => any::<u64>()].prop_map(Tuple::new),
+            0..40,
+        )
+    }
+
+    /// Every available implementation of `bucket_indices` on the tuples.
+    fn all_bucket_indices(tuples: &[Tuple], mask: u64) -> Vec<Vec<usize>> {
+        let mut results = vec![];
+        let mut run = |f: &dyn Fn(&mut [usize])| {
+            let mut indices = vec![0; tuples.len()];
+            f(&mut indices);
+            results.push(indices);
+        };
+        run(&|indices| bucket_indices_scalar(tuples, mask, indices));
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                run(&|indices| unsafe { x86::bucket_indices_avx2(tuples, mask, indices) });
+            }
+            if is_x86_feature_detected!("sse4.1") {
+                run(&|indices| unsafe { x86::bucket_indices_sse41(tuples, mask, indices) });
+            }
+        }
+        results
+    }
+
+    /// Every available implementation of `for_each_match` on the tuples.
+    fn all_matches(tuples: &[Tuple], key: Key) -> Vec<Vec<Key>> {
+        let mut results = vec![];
+        let mut matches = vec![];
+        for_each_match_scalar(tuples, key, |t| matches.push(t.key()));
+        results.push(std::mem::take(&mut matches));
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                unsafe { x86::for_each_match_avx2(tuples, key, |t| matches.push(t.key())) };
+                results.push(std::mem::take(&mut matches));
+            }
+            if is_x86_feature_detected!("sse4.1") {
+                unsafe { x86::for_each_match_sse41(tuples, key, |t| matches.push(t.key())) };
+                results.push(std::mem::take(&mut matches));
+            }
+        }
+        results
+    }
+
+    proptest! {
+        /// All implementations compute exactly `bucket_hash`.
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_bucket_indices_match_bucket_hash(tuples in tuples(), bucket_exp in 0..64u32) {
+            let bucket_num = 1usize << bucket_exp;
+            let expected = tuples
+                .iter()
+                .map(|t| bucket_hash(t.key()) as usize & (bucket_num - 1))
+                .collect::<Vec<_>>();
+            for indices in all_bucket_indices(&tuples, bucket_num as u64 - 1) {
+                prop_assert_eq!(&indices, &expected);
+            }
+            let references = tuples.iter().collect::<Vec<_>>();
+            let mut indices = vec![0; references.len()];
+            bucket_indices(&references, bucket_num, &mut indices);
+            prop_assert_eq!(&indices, &expected);
+        }
+
+        /// All implementations find the matching tuples in order.
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prop_for_each_match_finds_matches(tuples in tuples(), key in 0..8u64) {
+            let expected = tuples
+                .iter()
+                .map(Tuple::key)
+                .filter(|&k| k == key)
+                .collect::<Vec<_>>();
+            for matches in all_matches(&tuples, key) {
+                prop_assert_eq!(&matches, &expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{binary_heap::PeekMut, BinaryHeap},
+};
+
+use parking_lot::Mutex;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    tuple::{DataChunk, Key, Tuple},
+    work::WorkTracker,
+};
+
+use super::{thread_chunk_size, HashJoinBenchmark, JoinOutput, SchedulingType, STATIC_SCHEDULING};
+
+/// Bits of the key sorted in each radix sort pass.
+const RADIX_BITS: u32 = 8;
+/// Key ranges merged and joined per thread in dynamic scheduling, so that threads that
+/// finish early can take over ranges of others.
+const RANGES_PER_THREAD: usize = 8;
+
+/// Sorted runs of both relations.
+pub struct SortedRuns {
+    inner: Vec<DataChunk>,
+    outer: Vec<DataChunk>,
+}
+
+/// A key range of both relations, each merged into a single sorted run.
+pub struct MergedRange {
+    inner: DataChunk,
+    outer: DataChunk,
+}
+
+/// Parallel sort-merge join, the baseline the hash joins are compared against.
+///
+/// The partition phase generates sorted runs with a radix sort: one per chunk in dynamic
+/// scheduling, and one per thread in static scheduling. The build phase splits the key
+/// domain into ranges at splitters sampled from the outer relation and merges the runs
+/// of each range with a multiway merge. The probe phase merge-joins the ranges. Like
+/// the hash joins, the probe phase suffers from skew, since all tuples with the same key
+/// end up in the same range.
+pub struct SortMergeJoin<const S: SchedulingType> {
+    inner: Option<Vec<DataChunk>>,
+    outer: Option<Vec<DataChunk>>,
+}
+
+impl<const S: SchedulingType> SortMergeJoin<S> {
+    pub fn new(inner: Vec<DataChunk>, outer: Vec<DataChunk>) -> Self {
+        Self {
+            inner: Some(inner),
+            outer: Some(outer),
+        }
+    }
+
+    /// Sort the chunks of a relation into runs.
+    fn generate_runs(chunks: Vec<DataChunk>) -> Vec<DataChunk> {
+        if S == STATIC_SCHEDULING {
+            // Each thread concatenates and sorts an equal share of the chunks.
+            let num_threads = rayon::current_num_threads();
+            let thread_chunk_size = thread_chunk_size(chunks.len(), num_threads);
+            let runs = Mutex::new(Vec::with_capacity(num_threads));
+            rayon::scope(|s| {
+                for chunks in chunks.chunks(thread_chunk_size) {
+                    let runs = &runs;
+                    s.spawn(move |_| {
+                        let mut work = WorkTracker::start();
+                        let mut run = chunks.concat();
+                        radix_sort(&mut run);
+                        work.add(run.len());
+                        runs.lock().push(run);
+                    });
+                }
+            });
+            runs.into_inner()
+        } else {
+            chunks
+                .into_par_iter()
+                .map_init(WorkTracker::start, |work, mut run| {
+                    radix_sort(&mut run);
+                    work.add(run.len());
+                    run
+                })
+                .collect()
+        }
+    }
+
+    /// Merge-join the sorted relations. Every outer tuple produces every inner tuple with
+    /// the same key.
+    fn merge_join(inner: &[Tuple], outer: &[Tuple]) -> JoinOutput {
+        let mut output = JoinOutput::default();
+        let (mut i, mut o) = (0, 0);
+        while i < inner.len() && o < outer.len() {
+            let key = inner[i].key();
+            match key.cmp(&outer[o].key()) {
+                Ordering::Less => i += 1,
+                Ordering::Greater => o += 1,
+                Ordering::Equal => {
+                    let matches = &inner[i..i + inner[i..].partition_point(|t| t.key() == key)];
+                    while o < outer.len() && outer[o].key() == key {
+                        for tuple in matches {
+                            Self::produce_tuple(tuple);
+                            output.push(tuple);
+                        }
+                        o += 1;
+                    }
+                    i += matches.len();
+                }
+            }
+        }
+        output
+    }
+}
+
+/// Sort tuples by key with a least significant digit radix sort. Passes in which all keys
+/// have the same digit, e.g. the high bytes of small keys, are skipped.
+fn radix_sort(tuples: &mut Vec<Tuple>) {
+    let digit =
+        |tuple: &Tuple, shift: u32| ((tuple.key() >> shift) & ((1 << RADIX_BITS) - 1)) as usize;
+    let mut scratch = tuples.clone();
+    for shift in (0..Key::BITS).step_by(RADIX_BITS as usize) {
+        let mut offsets = [0; 1 << RADIX_BITS];
+        for tuple in tuples.iter() {
+            offsets[digit(tuple, shift)] += 1;
+        }
+        if offsets.contains(&tuples.len()) {
+            continue;
+        }
+        let mut offset = 0;
+        for count in offsets.iter_mut() {
+            (*count, offset) = (offset, offset + *count);
+        }
+        for tuple in tuples.iter() {
+            let offset = &mut offsets[digit(tuple, shift)];
+            scratch[*offset] = tuple.clone();
+            *offset += 1;
+        }
+        std::mem::swap(tuples, &mut scratch);
+    }
+}
+
+/// Keys that split the runs into about `range_num` ranges of equal size, sampled evenly
+/// from each run. Duplicate splitters are removed, so a frequent key can leave fewer
+/// ranges.
+fn splitters(runs: &[DataChunk], range_num: usize) -> Vec<Key> {
+    let mut samples = runs
+        .iter()
+        .flat_map(|run| {
+            let step = (run.len() / range_num).max(1);
+            run.iter().step_by(step).map(Tuple::key)
+        })
+        .collect::<Vec<_>>();
+    samples.sort_unstable();
+    let step = (samples.len() / range_num).max(1);
+    let mut splitters = samples
+        .into_iter()
+        .step_by(step)
+        .skip(1)
+        .collect::<Vec<_>>();
+    splitters.dedup();
+    splitters
+}
+
+/// The parts of the runs with keys in `[lower, upper)`.
+fn range_of(runs: &[DataChunk], lower: Option<Key>, upper: Option<Key>) -> Vec<&[Tuple]> {
+    runs.iter()
+        .map(|run| {
+            let start = lower.map_or(0, |lower| run.partition_point(|t| t.key() < lower));
+            let end = upper.map_or(run.len(), |upper| run.partition_point(|t| t.key() < upper));
+            &run[start..end]
+        })
+        .filter(|run| !run.is_empty())
+        .collect()
+}
+
+/// Merge sorted runs into one with a heap of the next tuple of each run.
+fn merge(runs: &[&[Tuple]]) -> DataChunk {
+    let mut merged = Vec::with_capacity(runs.iter().map(|run| run.len()).sum());
+    let mut positions = vec![0; runs.len()];
+    let mut heap = runs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, run)| run.first().map(|tuple| Reverse((tuple.key(), i))))
+        .collect::<BinaryHeap<_>>();
+    while let Some(mut top) = heap.peek_mut() {
+        let Reverse((_, i)) = *top;
+        merged.push(runs[i][positions[i]].clone());
+        positions[i] += 1;
+        match runs[i].get(positions[i]) {
+            Some(tuple) => *top = Reverse((tuple.key(), i)),
+            None => {
+                PeekMut::pop(top);
+            }
+        }
+    }
+    merged
+}
+
+impl<const S: SchedulingType> HashJoinBenchmark for SortMergeJoin<S> {
+    type PartitionOutput = SortedRuns;
+    type BuildOutput = Vec<MergedRange>;
+
+    fn partition(&mut self) -> Self::PartitionOutput {
+        SortedRuns {
+            inner: Self::generate_runs(self.inner.take().unwrap()),
+            outer: Self::generate_runs(self.outer.take().unwrap()),
+        }
+    }
+
+    fn build(&mut self, runs: Self::PartitionOutput) -> Self::BuildOutput {
+        let num_threads = rayon::current_num_threads();
+        let range_num = if S == STATIC_SCHEDULING {
+            num_threads
+        } else {
+            num_threads * RANGES_PER_THREAD
+        };
+        // The outer relation is sampled because it is larger and skewed.
+        let splitters = splitters(&runs.outer, range_num);
+        let bounds = std::iter::once(None)
+            .chain(splitters.iter().copied().map(Some))
+            .zip(
+                splitters
+                    .iter()
+                    .copied()
+                    .map(Some)
+                    .chain(std::iter::once(None)),
+            )
+            .collect::<Vec<_>>();
+        let merge_range = |work: &mut WorkTracker, (lower, upper): (Option<Key>, Option<Key>)| {
+            let range = MergedRange {
+                inner: merge(&range_of(&runs.inner, lower, upper)),
+                outer: merge(&range_of(&runs.outer, lower, upper)),
+            };
+            work.add(range.inner.len() + range.outer.len());
+            range
+        };
+        if S == STATIC_SCHEDULING {
+            // One range per thread.
+            let ranges = Mutex::new(vec![]);
+            rayon::scope(|s| {
+                for (i, &bounds) in bounds.iter().enumerate() {
+                    let (ranges, merge_range) = (&ranges, &merge_range);
+                    s.spawn(move |_| {
+                        let range = merge_range(&mut WorkTracker::start(), bounds);
+                        ranges.lock().push((i, range));
+                    });
+                }
+            });
+            let mut ranges = ranges.into_inner();
+            ranges.sort_unstable_by_key(|(i, _)| *i);
+            ranges.into_iter().map(|(_, range)| range).collect()
+        } else {
+            bounds
+                .into_par_iter()
+                .map_init(WorkTracker::start, merge_range)
+                .collect()
+        }
+    }
+
+    fn probe(&mut self, ranges: Self::BuildOutput) -> JoinOutput {
+        let join_range = |work: &mut WorkTracker, range: &MergedRange| {
+            work.add(range.outer.len());
+            Self::merge_join(&range.inner, &range.outer)
+        };
+        if S == STATIC_SCHEDULING {
+            let output = Mutex::new(JoinOutput::default());
+            let num_threads = rayon::current_num_threads();
+            let thread_range_size = thread_chunk_size(ranges.len(), num_threads);
+            rayon::scope(|s| {
+                for ranges in ranges.chunks(thread_range_size) {
+                    let (output, join_range) = (&output, &join_range);
+                    s.spawn(move |_| {
+                        let mut work = WorkTracker::start();
+                        let thread_output = ranges
+                            .iter()
+                            .map(|range| join_range(&mut work, range))
+                            .sum::<JoinOutput>();
+                        *output.lock() += thread_output;
+                    });
+                }
+            });
+            output.into_inner()
+        } else {
+            ranges
+                .into_par_iter()
+                .map_init(WorkTracker::start, |work, range| join_range(work, &range))
+                .sum()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn keys(tuples: &[Tuple]) -> Vec<Key> {
+        tuples.iter().map(Tuple::key).collect()
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        /// Radix sorting runs and merging them in key ranges sorts the whole relation.
+        #[test]
+        fn prop_sort_and_merge(
+            runs in prop::collection::vec(
+                prop::collection::vec(
+                    prop_oneof![4 => 0..64u64, 1 => any::<u64>()].prop_map(Tuple::new),
+                    0..100,
+                ),
+                0..8,
+            ),
+            range_num in 1..16usize,
+        ) {
+            let mut expected = keys(&runs.concat());
+            expected.sort_unstable();
+
+            let mut runs = runs;
+            for run in &mut runs {
+                radix_sort(run);
+                prop_assert!(run.is_sorted_by_key(Tuple::key));
+            }
+            let splitters = splitters(&runs, range_num);
+            prop_assert!(splitters.is_sorted());
+            let mut merged = vec![];
+            let mut lower = None;
+            for upper in splitters.into_iter().map(Some).chain([None]) {
+                merged.extend(keys(&merge(&range_of(&runs, lower, upper))));
+                lower = upper;
+            }
+            prop_assert_eq!(merged, expected);
+        }
+    }
+}
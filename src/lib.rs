@@ -1,3 +1,15 @@
+pub mod alloc;
+pub mod analysis;
+pub mod experiment;
 pub mod join_benchmark;
+pub mod perf;
+pub mod pool;
+pub mod record;
+pub mod spill;
+pub mod stats;
+pub mod sweep;
+pub mod sync;
 pub mod timer;
 pub mod tuple;
+pub mod verify;
+pub mod work;
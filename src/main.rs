@@ -1,16 +1,74 @@
-use std::num::NonZeroUsize;
-use clap::Parser;
+use std::{num::NonZeroUsize, path::PathBuf};
+
+use anyhow::Context;
+use clap::{Args as ClapArgs, Parser, Subcommand};
 use parallel_hash_join::{
+    alloc::{self, Bytes},
+    analysis::{HashAnalysis, HashFunction},
+    experiment::{
+        print_allocation_cost, print_results, Algorithm, Experiment, JoinParams, Scheduling,
+    },
     join_benchmark::{
-        partitioned::PartitionedHashJoin, sequential::SequentialHashJoin, shared::SharedHashJoin,
-        HashJoinBenchmark, DYNAMIC_SCHEDULING, STATIC_SCHEDULING,
+        numa::Topology,
+        prefetch::{Prefetch, PrefetchMode},
+        shared::SharedTable,
+        BucketSizing, Instrumentation, LoadFactor,
     },
-    tuple::TupleGenerator,
+    perf::PerfCounters,
+    pool::{self, HugePages, PoolInfo},
+    record::{append_records, BenchmarkRecord, HostInfo, OutputFormat, RunContext, Workload},
+    sweep::{ParamList, Sweep},
+    tuple::{Distribution, Tuple, TupleGenerator},
+    verify,
 };
 
+/// Counts allocations for `--track-memory` and serves them from the memory pool. Only
+/// installed with the `track-memory` feature, so that other builds allocate straight
+/// from the system allocator.
+#[cfg(feature = "track-memory")]
+#[global_allocator]
+static GLOBAL: parallel_hash_join::alloc::CountingAllocator =
+    parallel_hash_join::alloc::CountingAllocator;
+
+/// Fail if an option needs the allocator of the `track-memory` feature.
+fn ensure_counting_allocator(needed: bool) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !needed || cfg!(feature = "track-memory"),
+        "--track-memory and the memory pool need a build with `--features track-memory`"
+    );
+    Ok(())
+}
+
+/// Fail if NUMA placement is combined with the memory pool, whose pages are faulted in
+/// by the main thread before the runs and so are not placed by the threads that use them.
+fn ensure_numa_without_pool(numa: bool, pool: bool) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !(numa && pool),
+        "--numa cannot be combined with the memory pool, which is faulted in on one node"
+    );
+    Ok(())
+}
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Build the hash table for a workload and report bucket occupancy statistics
+    Analyze(AnalyzeArgs),
+    /// Run every combination of experiments, thread numbers, partition numbers and bucket sizings
+    Sweep(SweepArgs),
+}
+
+#[derive(ClapArgs, Debug)]
+struct WorkloadArgs {
     /// Number of tuples in the inner relation
     #[arg(short, long, default_value_t = 16_000_000)]
     inner_tuple_num: usize,
@@ -23,35 +81,440 @@ struct Args {
     #[arg(long, default_value_t = 1_00_000)]
     batch_size: usize,
 
+    /// Percentage of outer tuples that match an inner tuple
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u8).range(0..=100))]
+    selectivity: u8,
+}
+
+#[derive(ClapArgs, Debug)]
+struct TableArgs {
     /// Number of partitions. Default value calculated from GHC L3 cache size of 10MB.
     /// Best partition number: 4096
     #[arg(short, long, default_value_t = 4096)]
     partition_num: usize,
 
-    /// Total number of buckets in the hash table(s)
-    #[arg(short, long, default_value_t = 1_048_576)]
-    bucket_num: usize,
+    /// Total number of buckets in the hash table(s). Overrides the load factor.
+    #[arg(short, long)]
+    bucket_num: Option<usize>,
+
+    /// Size each hash table for this many inner tuples per bucket on average, from the
+    /// actual number of inner tuples it holds
+    #[arg(long, default_value_t = LoadFactor::default())]
+    load_factor: LoadFactor,
+}
+
+impl WorkloadArgs {
+    fn tuple_generator(&self) -> TupleGenerator {
+        TupleGenerator::new(
+            self.inner_tuple_num as u64,
+            self.outer_ratio as u64,
+            self.batch_size as u64,
+        )
+        .with_selectivity(self.selectivity)
+    }
+
+    fn workload(&self) -> Workload {
+        Workload {
+            inner_tuple_num: self.inner_tuple_num,
+            outer_ratio: self.outer_ratio,
+            batch_size: self.batch_size,
+            selectivity: self.selectivity,
+        }
+    }
+}
+
+impl TableArgs {
+    fn buckets(&self) -> BucketSizing {
+        match self.bucket_num {
+            Some(bucket_num) => BucketSizing::Fixed(bucket_num),
+            None => BucketSizing::LoadFactor(self.load_factor),
+        }
+    }
+
+    fn join_params(&self) -> JoinParams {
+        JoinParams {
+            buckets: self.buckets(),
+            shared_table: SharedTable::Fixed,
+            partition_num: self.partition_num,
+            memory_limit: None,
+            prefetch: Prefetch::None,
+            bloom_bits_per_key: None,
+            dense_tables: false,
+            numa: false,
+        }
+    }
+}
+
+#[derive(ClapArgs, Debug)]
+struct ProbeArgs {
+    /// Prefetch buckets in the probe phase of the sequential and shared algorithms
+    #[arg(long, value_enum, default_value_t = PrefetchMode::None)]
+    prefetch: PrefetchMode,
+
+    /// Number of outer tuples whose lookups are overlapped by prefetching
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u16).range(1..))]
+    prefetch_distance: u16,
+
+    /// Build Bloom filters with this many bits per key alongside the hash tables, and
+    /// only probe the outer tuples that pass them
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    bloom_bits_per_key: Option<u16>,
+
+    /// The hash table of the shared algorithm. A growable table starts small and doubles
+    /// while the inner relation is inserted, as if its size were unknown
+    #[arg(long, value_enum, default_value_t = SharedTable::Fixed)]
+    shared_table: SharedTable,
+
+    /// Let the sequential and shared algorithms build a direct-addressed array instead of
+    /// a hash table if the inner keys are dense. Replaces the table kind, prefetching and
+    /// the SIMD probe of those algorithms.
+    #[arg(long)]
+    dense_tables: bool,
+
+    /// Place the partition buffers and tables of the partitioned algorithm on the NUMA
+    /// node of the threads that own them, and process partitions on their own node
+    /// first. Has no effect on a single node, and cannot be combined with the memory pool.
+    #[arg(long)]
+    numa: bool,
+}
+
+impl ProbeArgs {
+    fn prefetch(&self) -> Prefetch {
+        Prefetch::new(self.prefetch, self.prefetch_distance as usize)
+    }
+
+    fn bloom_bits_per_key(&self) -> Option<usize> {
+        self.bloom_bits_per_key.map(usize::from)
+    }
+}
+
+#[derive(ClapArgs, Debug)]
+struct PoolArgs {
+    /// Serve allocations from a pool of memory that is reserved and pre-faulted before
+    /// the runs, so that phase times do not include page faults
+    #[arg(long)]
+    memory_pool: bool,
+
+    /// Back the memory pool with huge pages. Implies --memory-pool.
+    #[arg(long, value_enum)]
+    huge_pages: Option<HugePages>,
 
-    /// Execution mode, value = [all, uq, uhd, uhs, lq, lhd, lhs, hq, hhd, hhs]
-    /// [u: uniform, l: low skew, h: high skew], [q: sequential, hd: shared dynamic, hs: shared static]
-    #[arg(short, long, default_value = "all")]
-    mode: String,
+    /// Size of the memory pool, e.g. 4G. Defaults to room for three copies of the input
+    /// and a bucket with its tuples per inner tuple. Implies --memory-pool.
+    #[arg(long)]
+    pool_size: Option<Bytes>,
+}
+
+impl PoolArgs {
+    fn enabled(&self) -> bool {
+        self.memory_pool || self.huge_pages.is_some() || self.pool_size.is_some()
+    }
+
+    /// Reserve the pool and report how much of it huge pages back.
+    fn reserve(&self, workload: &WorkloadArgs) -> anyhow::Result<PoolInfo> {
+        let size = match self.pool_size {
+            Some(size) => size.0 as usize,
+            // The input, the copy of a run, the partition buffers, and a bucket of the
+            // shared table with room for its tuples per inner tuple.
+            None => {
+                let tuple_num = workload.inner_tuple_num * (1 + workload.outer_ratio);
+                3 * tuple_num * std::mem::size_of::<Tuple>() + workload.inner_tuple_num * 128
+            }
+        };
+        let pool = pool::reserve(size, self.huge_pages)?;
+        println!(" ==> Memory pool: {}", pool);
+        Ok(pool)
+    }
+}
+
+/// Warn if the memory pool was too small for some allocations.
+fn report_pool_fallbacks() {
+    let fallbacks = pool::fallbacks();
+    if fallbacks > 0 {
+        println!(
+            " ==> Memory pool: {} allocations did not fit in the pool",
+            fallbacks
+        );
+    }
+}
+
+/// Every combination of the selected distributions, algorithms and scheduling methods is run.
+#[derive(ClapArgs, Debug)]
+struct ExperimentArgs {
+    /// Comma-separated join algorithms
+    #[arg(
+        short,
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [Algorithm::Sequential, Algorithm::Shared, Algorithm::Partitioned]
+    )]
+    algorithm: Vec<Algorithm>,
+
+    /// Comma-separated scheduling methods of the parallel algorithms
+    #[arg(
+        short,
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [Scheduling::Dynamic, Scheduling::Static]
+    )]
+    scheduling: Vec<Scheduling>,
+
+    /// Comma-separated distributions of the outer relation
+    #[arg(
+        short,
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [Distribution::Uniform, Distribution::LowSkew, Distribution::HighSkew]
+    )]
+    distribution: Vec<Distribution>,
+}
+
+impl ExperimentArgs {
+    fn experiments(&self) -> Vec<Experiment> {
+        Experiment::product(&self.distribution, &self.algorithm, &self.scheduling)
+    }
+}
+
+#[derive(ClapArgs, Debug)]
+struct Args {
+    #[command(flatten)]
+    workload: WorkloadArgs,
+
+    #[command(flatten)]
+    table: TableArgs,
+
+    #[command(flatten)]
+    experiments: ExperimentArgs,
+
+    #[command(flatten)]
+    probe: ProbeArgs,
+
+    #[command(flatten)]
+    pool: PoolArgs,
 
     /// Number of threads to use
     /// Must be a power of 2, and a multiple of batch size and partition number
     /// Default = 8, if set to 0, it will use the number of logical cores
     #[arg(short, long, default_value_t = 8)]
     threads: usize,
+
+    /// Memory limit of the grace algorithm, e.g. 512M. Partitions beyond it are spilled
+    /// to disk in the temporary directory.
+    #[arg(long)]
+    memory_limit: Option<Bytes>,
+
+    /// Number of unmeasured warm-up runs before the measured ones
+    #[arg(short, long, default_value_t = 0)]
+    warmup: usize,
+
+    /// Number of measured runs. Each run gets a fresh copy of the same input.
+    #[arg(short, long, default_value_t = 1)]
+    repetitions: usize,
+
+    /// Measure CPU performance counters in each phase. Falls back to software counters
+    /// if hardware counters are unavailable.
+    #[arg(long)]
+    perf: bool,
+
+    /// Account the tuples and busy time of each worker in each phase and report the
+    /// load imbalance
+    #[arg(long)]
+    work_stats: bool,
+
+    /// Track the peak and current allocated bytes and the allocations of each phase.
+    /// Adds contention to every allocation, so timings are slightly pessimistic.
+    #[arg(long)]
+    track_memory: bool,
+
+    /// Also run every experiment with the system allocator instead of the pre-faulted
+    /// memory pool, and report how much longer each phase takes. Implies --memory-pool.
+    #[arg(long)]
+    alloc_cost: bool,
+
+    /// Check the output of every run against a reference join
+    #[arg(long)]
+    verify: bool,
+
+    /// Append a record of each measured run to a file in this format
+    #[arg(long, value_enum)]
+    output: Option<OutputFormat>,
+
+    /// File to append records to. Defaults to results.jsonl or results.csv.
+    #[arg(long, requires = "output")]
+    output_file: Option<PathBuf>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct AnalyzeArgs {
+    #[command(flatten)]
+    workload: WorkloadArgs,
+
+    #[command(flatten)]
+    table: TableArgs,
+
+    /// Distribution of the outer relation
+    #[arg(short, long, value_enum, default_value_t = Distribution::Uniform)]
+    distribution: Distribution,
+
+    /// Hash function used to assign keys to buckets and partitions
+    #[arg(long, value_enum, default_value_t = HashFunction::Xxh3)]
+    hash: HashFunction,
+
+    /// Write the bucket size histogram to this CSV file
+    #[arg(long)]
+    histogram_csv: Option<PathBuf>,
+
+    /// Append the summary statistics as a row to this CSV file
+    #[arg(long)]
+    csv: Option<PathBuf>,
 }
 
-fn main() {
-    let args = Args::parse();
+/// Parameter lists are comma-separated numbers (8), powers of 2 (2^6), inclusive ranges
+/// (1..4) or inclusive ranges of powers of 2 (2^6..2^14).
+#[derive(ClapArgs, Debug)]
+struct SweepArgs {
+    #[command(flatten)]
+    workload: WorkloadArgs,
+
+    #[command(flatten)]
+    experiments: ExperimentArgs,
+
+    #[command(flatten)]
+    probe: ProbeArgs,
+
+    #[command(flatten)]
+    pool: PoolArgs,
+
+    /// Thread numbers
+    #[arg(short, long, default_value = "1,2,4,8")]
+    threads: ParamList,
+
+    /// Partition numbers
+    #[arg(short, long, default_value = "4096")]
+    partition_num: ParamList,
+
+    /// Total bucket numbers. Override the load factors.
+    #[arg(short, long)]
+    bucket_num: Option<ParamList>,
+
+    /// Comma-separated load factors that the hash tables are sized for
+    #[arg(long, value_delimiter = ',', default_value = "1")]
+    load_factor: Vec<LoadFactor>,
+
+    /// Memory limit of the grace algorithm, e.g. 512M
+    #[arg(long)]
+    memory_limit: Option<Bytes>,
+
+    /// Number of unmeasured warm-up runs before the measured ones
+    #[arg(short, long, default_value_t = 0)]
+    warmup: usize,
+
+    /// Number of measured runs of each point
+    #[arg(short, long, default_value_t = 1)]
+    repetitions: usize,
+
+    /// Format of the results file
+    #[arg(long, value_enum, default_value_t = OutputFormat::Json)]
+    output: OutputFormat,
+
+    /// File to append records to. Defaults to results.jsonl or results.csv.
+    #[arg(long)]
+    output_file: Option<PathBuf>,
+
+    /// Skip points whose records are already in the results file
+    #[arg(long)]
+    resume: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Analyze(args)) => analyze(args),
+        Some(Command::Sweep(args)) => sweep(args),
+        None => benchmark(cli.args),
+    }
+}
 
-    let tuple_gen = TupleGenerator::new(
-        args.inner_tuple_num as u64,
-        args.outer_ratio as u64,
-        args.batch_size as u64,
+fn analyze(args: AnalyzeArgs) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        args.table.bucket_num.is_none_or(usize::is_power_of_two),
+        "bucket number must be a power of 2"
     );
+    let (inner, outer) = args.workload.tuple_generator().generate(args.distribution);
+    println!(
+        "Analyzing {:?} hash on {:?} workload...",
+        args.hash, args.distribution
+    );
+    let analysis = HashAnalysis::new(
+        args.hash,
+        args.table
+            .buckets()
+            .bucket_num(args.workload.inner_tuple_num, 1),
+        args.table.partition_num,
+        &inner,
+        &outer,
+    )?;
+    analysis.print();
+    if let Some(path) = &args.histogram_csv {
+        analysis.write_histogram_csv(path)?;
+    }
+    if let Some(path) = &args.csv {
+        analysis.append_summary_csv(path, args.hash, args.distribution)?;
+    }
+    Ok(())
+}
+
+fn sweep(args: SweepArgs) -> anyhow::Result<()> {
+    anyhow::ensure!(args.repetitions > 0, "repetitions must be positive");
+    ensure_counting_allocator(args.pool.enabled())?;
+    ensure_numa_without_pool(args.probe.numa, args.pool.enabled())?;
+    let pool = match args.pool.enabled() {
+        true => Some(args.pool.reserve(&args.workload)?),
+        false => None,
+    };
+    let sweep = Sweep {
+        workload: args.workload.workload(),
+        experiments: args.experiments.experiments(),
+        threads: args.threads.0,
+        partition_nums: args.partition_num.0,
+        bucket_sizings: match args.bucket_num {
+            Some(bucket_nums) => bucket_nums.0.into_iter().map(BucketSizing::Fixed).collect(),
+            None => args
+                .load_factor
+                .into_iter()
+                .map(BucketSizing::LoadFactor)
+                .collect(),
+        },
+        memory_limit: args.memory_limit.map(|limit| limit.0 as usize),
+        prefetch: args.probe.prefetch(),
+        bloom_bits_per_key: args.probe.bloom_bits_per_key(),
+        dense_tables: args.probe.dense_tables,
+        numa: args.probe.numa,
+        shared_table: args.probe.shared_table,
+        pool,
+        warmup: args.warmup,
+        repetitions: args.repetitions,
+        format: args.output,
+        output_file: args
+            .output_file
+            .unwrap_or_else(|| PathBuf::from(args.output.default_path())),
+        resume: args.resume,
+    };
+    sweep.run()?;
+    report_pool_fallbacks();
+    Ok(())
+}
+
+fn benchmark(args: Args) -> anyhow::Result<()> {
+    ensure_counting_allocator(args.track_memory || args.alloc_cost || args.pool.enabled())?;
+    ensure_numa_without_pool(args.probe.numa, args.alloc_cost || args.pool.enabled())?;
+    if args.track_memory {
+        alloc::enable();
+    }
+    let tuple_gen = args.workload.tuple_generator();
 
     // let parallelism = std::thread::available_parallelism().unwrap();
     let parallelism = if args.threads == 0 {
@@ -60,248 +523,145 @@ fn main() {
         NonZeroUsize::try_from(args.threads).unwrap()
     };
     println!(" ==> Thread number: {}", parallelism.get());
+    if args.probe.prefetch != PrefetchMode::None {
+        println!(" ==> Probe: {}", args.probe.prefetch());
+    }
+    if let Some(bits) = args.probe.bloom_bits_per_key {
+        println!(" ==> Bloom filter: {} bits per key", bits);
+    }
+    if args.probe.numa {
+        match Topology::detect() {
+            Some(topology) => println!(" ==> NUMA: {} nodes", topology.node_num()),
+            None => println!(" ==> NUMA: single node, placement disabled"),
+        }
+    }
 
     // Batch size must be a multiple of the number of threads.
     assert!(parallelism.is_power_of_two());
-    assert_eq!(args.batch_size % parallelism.get(), 0);
-    assert_eq!(args.partition_num % parallelism.get(), 0);
+    assert_eq!(args.workload.batch_size % parallelism.get(), 0);
+    assert_eq!(args.table.partition_num % parallelism.get(), 0);
     // We should be able to assign buckets evenly to partitions.
-    assert_eq!(args.bucket_num % args.partition_num, 0);
+    if let Some(bucket_num) = args.table.bucket_num {
+        anyhow::ensure!(
+            bucket_num.is_power_of_two(),
+            "bucket number must be a power of 2"
+        );
+        assert_eq!(bucket_num % args.table.partition_num, 0);
+    }
+    assert!(args.repetitions > 0);
 
     rayon::ThreadPoolBuilder::new()
         .num_threads(parallelism.get())
         .build_global()
         .unwrap();
+    let pool = match args.alloc_cost || args.pool.enabled() {
+        true => Some(args.pool.reserve(&args.workload)?),
+        false => None,
+    };
 
-    macro_rules! run {
-        ($name:expr, $workload_fn:ident, $benchmark:ident $(::< $scheduling:ident >)? $(, $args:expr)*) => {
-            let (inner, outer) = tuple_gen.$workload_fn();
-            let mut benchmark = $benchmark$(::< $scheduling >)?::new($($args,)* inner, outer);
-            println!("Running {}...", $name);
-            benchmark.run();
-            println!();
-        };
+    let context = RunContext {
+        workload: args.workload.workload(),
+        threads: parallelism.get(),
+        params: JoinParams {
+            memory_limit: args.memory_limit.map(|limit| limit.0 as usize),
+            prefetch: args.probe.prefetch(),
+            bloom_bits_per_key: args.probe.bloom_bits_per_key(),
+            dense_tables: args.probe.dense_tables,
+            shared_table: args.probe.shared_table,
+            numa: args.probe.numa,
+            ..args.table.join_params()
+        },
+        pool,
+        host: HostInfo::detect(),
+    };
+    let mut instrumentation = Instrumentation {
+        work: args.work_stats,
+        memory: args.track_memory,
+        ..Default::default()
+    };
+    if args.perf {
+        instrumentation.perf = PerfCounters::open();
+        match &instrumentation.perf {
+            Some(perf) => println!(" ==> Performance counters: {:?}", perf.kind()),
+            None => println!(" ==> Performance counters: unavailable"),
+        }
     }
+    let output_path = args.output.map(|format| {
+        let path = args
+            .output_file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format.default_path()));
+        (format, path)
+    });
 
-    // Execute the selected mode
-    match args.mode.as_str() {
-        "all" => {
-            run!(
-                "Uniform + Sequential",
-                gen_uniform,
-                SequentialHashJoin,
-                args.bucket_num
-            );
-            run!(
-                "Uniform + Shared + Dynamic",
-                gen_uniform,
-                SharedHashJoin::<DYNAMIC_SCHEDULING>,
-                args.bucket_num
-            );
-            run!(
-                "Uniform + Shared + Static",
-                gen_uniform,
-                SharedHashJoin::<STATIC_SCHEDULING>,
-                args.bucket_num
-            );
-            run!(
-                "Uniform + Partitioned + Dynamic",
-                gen_low_skew,
-                PartitionedHashJoin::<DYNAMIC_SCHEDULING>,
-                args.bucket_num,
-                args.partition_num
-            );
-            run!(
-                "Uniform + Partitioned + Static",
-                gen_uniform,
-                PartitionedHashJoin::<STATIC_SCHEDULING>,
-                args.bucket_num,
-                args.partition_num
-            );
-            run!(
-                "Low Skew + Sequential",
-                gen_low_skew,
-                SequentialHashJoin,
-                args.bucket_num
-            );
-            run!(
-                "Low Skew + Shared + Dynamic",
-                gen_low_skew,
-                SharedHashJoin::<DYNAMIC_SCHEDULING>,
-                args.bucket_num
-            );
-            run!(
-                "Low Skew + Shared + Static",
-                gen_low_skew,
-                SharedHashJoin::<STATIC_SCHEDULING>,
-                args.bucket_num
-            );
-            run!(
-                "Low Skew + Partitioned + Dynamic",
-                gen_low_skew,
-                PartitionedHashJoin::<DYNAMIC_SCHEDULING>,
-                args.bucket_num,
-                args.partition_num
-            );
-            run!(
-                "Low Skew + Partitioned + Static",
-                gen_low_skew,
-                PartitionedHashJoin::<STATIC_SCHEDULING>,
-                args.bucket_num,
-                args.partition_num
-            );
-            run!(
-                "High Skew + Sequential",
-                gen_high_skew,
-                SequentialHashJoin,
-                args.bucket_num
-            );
-            run!(
-                "High Skew + Shared + Dynamic",
-                gen_high_skew,
-                SharedHashJoin::<DYNAMIC_SCHEDULING>,
-                args.bucket_num
-            );
-            run!(
-                "High Skew + Shared + Static",
-                gen_high_skew,
-                SharedHashJoin::<STATIC_SCHEDULING>,
-                args.bucket_num
-            );
-            run!(
-                "High Skew + Partitioned + Dynamic",
-                gen_low_skew,
-                PartitionedHashJoin::<DYNAMIC_SCHEDULING>,
-                args.bucket_num,
-                args.partition_num
-            );
-        }
-        "uq" => {
-            run!(
-                "Uniform + Sequential",
-                gen_uniform,
-                SequentialHashJoin,
-                args.bucket_num
-            );
+    for experiment in args.experiments.experiments() {
+        let (inner, outer) = tuple_gen.generate(experiment.distribution);
+        println!("Running {}...", experiment);
+        let results = experiment.run_repeated(
+            &context.params,
+            &instrumentation,
+            &inner,
+            &outer,
+            args.warmup,
+            args.repetitions,
+        );
+        if args.alloc_cost {
+            println!("With the memory pool:");
         }
-        "uhd" => {
-            run!(
-                "Uniform + Shared + Dynamic",
-                gen_uniform,
-                SharedHashJoin::<DYNAMIC_SCHEDULING>,
-                args.bucket_num
+        print_results(&results);
+        // The same runs with freshly allocated memory, whose page faults count against
+        // the phases that touch it first.
+        let system_results = args.alloc_cost.then(|| {
+            pool::set_bypassed(true);
+            let results = experiment.run_repeated(
+                &context.params,
+                &instrumentation,
+                &inner,
+                &outer,
+                args.warmup,
+                args.repetitions,
             );
+            pool::set_bypassed(false);
+            results
+        });
+        if let Some(system_results) = &system_results {
+            println!("With the system allocator:");
+            print_results(system_results);
+            print_allocation_cost(&results, system_results);
         }
-        "uhs" => {
-            run!(
-                "Uniform + Shared + Static",
-                gen_uniform,
-                SharedHashJoin::<STATIC_SCHEDULING>,
-                args.bucket_num
-            );
-        }
-        "upd" => {
-            run!(
-                "Uniform + Partitioned + Dynamic",
-                gen_uniform,
-                PartitionedHashJoin::<DYNAMIC_SCHEDULING>,
-                args.bucket_num,
-                args.partition_num
-            );
-        }
-        "ups" => {
-            run!(
-                "Uniform + Partitioned + Static",
-                gen_uniform,
-                PartitionedHashJoin::<STATIC_SCHEDULING>,
-                args.bucket_num,
-                args.partition_num
-            );
-        }
-        "lq" => {
-            run!(
-                "Low Skew + Sequential",
-                gen_low_skew,
-                SequentialHashJoin,
-                args.bucket_num
-            );
-        }
-        "lhd" => {
-            run!(
-                "Low Skew + Shared + Dynamic",
-                gen_low_skew,
-                SharedHashJoin::<DYNAMIC_SCHEDULING>,
-                args.bucket_num
-            );
-        }
-        "lhs" => {
-            run!(
-                "Low Skew + Shared + Static",
-                gen_low_skew,
-                SharedHashJoin::<STATIC_SCHEDULING>,
-                args.bucket_num
-            );
-        }
-        "lpd" => {
-            run!(
-                "Low Skew + Partitioned + Dynamic",
-                gen_low_skew,
-                PartitionedHashJoin::<DYNAMIC_SCHEDULING>,
-                args.bucket_num,
-                args.partition_num
-            );
-        }
-        "lps" => {
-            run!(
-                "Low Skew + Partitioned + Static",
-                gen_low_skew,
-                PartitionedHashJoin::<STATIC_SCHEDULING>,
-                args.bucket_num,
-                args.partition_num
-            );
-        }
-        "hq" => {
-            run!(
-                "High Skew + Sequential",
-                gen_high_skew,
-                SequentialHashJoin,
-                args.bucket_num
-            );
-        }
-        "hhd" => {
-            run!(
-                "High Skew + Shared + Dynamic",
-                gen_high_skew,
-                SharedHashJoin::<DYNAMIC_SCHEDULING>,
-                args.bucket_num
-            );
-        }
-        "hhs" => {
-            run!(
-                "High Skew + Shared + Static",
-                gen_high_skew,
-                SharedHashJoin::<STATIC_SCHEDULING>,
-                args.bucket_num
-            );
-        }
-        "hpd" => {
-            run!(
-                "High Skew + Partitioned + Dynamic",
-                gen_high_skew,
-                PartitionedHashJoin::<DYNAMIC_SCHEDULING>,
-                args.bucket_num,
-                args.partition_num
-            );
+        let system_context = RunContext {
+            pool: None,
+            ..context.clone()
+        };
+        let runs = [
+            (&context, Some(&results)),
+            (&system_context, system_results.as_ref()),
+        ];
+        let runs = runs
+            .into_iter()
+            .filter_map(|(context, results)| Some((context, results?)));
+        if args.verify {
+            let expected = verify::reference_join(&inner, &outer);
+            for (_, results) in runs.clone() {
+                for result in results {
+                    verify::verify(&expected, &result.output)
+                        .with_context(|| format!("{} produced a wrong result", experiment))?;
+                }
+            }
+            println!("Verified {} output tuples", expected.tuple_num);
         }
-        "hps" => {
-            run!(
-                "High Skew + Partitioned + Static",
-                gen_high_skew,
-                PartitionedHashJoin::<STATIC_SCHEDULING>,
-                args.bucket_num,
-                args.partition_num
-            );
+        if let Some((format, path)) = &output_path {
+            for (context, results) in runs {
+                let records = results
+                    .iter()
+                    .enumerate()
+                    .map(|(i, result)| BenchmarkRecord::new(&experiment, context, i, result))
+                    .collect::<Vec<_>>();
+                append_records(path, *format, &records)?;
+            }
         }
-        _ => {}
+        println!();
     }
+    report_pool_fallbacks();
+    Ok(())
 }
@@ -0,0 +1,409 @@
+//! Per-phase CPU performance counters using Linux `perf_event_open`.
+//!
+//! Counters are opened on every thread of the current rayon pool and on the calling
+//! thread, and summed. If hardware counters are unavailable (e.g. in containers or VMs),
+//! software counters are used instead.
+
+use std::fmt;
+
+/// Whether the counters are hardware (PMU) or software (kernel) events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterKind {
+    Hardware,
+    Software,
+}
+
+/// Counter values of one phase, summed over all threads.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CounterValues {
+    pub values: Vec<(&'static str, u64)>,
+}
+
+impl CounterValues {
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.values
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| *v)
+    }
+
+    /// Format the values and the values per output tuple.
+    pub fn display_per_tuple(&self, tuple_num: usize) -> impl fmt::Display + '_ {
+        DisplayPerTuple {
+            values: self,
+            tuple_num,
+        }
+    }
+}
+
+struct DisplayPerTuple<'a> {
+    values: &'a CounterValues,
+    tuple_num: usize,
+}
+
+impl fmt::Display for DisplayPerTuple<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.values.values.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {}", name, value)?;
+            if self.tuple_num > 0 {
+                write!(f, " ({:.2}/tuple)", *value as f64 / self.tuple_num as f64)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use linux::PerfCounters;
+
+#[cfg(not(target_os = "linux"))]
+pub use fallback::PerfCounters;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        io,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    };
+
+    use parking_lot::Mutex;
+
+    use super::{CounterKind, CounterValues};
+
+    const PERF_TYPE_HARDWARE: u32 = 0;
+    const PERF_TYPE_SOFTWARE: u32 = 1;
+    const PERF_TYPE_HW_CACHE: u32 = 3;
+
+    const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+    const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+    const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+    const PERF_COUNT_HW_CACHE_LL: u64 = 2;
+    const PERF_COUNT_HW_CACHE_DTLB: u64 = 3;
+    const PERF_COUNT_HW_CACHE_OP_READ: u64 = 0;
+    const PERF_COUNT_HW_CACHE_RESULT_ACCESS: u64 = 0;
+    const PERF_COUNT_HW_CACHE_RESULT_MISS: u64 = 1;
+
+    const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+    const PERF_COUNT_SW_PAGE_FAULTS: u64 = 2;
+    const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+    const PERF_COUNT_SW_CPU_MIGRATIONS: u64 = 4;
+
+    const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+    const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+
+    const FLAG_DISABLED: u64 = 1 << 0;
+    const FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+    const FLAG_EXCLUDE_HV: u64 = 1 << 6;
+
+    const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+    const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+    const PERF_EVENT_IOC_DISABLE: libc::c_ulong = 0x2401;
+    const PERF_EVENT_IOC_RESET: libc::c_ulong = 0x2403;
+
+    const fn cache_event(cache: u64, op: u64, result: u64) -> u64 {
+        cache | (op << 8) | (result << 16)
+    }
+
+    const HARDWARE_EVENTS: [(&str, u32, u64); 6] = [
+        ("cycles", PERF_TYPE_HARDWARE, PERF_COUNT_HW_CPU_CYCLES),
+        (
+            "instructions",
+            PERF_TYPE_HARDWARE,
+            PERF_COUNT_HW_INSTRUCTIONS,
+        ),
+        (
+            "llc-loads",
+            PERF_TYPE_HW_CACHE,
+            cache_event(
+                PERF_COUNT_HW_CACHE_LL,
+                PERF_COUNT_HW_CACHE_OP_READ,
+                PERF_COUNT_HW_CACHE_RESULT_ACCESS,
+            ),
+        ),
+        (
+            "llc-misses",
+            PERF_TYPE_HW_CACHE,
+            cache_event(
+                PERF_COUNT_HW_CACHE_LL,
+                PERF_COUNT_HW_CACHE_OP_READ,
+                PERF_COUNT_HW_CACHE_RESULT_MISS,
+            ),
+        ),
+        (
+            "dtlb-misses",
+            PERF_TYPE_HW_CACHE,
+            cache_event(
+                PERF_COUNT_HW_CACHE_DTLB,
+                PERF_COUNT_HW_CACHE_OP_READ,
+                PERF_COUNT_HW_CACHE_RESULT_MISS,
+            ),
+        ),
+        (
+            "branch-misses",
+            PERF_TYPE_HARDWARE,
+            PERF_COUNT_HW_BRANCH_MISSES,
+        ),
+    ];
+
+    const SOFTWARE_EVENTS: [(&str, u32, u64); 4] = [
+        (
+            "task-clock-ns",
+            PERF_TYPE_SOFTWARE,
+            PERF_COUNT_SW_TASK_CLOCK,
+        ),
+        ("page-faults", PERF_TYPE_SOFTWARE, PERF_COUNT_SW_PAGE_FAULTS),
+        (
+            "context-switches",
+            PERF_TYPE_SOFTWARE,
+            PERF_COUNT_SW_CONTEXT_SWITCHES,
+        ),
+        (
+            "cpu-migrations",
+            PERF_TYPE_SOFTWARE,
+            PERF_COUNT_SW_CPU_MIGRATIONS,
+        ),
+    ];
+
+    /// `struct perf_event_attr` up to `PERF_ATTR_SIZE_VER5`.
+    #[repr(C)]
+    #[derive(Default)]
+    struct PerfEventAttr {
+        type_: u32,
+        size: u32,
+        config: u64,
+        sample_period: u64,
+        sample_type: u64,
+        read_format: u64,
+        flags: u64,
+        wakeup_events: u32,
+        bp_type: u32,
+        config1: u64,
+        config2: u64,
+        branch_sample_type: u64,
+        sample_regs_user: u64,
+        sample_stack_user: u32,
+        clockid: i32,
+        sample_regs_intr: u64,
+        aux_watermark: u32,
+        sample_max_stack: u16,
+        reserved: u16,
+    }
+
+    /// Whether the kernel records the event in kernel context, so that excluding the
+    /// kernel would always count 0.
+    fn counted_in_kernel(type_: u32, config: u64) -> bool {
+        type_ == PERF_TYPE_SOFTWARE
+            && matches!(
+                config,
+                PERF_COUNT_SW_CONTEXT_SWITCHES | PERF_COUNT_SW_CPU_MIGRATIONS
+            )
+    }
+
+    fn open_event(type_: u32, config: u64) -> io::Result<OwnedFd> {
+        // Events counted in the kernel cannot be opened if `perf_event_paranoid` forbids
+        // unprivileged users to count the kernel, and are then left out.
+        let exclude_kernel = if counted_in_kernel(type_, config) {
+            0
+        } else {
+            FLAG_EXCLUDE_KERNEL
+        };
+        let attr = PerfEventAttr {
+            type_,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config,
+            read_format: PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING,
+            flags: FLAG_DISABLED | exclude_kernel | FLAG_EXCLUDE_HV,
+            ..Default::default()
+        };
+        // Count the calling thread on any CPU.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                0 as libc::pid_t,
+                -1 as libc::c_int,
+                -1 as libc::c_int,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+    }
+
+    /// Read a counter, scaling it up if the kernel multiplexed it with other events.
+    fn read_event(fd: &OwnedFd) -> io::Result<u64> {
+        let mut buf = [0u64; 3];
+        let len = std::mem::size_of_val(&buf);
+        let ret = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), len) };
+        if ret != len as isize {
+            return Err(io::Error::last_os_error());
+        }
+        let [value, enabled, running] = buf;
+        if running == 0 {
+            return Ok(0);
+        }
+        Ok((value as f64 * enabled as f64 / running as f64) as u64)
+    }
+
+    fn ioctl_all(fds: &[OwnedFd], request: libc::c_ulong) {
+        for fd in fds {
+            unsafe { libc::ioctl(fd.as_raw_fd(), request as _, 0) };
+        }
+    }
+
+    /// Performance counters opened on a set of threads.
+    pub struct PerfCounters {
+        kind: CounterKind,
+        names: Vec<&'static str>,
+        /// `fds[i]` are the file descriptors of event `names[i]`, one per thread.
+        fds: Vec<Vec<OwnedFd>>,
+    }
+
+    impl PerfCounters {
+        /// Open counters on the threads of the current rayon pool and the calling thread.
+        /// Returns `None` if neither hardware nor software counters can be opened.
+        pub fn open() -> Option<Self> {
+            Self::open_events(CounterKind::Hardware, &HARDWARE_EVENTS)
+                .or_else(|| Self::open_events(CounterKind::Software, &SOFTWARE_EVENTS))
+        }
+
+        fn open_events(kind: CounterKind, events: &[(&'static str, u32, u64)]) -> Option<Self> {
+            // Only keep the events that can be opened on this thread. The first event must
+            // be available, otherwise the counters are useless.
+            let events = events
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, type_, config))| open_event(*type_, *config).is_ok())
+                .map(|(i, event)| (i, *event))
+                .collect::<Vec<_>>();
+            if events.first().map(|(i, _)| *i) != Some(0) {
+                return None;
+            }
+
+            let fds = Mutex::new((0..events.len()).map(|_| Vec::new()).collect::<Vec<_>>());
+            let open_on_current_thread = || {
+                let mut opened = Vec::with_capacity(events.len());
+                for (_, (_, type_, config)) in &events {
+                    opened.push(open_event(*type_, *config).ok()?);
+                }
+                let mut fds = fds.lock();
+                for (fds, fd) in fds.iter_mut().zip(opened) {
+                    fds.push(fd);
+                }
+                Some(())
+            };
+            if rayon::current_thread_index().is_none() {
+                open_on_current_thread()?;
+            }
+            rayon::broadcast(|_| open_on_current_thread())
+                .into_iter()
+                .collect::<Option<Vec<_>>>()?;
+
+            Some(Self {
+                kind,
+                names: events.iter().map(|(_, (name, _, _))| *name).collect(),
+                fds: fds.into_inner(),
+            })
+        }
+
+        pub fn kind(&self) -> CounterKind {
+            self.kind
+        }
+
+        /// Reset and start counting.
+        pub fn start(&self) {
+            for fds in &self.fds {
+                ioctl_all(fds, PERF_EVENT_IOC_RESET);
+            }
+            for fds in &self.fds {
+                ioctl_all(fds, PERF_EVENT_IOC_ENABLE);
+            }
+        }
+
+        /// Stop counting and return the values since the last `start`.
+        pub fn stop(&self) -> CounterValues {
+            for fds in &self.fds {
+                ioctl_all(fds, PERF_EVENT_IOC_DISABLE);
+            }
+            let values = self
+                .names
+                .iter()
+                .zip(&self.fds)
+                .map(|(name, fds)| {
+                    let total = fds.iter().map(|fd| read_event(fd).unwrap_or(0)).sum();
+                    (*name, total)
+                })
+                .collect();
+            CounterValues { values }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod fallback {
+    use super::{CounterKind, CounterValues};
+
+    /// Performance counters are only supported on Linux.
+    pub struct PerfCounters;
+
+    impl PerfCounters {
+        pub fn open() -> Option<Self> {
+            None
+        }
+
+        pub fn kind(&self) -> CounterKind {
+            CounterKind::Software
+        }
+
+        pub fn start(&self) {}
+
+        pub fn stop(&self) -> CounterValues {
+            CounterValues::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_counters_count_work() {
+        // Counters may be unavailable in the test environment.
+        let Some(counters) = PerfCounters::open() else {
+            return;
+        };
+        counters.start();
+        let mut sum = 0u64;
+        for i in 0..1_000_000u64 {
+            sum = std::hint::black_box(sum + i);
+        }
+        let values = counters.stop();
+        assert_eq!(sum, 499_999_500_000);
+        assert!(!values.values.is_empty());
+        let first = values.values[0].1;
+        assert!(first > 0, "{:?} {:?}", counters.kind(), values);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_context_switches_are_counted() {
+        let Some(counters) = PerfCounters::open() else {
+            return;
+        };
+        counters.start();
+        // Sleeping switches to another task.
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let values = counters.stop();
+        if let Some(switches) = values.get("context-switches") {
+            assert!(switches > 0, "{:?}", values);
+        }
+    }
+}
@@ -0,0 +1,528 @@
+//! A pool of pre-faulted memory, optionally backed by huge pages, which serves the
+//! allocations of the joins: bucket arrays and buckets, partition buffers and the copies
+//! of the input.
+//!
+//! Freshly allocated memory pays for its page faults in whichever phase first touches
+//! it, and large arrays that are accessed at random suffer TLB misses with 4 KiB pages.
+//! `reserve` maps the pool once, before the runs, with regular pages, transparent huge
+//! pages or explicit ones from hugetlbfs, and touches every page. `CountingAllocator`
+//! then serves allocations of at least `MIN_POOL_ALLOCATION` bytes from a free list of
+//! pool pages, and smaller ones by bumping a pointer through a chunk of the pool that
+//! belongs to the allocating thread. A chunk goes back to the free list once all its
+//! allocations are freed, so freed memory goes back to the pool rather than to the
+//! kernel, and later runs reuse pages that are already faulted in. Allocations that do
+//! not fit in the pool fall back to the system allocator, and are counted so that a
+//! too small pool is noticed.
+
+use std::{
+    alloc::Layout,
+    cell::Cell,
+    fmt, ptr,
+    sync::{
+        atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::alloc::Bytes;
+
+/// Size of a huge page on x86-64, and the granularity of the pool.
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// Granularity and maximum alignment of pool allocations.
+const PAGE_SIZE: usize = 4096;
+
+/// Smaller allocations are bump-allocated from chunks.
+pub const MIN_POOL_ALLOCATION: usize = 64 << 10;
+
+/// Size of the chunks that threads bump-allocate from.
+const CHUNK_SIZE: usize = 256 << 10;
+
+/// Offset of the first allocation in a chunk, after its header.
+const CHUNK_HEADER_SIZE: usize = 64;
+
+/// Maximum alignment of bump allocations. Each is preceded by a pointer to its chunk,
+/// padded to this alignment.
+const SMALL_ALIGN: usize = 16;
+
+/// How the pool is backed by huge pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum HugePages {
+    /// Transparent huge pages, requested with `madvise(MADV_HUGEPAGE)`
+    Transparent,
+    /// Huge pages reserved in hugetlbfs, e.g. with the `vm.nr_hugepages` sysctl
+    Explicit,
+}
+
+impl fmt::Display for HugePages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HugePages::Transparent => write!(f, "transparent"),
+            HugePages::Explicit => write!(f, "explicit"),
+        }
+    }
+}
+
+/// The pool as it was reserved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolInfo {
+    pub size: usize,
+    pub requested: Option<HugePages>,
+    /// The huge pages the pool was mapped with, or `None` for regular pages. Explicit
+    /// huge pages fall back to transparent ones if too few are reserved.
+    pub backing: Option<HugePages>,
+    /// Bytes of the pool that the kernel backed with huge pages after pre-faulting, or
+    /// `None` if it does not report them.
+    pub huge_page_bytes: Option<u64>,
+}
+
+impl fmt::Display for PoolInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Bytes(self.size as i64))?;
+        match (self.backing, self.huge_page_bytes) {
+            (None, _) => write!(f, ", regular pages")?,
+            (Some(backing), Some(bytes)) => {
+                write!(f, ", {} in {} huge pages", Bytes(bytes as i64), backing)?
+            }
+            (Some(backing), None) => write!(f, ", {} huge pages of unknown size", backing)?,
+        }
+        if let (Some(requested), true) = (self.requested, self.backing != self.requested) {
+            write!(f, ", {} huge pages unavailable", requested)?;
+        }
+        Ok(())
+    }
+}
+
+/// Header of a free block, stored at its start.
+struct Block {
+    size: usize,
+    next: *mut Block,
+}
+
+/// The free blocks of the pool, in address order so that neighbours coalesce.
+struct FreeList {
+    head: *mut Block,
+}
+
+// The blocks are only accessed under the lock of the list.
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    const fn new() -> FreeList {
+        FreeList {
+            head: ptr::null_mut(),
+        }
+    }
+
+    /// Take `size` bytes from the end of the first block that is large enough, or
+    /// return null if none is. `size` must be a multiple of `PAGE_SIZE`.
+    unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+        let mut link = &mut self.head as *mut *mut Block;
+        while !(*link).is_null() {
+            let block = *link;
+            if (*block).size == size {
+                *link = (*block).next;
+                return block.cast();
+            }
+            if (*block).size > size {
+                (*block).size -= size;
+                return block.cast::<u8>().add((*block).size);
+            }
+            link = &mut (*block).next;
+        }
+        ptr::null_mut()
+    }
+
+    /// Return `size` bytes at `ptr` to the list, merging them with adjacent free blocks.
+    /// `ptr` must be aligned to `PAGE_SIZE`, and `size` a multiple of it.
+    unsafe fn free(&mut self, ptr: *mut u8, size: usize) {
+        let mut prev: *mut Block = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < ptr as usize {
+            prev = next;
+            next = (*next).next;
+        }
+        let block = ptr.cast::<Block>();
+        block.write(Block { size, next });
+        if ptr.add(size) == next.cast() {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+        if prev.is_null() {
+            self.head = block;
+        } else if prev.cast::<u8>().add((*prev).size) == ptr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+}
+
+static FREE_LIST: Mutex<FreeList> = Mutex::new(FreeList::new());
+/// Address range of the pool, or empty if none was reserved.
+static START: AtomicUsize = AtomicUsize::new(0);
+static END: AtomicUsize = AtomicUsize::new(0);
+static BYPASSED: AtomicBool = AtomicBool::new(false);
+/// Allocations that the pool would have served if it had room.
+static FALLBACKS: AtomicU64 = AtomicU64::new(0);
+
+fn free_list() -> MutexGuard<'static, FreeList> {
+    FREE_LIST.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn pool_size(size: usize) -> usize {
+    size.div_ceil(PAGE_SIZE) * PAGE_SIZE
+}
+
+/// Whether allocations of this layout are bump-allocated from chunks.
+fn is_small(layout: Layout) -> bool {
+    layout.size() < MIN_POOL_ALLOCATION && layout.align() <= SMALL_ALIGN
+}
+
+fn is_large(layout: Layout) -> bool {
+    layout.size() >= MIN_POOL_ALLOCATION && layout.align() <= PAGE_SIZE
+}
+
+/// Whether an allocation of this layout would be served by the pool.
+pub(crate) fn serves(layout: Layout) -> bool {
+    (is_small(layout) || is_large(layout))
+        && END.load(Ordering::Relaxed) != 0
+        && !BYPASSED.load(Ordering::Relaxed)
+}
+
+/// Serve new allocations from the system allocator instead of the pool, or from the
+/// pool again. Allocations of the pool are still returned to it.
+pub fn set_bypassed(bypassed: bool) {
+    BYPASSED.store(bypassed, Ordering::Relaxed);
+}
+
+pub(crate) fn contains(ptr: *const u8) -> bool {
+    (START.load(Ordering::Relaxed)..END.load(Ordering::Relaxed)).contains(&(ptr as usize))
+}
+
+/// Allocate from the pool, or return null if it does not serve the layout or has no
+/// room for it.
+pub(crate) unsafe fn alloc(layout: Layout) -> *mut u8 {
+    if !serves(layout) {
+        return ptr::null_mut();
+    }
+    let ptr = if is_small(layout) {
+        alloc_small(layout.size())
+    } else {
+        free_list().alloc(pool_size(layout.size()))
+    };
+    if ptr.is_null() {
+        FALLBACKS.fetch_add(1, Ordering::Relaxed);
+    }
+    ptr
+}
+
+/// Return an allocation of the pool, which `contains` its pointer.
+pub(crate) unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+    if is_small(layout) {
+        release_chunk(ptr.sub(SMALL_ALIGN).cast::<*mut Chunk>().read());
+    } else {
+        free_list().free(ptr, pool_size(layout.size()));
+    }
+}
+
+/// Whether a pool allocation of `layout` can be resized to `new_size` in place, which
+/// only pages of the free list can.
+pub(crate) fn fits_in_place(layout: Layout, new_size: usize) -> bool {
+    is_large(layout)
+        && new_size >= MIN_POOL_ALLOCATION
+        && pool_size(new_size) == pool_size(layout.size())
+}
+
+/// Header of a chunk.
+struct Chunk {
+    /// Allocations in the chunk that were not freed yet, plus one while a thread
+    /// allocates from it. The chunk is returned to the free list when this drops to 0.
+    live: AtomicUsize,
+}
+
+thread_local! {
+    /// The chunk that the thread allocates from, and the offset of its next allocation.
+    static CURRENT_CHUNK: Cell<(*mut Chunk, usize)> = const { Cell::new((ptr::null_mut(), 0)) };
+}
+
+/// Bump-allocate `size` bytes from the chunk of the current thread, taking a new chunk
+/// from the free list when it is full. Returns null if the pool has no room for one.
+unsafe fn alloc_small(size: usize) -> *mut u8 {
+    let size = (SMALL_ALIGN + size).next_multiple_of(SMALL_ALIGN);
+    CURRENT_CHUNK
+        .try_with(|current| {
+            let (mut chunk, mut offset) = current.get();
+            if chunk.is_null() || offset + size > CHUNK_SIZE {
+                let new_chunk = free_list().alloc(CHUNK_SIZE).cast::<Chunk>();
+                if new_chunk.is_null() {
+                    return ptr::null_mut();
+                }
+                new_chunk.write(Chunk {
+                    live: AtomicUsize::new(1),
+                });
+                if !chunk.is_null() {
+                    release_chunk(chunk);
+                }
+                (chunk, offset) = (new_chunk, CHUNK_HEADER_SIZE);
+            }
+            (*chunk).live.fetch_add(1, Ordering::Relaxed);
+            let block = chunk.cast::<u8>().add(offset);
+            block.cast::<*mut Chunk>().write(chunk);
+            current.set((chunk, offset + size));
+            block.add(SMALL_ALIGN)
+        })
+        .unwrap_or(ptr::null_mut())
+}
+
+/// Drop a reference to a chunk, returning it to the free list if it was the last.
+unsafe fn release_chunk(chunk: *mut Chunk) {
+    if (*chunk).live.fetch_sub(1, Ordering::Release) == 1 {
+        // Make the frees of other threads happen before the chunk is reused.
+        atomic::fence(Ordering::Acquire);
+        free_list().free(chunk.cast(), CHUNK_SIZE);
+    }
+}
+
+/// Number of allocations that did not fit in the pool and came from the system
+/// allocator.
+pub fn fallbacks() -> u64 {
+    FALLBACKS.load(Ordering::Relaxed)
+}
+
+unsafe fn mmap(size: usize, flags: libc::c_int) -> Result<*mut u8> {
+    let ptr = libc::mmap(
+        ptr::null_mut(),
+        size,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
+        -1,
+        0,
+    );
+    if ptr == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(ptr.cast())
+}
+
+/// Map `size` bytes aligned to a huge page, give the kernel `advice` about huge pages
+/// and touch every page.
+unsafe fn map_and_touch(size: usize, advice: libc::c_int) -> Result<*mut u8> {
+    let mapping = mmap(size + HUGE_PAGE_SIZE, 0)?;
+    let offset = mapping.align_offset(HUGE_PAGE_SIZE);
+    let start = mapping.add(offset);
+    // Unmap the slack around the aligned range.
+    if offset > 0 {
+        libc::munmap(mapping.cast(), offset);
+    }
+    libc::munmap(start.add(size).cast(), HUGE_PAGE_SIZE - offset);
+    // Kernels without transparent huge pages reject the advice, which the report shows.
+    libc::madvise(start.cast(), size, advice);
+    for offset in (0..size).step_by(PAGE_SIZE) {
+        start.add(offset).write_volatile(0);
+    }
+    Ok(start)
+}
+
+/// Reserve and pre-fault a pool of at least `size` bytes, backed by `huge_pages` or by
+/// regular pages if `None`. Can only be called once, as the pool is never unmapped.
+pub fn reserve(size: usize, huge_pages: Option<HugePages>) -> Result<PoolInfo> {
+    let size = size.div_ceil(HUGE_PAGE_SIZE).max(1) * HUGE_PAGE_SIZE;
+    if END.load(Ordering::Relaxed) != 0 {
+        bail!("the memory pool is already reserved");
+    }
+    // SAFETY: The mappings are private to the pool, which hands out disjoint ranges.
+    let (start, backing) = unsafe {
+        let transparent = || map_and_touch(size, libc::MADV_HUGEPAGE);
+        match huge_pages {
+            Some(HugePages::Explicit) => match mmap(size, libc::MAP_HUGETLB | libc::MAP_POPULATE) {
+                Ok(start) => (start, Some(HugePages::Explicit)),
+                Err(_) => (transparent()?, Some(HugePages::Transparent)),
+            },
+            Some(HugePages::Transparent) => (transparent()?, Some(HugePages::Transparent)),
+            None => (map_and_touch(size, libc::MADV_NOHUGEPAGE)?, None),
+        }
+    };
+    unsafe { free_list().free(start, size) };
+    START.store(start as usize, Ordering::Relaxed);
+    END.store(start as usize + size, Ordering::Relaxed);
+    let smaps = std::fs::read_to_string("/proc/self/smaps").ok();
+    Ok(PoolInfo {
+        size,
+        requested: huge_pages,
+        backing,
+        huge_page_bytes: smaps
+            .map(|smaps| huge_page_bytes(&smaps, start as usize, start as usize + size)),
+    })
+}
+
+/// Bytes backed by huge pages in the mappings of `smaps` that overlap `start..end`.
+fn huge_page_bytes(smaps: &str, start: usize, end: usize) -> u64 {
+    let mut in_range = false;
+    let mut kib = 0;
+    for line in smaps.lines() {
+        if let Some((mapping_start, mapping_end)) = mapping_range(line) {
+            in_range = mapping_start < end && start < mapping_end;
+        } else if let (true, Some((field, value))) = (in_range, line.split_once(':')) {
+            if matches!(
+                field,
+                "AnonHugePages" | "Private_Hugetlb" | "Shared_Hugetlb"
+            ) {
+                kib += value
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .unwrap_or(0);
+            }
+        }
+    }
+    // The pool can be merged with adjacent mappings.
+    (kib << 10).min((end - start) as u64)
+}
+
+/// The address range of a mapping header line of `smaps`.
+fn mapping_range(line: &str) -> Option<(usize, usize)> {
+    let (range, _) = line.split_once(' ')?;
+    let (start, end) = range.split_once('-')?;
+    Some((
+        usize::from_str_radix(start, 16).ok()?,
+        usize::from_str_radix(end, 16).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A free list over a heap buffer of 16 pages.
+    fn with_free_list(f: impl FnOnce(&mut FreeList, *mut u8)) {
+        let layout = Layout::from_size_align(16 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        unsafe {
+            let buffer = std::alloc::alloc(layout);
+            let mut list = FreeList::new();
+            list.free(buffer, layout.size());
+            f(&mut list, buffer);
+            std::alloc::dealloc(buffer, layout);
+        }
+    }
+
+    #[test]
+    fn test_free_list() {
+        with_free_list(|list, buffer| unsafe {
+            let a = list.alloc(4 * PAGE_SIZE);
+            let b = list.alloc(4 * PAGE_SIZE);
+            let c = list.alloc(8 * PAGE_SIZE);
+            assert_eq!(c, buffer);
+            assert_eq!(b, buffer.add(8 * PAGE_SIZE));
+            assert_eq!(a, buffer.add(12 * PAGE_SIZE));
+            assert!(list.alloc(PAGE_SIZE).is_null());
+
+            // Freeing the blocks in any order coalesces them into the whole buffer.
+            list.free(b, 4 * PAGE_SIZE);
+            list.free(c, 8 * PAGE_SIZE);
+            assert!(list.alloc(16 * PAGE_SIZE).is_null());
+            list.free(a, 4 * PAGE_SIZE);
+            assert_eq!(list.alloc(16 * PAGE_SIZE), buffer);
+        });
+    }
+
+    #[test]
+    fn test_free_list_first_fit() {
+        with_free_list(|list, buffer| unsafe {
+            let blocks = (0..4)
+                .map(|_| list.alloc(4 * PAGE_SIZE))
+                .collect::<Vec<_>>();
+            list.free(blocks[3], 4 * PAGE_SIZE);
+            list.free(blocks[1], 4 * PAGE_SIZE);
+            // Both free blocks are too small for 8 pages, and the first one fits 2.
+            assert!(list.alloc(8 * PAGE_SIZE).is_null());
+            assert_eq!(list.alloc(2 * PAGE_SIZE), buffer.add(2 * PAGE_SIZE));
+            assert_eq!(list.alloc(2 * PAGE_SIZE), buffer);
+            assert_eq!(list.alloc(4 * PAGE_SIZE), buffer.add(8 * PAGE_SIZE));
+            assert!(list.alloc(PAGE_SIZE).is_null());
+        });
+    }
+
+    /// Bytes in the free list of the pool.
+    fn free_bytes() -> usize {
+        let list = free_list();
+        let mut bytes = 0;
+        let mut block = list.head;
+        while !block.is_null() {
+            unsafe {
+                bytes += (*block).size;
+                block = (*block).next;
+            }
+        }
+        bytes
+    }
+
+    /// The only test that reserves the pool, as it can be reserved once per process.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_reserve() {
+        let pool = reserve(1, None).unwrap();
+        assert_eq!(pool.size, HUGE_PAGE_SIZE);
+        assert_eq!(pool.backing, None);
+        assert!(reserve(1, None).is_err());
+
+        let layout = Layout::from_size_align(MIN_POOL_ALLOCATION, 8).unwrap();
+        unsafe {
+            let ptr = alloc(layout);
+            assert!(contains(ptr));
+            assert!(alloc(Layout::from_size_align(HUGE_PAGE_SIZE, 8).unwrap()).is_null());
+            dealloc(ptr, layout);
+            let whole = Layout::from_size_align(HUGE_PAGE_SIZE, PAGE_SIZE).unwrap();
+            let ptr = alloc(whole);
+            assert!(contains(ptr) && contains(ptr.add(HUGE_PAGE_SIZE - 1)));
+            dealloc(ptr, whole);
+            assert!(alloc(Layout::from_size_align(64, PAGE_SIZE).unwrap()).is_null());
+
+            // Small allocations fill several chunks, which return to the free list once
+            // their allocations are freed, except for the current one.
+            let small = Layout::from_size_align(10_000, 8).unwrap();
+            let ptrs = (0..100).map(|_| alloc(small)).collect::<Vec<_>>();
+            for (i, &ptr) in ptrs.iter().enumerate() {
+                assert!(contains(ptr) && (ptr as usize).is_multiple_of(SMALL_ALIGN));
+                ptr.write_bytes(i as u8, small.size());
+            }
+            for (i, &ptr) in ptrs.iter().enumerate() {
+                assert_eq!(*ptr.add(small.size() - 1), i as u8);
+            }
+            assert!(free_bytes() <= HUGE_PAGE_SIZE - 4 * CHUNK_SIZE);
+            ptrs.into_iter().for_each(|ptr| dealloc(ptr, small));
+            assert_eq!(free_bytes(), HUGE_PAGE_SIZE - CHUNK_SIZE);
+
+            set_bypassed(true);
+            assert!(alloc(small).is_null());
+            set_bypassed(false);
+        }
+        assert_eq!(fallbacks(), 1);
+    }
+
+    #[test]
+    fn test_huge_page_bytes() {
+        let smaps = "\
+00400000-00600000 r-xp 00000000 08:01 1 /bin/join
+Size:               2048 kB
+AnonHugePages:      2048 kB
+7f0000000000-7f0000800000 rw-p 00000000 00:00 0
+Size:               8192 kB
+AnonHugePages:      6144 kB
+Private_Hugetlb:       0 kB
+VmFlags: rd wr mr mw me ac hg
+7f0000800000-7f0000a00000 rw-p 00000000 00:00 0
+AnonHugePages:      2048 kB
+";
+        let start = 0x7f00_0000_0000;
+        assert_eq!(huge_page_bytes(smaps, start, start + (8 << 20)), 6 << 20);
+        assert_eq!(huge_page_bytes(smaps, start, start + (4 << 20)), 4 << 20);
+        assert_eq!(huge_page_bytes(smaps, 0, 0x1000), 0);
+    }
+}
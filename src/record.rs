@@ -0,0 +1,357 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    experiment::{Algorithm, Experiment, JoinParams, Scheduling},
+    join_benchmark::{
+        prefetch::PrefetchMode, shared::SharedTable, BucketSizing, LoadFactor, RunResult, TableKind,
+    },
+    pool::{HugePages, PoolInfo},
+    tuple::Distribution,
+};
+
+/// File formats of benchmark records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// One JSON object per line.
+    Json,
+    /// Comma-separated values with a header line.
+    Csv,
+}
+
+impl OutputFormat {
+    pub fn default_path(self) -> &'static str {
+        match self {
+            OutputFormat::Json => "results.jsonl",
+            OutputFormat::Csv => "results.csv",
+        }
+    }
+}
+
+/// The machine a benchmark ran on.
+#[derive(Debug, Clone)]
+pub struct HostInfo {
+    pub hostname: String,
+    pub cpu_model: String,
+    pub logical_cpus: usize,
+    pub os: String,
+}
+
+impl HostInfo {
+    pub fn detect() -> Self {
+        let read = |path: &str| {
+            std::fs::read_to_string(path)
+                .map(|s| s.trim().to_string())
+                .ok()
+        };
+        let cpu_model = read("/proc/cpuinfo")
+            .and_then(|cpuinfo| {
+                cpuinfo
+                    .lines()
+                    .find(|line| line.starts_with("model name"))
+                    .and_then(|line| line.split_once(':'))
+                    .map(|(_, model)| model.trim().to_string())
+            })
+            .unwrap_or_else(|| std::env::consts::ARCH.to_string());
+        let os = match read("/proc/sys/kernel/osrelease") {
+            Some(release) => format!("{} {}", std::env::consts::OS, release),
+            None => std::env::consts::OS.to_string(),
+        };
+        Self {
+            hostname: read("/proc/sys/kernel/hostname")
+                .or_else(|| std::env::var("HOSTNAME").ok())
+                .unwrap_or_default(),
+            cpu_model,
+            logical_cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            os,
+        }
+    }
+}
+
+/// Workload parameters of a benchmark run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Workload {
+    pub inner_tuple_num: usize,
+    pub outer_ratio: usize,
+    pub batch_size: usize,
+    /// Percentage of outer tuples that match an inner tuple.
+    pub selectivity: u8,
+}
+
+/// Everything about a benchmark run except the experiment and its result.
+#[derive(Debug, Clone)]
+pub struct RunContext {
+    pub workload: Workload,
+    pub threads: usize,
+    pub params: JoinParams,
+    /// The memory pool that served the allocations, if any.
+    pub pool: Option<PoolInfo>,
+    pub host: HostInfo,
+}
+
+/// One measured run of an experiment. The fields are flat so the record maps to a CSV row.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchmarkRecord {
+    /// Seconds since the Unix epoch when the record was created.
+    pub timestamp: u64,
+    pub algorithm: Algorithm,
+    pub scheduling: Option<Scheduling>,
+    pub distribution: Distribution,
+    pub threads: usize,
+    pub inner_tuple_num: usize,
+    pub outer_ratio: usize,
+    pub batch_size: usize,
+    #[serde(default = "full_selectivity")]
+    pub selectivity: u8,
+    pub partition_num: usize,
+    /// Total number of buckets if it was fixed, or `None` if the tables were sized by
+    /// `load_factor`.
+    pub bucket_num: Option<usize>,
+    #[serde(default)]
+    pub load_factor: Option<f64>,
+    #[serde(default)]
+    pub shared_table: SharedTable,
+    #[serde(default)]
+    pub memory_limit: Option<usize>,
+    #[serde(default)]
+    pub prefetch: PrefetchMode,
+    #[serde(default)]
+    pub prefetch_distance: Option<usize>,
+    #[serde(default)]
+    pub bloom_bits_per_key: Option<usize>,
+    #[serde(default)]
+    pub dense_tables: bool,
+    #[serde(default)]
+    pub numa: bool,
+    /// Whether allocations were served by the pre-faulted memory pool.
+    #[serde(default)]
+    pub memory_pool: bool,
+    /// The huge pages that backed the memory pool, if any.
+    #[serde(default)]
+    pub huge_pages: Option<HugePages>,
+    /// Bytes of the memory pool that were backed by huge pages.
+    #[serde(default)]
+    pub huge_page_bytes: Option<u64>,
+    /// Index of the measured run, starting from 0 after the warm-up runs.
+    pub repetition: usize,
+    pub partition_ms: f64,
+    pub build_ms: f64,
+    pub probe_ms: f64,
+    pub total_ms: f64,
+    pub output_tuples: usize,
+    /// The kind of table built, for the algorithms that choose one.
+    #[serde(default)]
+    pub table: Option<TableKind>,
+    /// Bytes written to spill files in all phases.
+    #[serde(default)]
+    pub spill_bytes: Option<u64>,
+    #[serde(default)]
+    pub spill_io_ms: Option<f64>,
+    /// Outer tuples ruled out by Bloom filters, if any were used.
+    #[serde(default)]
+    pub bloom_filtered: Option<u64>,
+    pub hostname: String,
+    pub cpu_model: String,
+    pub logical_cpus: usize,
+    pub os: String,
+}
+
+impl BenchmarkRecord {
+    pub fn new(
+        experiment: &Experiment,
+        context: &RunContext,
+        repetition: usize,
+        result: &RunResult,
+    ) -> Self {
+        let RunContext {
+            workload,
+            threads,
+            params,
+            pool,
+            host,
+        } = context;
+        let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            algorithm: experiment.algorithm,
+            scheduling: experiment.scheduling,
+            distribution: experiment.distribution,
+            threads: *threads,
+            inner_tuple_num: workload.inner_tuple_num,
+            outer_ratio: workload.outer_ratio,
+            batch_size: workload.batch_size,
+            selectivity: workload.selectivity,
+            partition_num: params.partition_num,
+            bucket_num: params.buckets.fixed(),
+            load_factor: params.buckets.load_factor().map(LoadFactor::get),
+            shared_table: params.shared_table,
+            memory_limit: params.memory_limit,
+            prefetch: params.prefetch.mode(),
+            prefetch_distance: params.prefetch.distance(),
+            bloom_bits_per_key: params.bloom_bits_per_key,
+            dense_tables: params.dense_tables,
+            numa: params.numa,
+            memory_pool: pool.is_some(),
+            huge_pages: pool.and_then(|pool| pool.backing),
+            huge_page_bytes: pool.and_then(|pool| pool.huge_page_bytes),
+            repetition,
+            partition_ms: ms(result.partition.elapsed),
+            build_ms: ms(result.build.elapsed),
+            probe_ms: ms(result.probe.elapsed),
+            total_ms: ms(result.total()),
+            output_tuples: result.output.tuple_num,
+            table: result.table,
+            spill_bytes: result.spill().map(|spill| spill.bytes_written),
+            spill_io_ms: result.spill().map(|spill| ms(spill.io_time)),
+            bloom_filtered: result.filter().map(|filter| filter.filtered),
+            hostname: host.hostname.clone(),
+            cpu_model: host.cpu_model.clone(),
+            logical_cpus: host.logical_cpus,
+            os: host.os.clone(),
+        }
+    }
+
+    /// The bucket sizing of the run. Records written before tables could be sized by
+    /// their cardinality always have a fixed bucket number.
+    pub fn buckets(&self) -> BucketSizing {
+        match (self.bucket_num, self.load_factor) {
+            (Some(bucket_num), _) => BucketSizing::Fixed(bucket_num),
+            (None, load_factor) => BucketSizing::LoadFactor(
+                load_factor
+                    .and_then(|load_factor| LoadFactor::new(load_factor).ok())
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+/// Records written before the selectivity was configurable had every outer tuple match.
+fn full_selectivity() -> u8 {
+    100
+}
+
+/// Append records to `path`, creating it if it does not exist.
+pub fn append_records(
+    path: &Path,
+    format: OutputFormat,
+    records: &[BenchmarkRecord],
+) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    match format {
+        OutputFormat::Json => {
+            for record in records {
+                serde_json::to_writer(&mut file, record)?;
+                writeln!(file)?;
+            }
+        }
+        OutputFormat::Csv => {
+            let is_new = file.metadata()?.len() == 0;
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(is_new)
+                .from_writer(file);
+            for record in records {
+                writer.serialize(record)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Read all records from `path`. A missing file has no records.
+pub fn read_records(path: &Path, format: OutputFormat) -> Result<Vec<BenchmarkRecord>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    match format {
+        OutputFormat::Json => BufReader::new(file)
+            .lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect(),
+        OutputFormat::Csv => csv::Reader::from_reader(file)
+            .deserialize()
+            .map(|record| Ok(record?))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::join_benchmark::prefetch::Prefetch;
+
+    fn record(repetition: usize) -> BenchmarkRecord {
+        let experiment = Experiment::new(
+            Distribution::HighSkew,
+            Algorithm::Shared,
+            Some(Scheduling::Dynamic),
+        );
+        let context = RunContext {
+            workload: Workload {
+                inner_tuple_num: 1024,
+                outer_ratio: 4,
+                batch_size: 128,
+                selectivity: 50,
+            },
+            threads: 8,
+            params: JoinParams {
+                buckets: BucketSizing::LoadFactor(LoadFactor::new(0.5).unwrap()),
+                shared_table: SharedTable::Growable,
+                partition_num: 64,
+                memory_limit: Some(1 << 20),
+                prefetch: Prefetch::Amac { distance: 8 },
+                bloom_bits_per_key: Some(10),
+                dense_tables: true,
+                numa: true,
+            },
+            pool: Some(PoolInfo {
+                size: 8 << 20,
+                requested: Some(HugePages::Explicit),
+                backing: Some(HugePages::Transparent),
+                huge_page_bytes: Some(4 << 20),
+            }),
+            host: HostInfo {
+                hostname: "host, with comma".to_string(),
+                cpu_model: "cpu".to_string(),
+                logical_cpus: 8,
+                os: "linux".to_string(),
+            },
+        };
+        BenchmarkRecord::new(&experiment, &context, repetition, &RunResult::default())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_append_and_read_records() {
+        let records = [record(0), record(1), record(2)];
+        for format in [OutputFormat::Json, OutputFormat::Csv] {
+            let path = std::env::temp_dir().join(format!(
+                "parallel-hash-join-records-{}.{:?}",
+                std::process::id(),
+                format
+            ));
+            let _ = std::fs::remove_file(&path);
+            append_records(&path, format, &records[..1]).unwrap();
+            append_records(&path, format, &records[1..]).unwrap();
+            let parsed = read_records(&path, format).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(parsed, records);
+            assert_eq!(
+                parsed[0].buckets(),
+                BucketSizing::LoadFactor(LoadFactor::new(0.5).unwrap())
+            );
+            assert!(read_records(&path, format).unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Temporary files that join algorithms spill tuples to when they run out of memory.
+//!
+//! Spill files are created in the system temporary directory, which can be moved to
+//! another disk with `TMPDIR`, and are deleted when dropped. The bytes written and read
+//! and the time spent in I/O by all threads are accumulated per phase.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use tempfile::{NamedTempFile, TempPath};
+
+use crate::{
+    alloc::Bytes,
+    tuple::{Key, Tuple},
+};
+
+/// Bytes buffered in memory before they are written, and read at once.
+const BLOCK_SIZE: usize = 1 << 15;
+const TUPLE_SIZE: usize = std::mem::size_of::<Key>();
+
+static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+static BYTES_READ: AtomicU64 = AtomicU64::new(0);
+static IO_NANOS: AtomicU64 = AtomicU64::new(0);
+
+fn timed_io<T>(bytes: &AtomicU64, len: usize, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    let start = Instant::now();
+    let ret = f()?;
+    IO_NANOS.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    bytes.fetch_add(len as u64, Ordering::Relaxed);
+    Ok(ret)
+}
+
+/// An append-only temporary file of tuples.
+///
+/// The file is only open while a block is written or read, so that a join can spill
+/// more partitions than it may have open files. Each file buffers up to `BLOCK_SIZE`
+/// bytes in memory.
+pub struct SpillFile {
+    /// Created on the first write.
+    path: Option<TempPath>,
+    buffer: Vec<u8>,
+    tuple_num: usize,
+}
+
+impl SpillFile {
+    pub fn new() -> Self {
+        Self {
+            path: None,
+            buffer: Vec::new(),
+            tuple_num: 0,
+        }
+    }
+
+    pub fn push(&mut self, tuple: &Tuple) -> io::Result<()> {
+        self.buffer.extend_from_slice(&tuple.key().to_le_bytes());
+        self.tuple_num += 1;
+        if self.buffer.len() >= BLOCK_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn tuple_num(&self) -> usize {
+        self.tuple_num
+    }
+
+    /// Write the buffered tuples to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let path = match &mut self.path {
+            Some(path) => path,
+            None => self.path.insert(NamedTempFile::new()?.into_temp_path()),
+        };
+        let buffer = &self.buffer;
+        timed_io(&BYTES_WRITTEN, buffer.len(), || {
+            OpenOptions::new()
+                .append(true)
+                .open(&*path)?
+                .write_all(buffer)
+        })?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Finish writing and read the tuples back from the start of the file.
+    pub fn into_reader(mut self) -> io::Result<SpillReader> {
+        self.flush()?;
+        let file = match &self.path {
+            Some(path) => Some(File::open(path)?),
+            None => None,
+        };
+        Ok(SpillReader {
+            file,
+            _path: self.path,
+            remaining: self.tuple_num,
+        })
+    }
+}
+
+impl Default for SpillFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads the tuples of a `SpillFile` in blocks. The file is deleted when the reader is
+/// dropped.
+pub struct SpillReader {
+    file: Option<File>,
+    _path: Option<TempPath>,
+    remaining: usize,
+}
+
+impl SpillReader {
+    /// The next block of tuples, or `None` at the end of the file.
+    pub fn next_block(&mut self) -> io::Result<Option<Vec<Tuple>>> {
+        let Some(file) = &mut self.file else {
+            return Ok(None);
+        };
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let tuple_num = self.remaining.min(BLOCK_SIZE / TUPLE_SIZE);
+        let mut bytes = vec![0; tuple_num * TUPLE_SIZE];
+        timed_io(&BYTES_READ, bytes.len(), || file.read_exact(&mut bytes))?;
+        self.remaining -= tuple_num;
+        let tuples = bytes
+            .chunks_exact(TUPLE_SIZE)
+            .map(|key| Tuple::new(Key::from_le_bytes(key.try_into().unwrap())))
+            .collect();
+        Ok(Some(tuples))
+    }
+}
+
+/// Spilling done in a phase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpillStats {
+    pub bytes_written: u64,
+    pub bytes_read: u64,
+    /// Time spent in I/O summed over all threads, so it can exceed the phase time.
+    pub io_time: Duration,
+}
+
+/// Reset the counters at the start of a phase.
+pub fn start_phase() {
+    BYTES_WRITTEN.store(0, Ordering::Relaxed);
+    BYTES_READ.store(0, Ordering::Relaxed);
+    IO_NANOS.store(0, Ordering::Relaxed);
+}
+
+/// The spilling done since `start_phase`, or `None` if nothing was spilled.
+pub fn finish_phase() -> Option<SpillStats> {
+    let stats = SpillStats {
+        bytes_written: BYTES_WRITTEN.load(Ordering::Relaxed),
+        bytes_read: BYTES_READ.load(Ordering::Relaxed),
+        io_time: Duration::from_nanos(IO_NANOS.load(Ordering::Relaxed)),
+    };
+    (stats != SpillStats::default()).then_some(stats)
+}
+
+impl fmt::Display for SpillStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "spill: wrote {}, read {}, I/O time {:?}",
+            Bytes(self.bytes_written as i64),
+            Bytes(self.bytes_read as i64),
+            self.io_time
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_spill_file_round_trip() {
+        let mut file = SpillFile::new();
+        // More than one block.
+        let keys = (0..20_000).map(|i| i * 7).collect::<Vec<Key>>();
+        for &key in &keys {
+            file.push(&Tuple::new(key)).unwrap();
+        }
+        assert_eq!(file.tuple_num(), keys.len());
+
+        let mut reader = file.into_reader().unwrap();
+        let mut read = Vec::new();
+        while let Some(block) = reader.next_block().unwrap() {
+            assert!(!block.is_empty());
+            read.extend(block.iter().map(Tuple::key));
+        }
+        assert_eq!(read, keys);
+
+        let mut empty = SpillFile::new().into_reader().unwrap();
+        assert!(empty.next_block().unwrap().is_none());
+    }
+}
@@ -0,0 +1,119 @@
+use std::{fmt, time::Duration};
+
+/// Two-sided 95% critical values of Student's t-distribution for 1 to 30 degrees of freedom.
+const T_CRITICAL_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+/// Critical value of the standard normal distribution, used beyond 30 degrees of freedom.
+const Z_CRITICAL_95: f64 = 1.960;
+
+/// Summary statistics of repeated measurements.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub mean: f64,
+    pub median: f64,
+    /// Sample standard deviation. Zero if there is only one sample.
+    pub std_dev: f64,
+    pub min: f64,
+    pub max: f64,
+    /// 95% confidence interval of the mean.
+    pub ci95: (f64, f64),
+}
+
+impl Summary {
+    /// Summarize durations in milliseconds.
+    pub fn from_durations(durations: impl IntoIterator<Item = Duration>) -> Option<Self> {
+        let samples = durations
+            .into_iter()
+            .map(|d| d.as_secs_f64() * 1000.0)
+            .collect::<Vec<_>>();
+        Self::new(&samples)
+    }
+
+    /// Summarize the samples. Returns `None` if there are no samples.
+    pub fn new(samples: &[f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let count = samples.len();
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+
+        let mean = sorted.iter().sum::<f64>() / count as f64;
+        let median = if count % 2 == 1 {
+            sorted[count / 2]
+        } else {
+            (sorted[count / 2 - 1] + sorted[count / 2]) / 2.0
+        };
+        let std_dev = if count == 1 {
+            0.0
+        } else {
+            let variance =
+                sorted.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (count - 1) as f64;
+            variance.sqrt()
+        };
+        let half_width = if count == 1 {
+            0.0
+        } else {
+            let t = T_CRITICAL_95
+                .get(count - 2)
+                .copied()
+                .unwrap_or(Z_CRITICAL_95);
+            t * std_dev / (count as f64).sqrt()
+        };
+
+        Some(Self {
+            count,
+            mean,
+            median,
+            std_dev,
+            min: sorted[0],
+            max: sorted[count - 1],
+            ci95: (mean - half_width, mean + half_width),
+        })
+    }
+}
+
+/// Formats the statistics as milliseconds.
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "mean {:.3}ms, median {:.3}ms, std dev {:.3}ms, min {:.3}ms, max {:.3}ms, \
+             95% CI [{:.3}ms, {:.3}ms]",
+            self.mean, self.median, self.std_dev, self.min, self.max, self.ci95.0, self.ci95.1
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let summary = Summary::new(&[4.0, 1.0, 3.0, 2.0]).unwrap();
+        assert_eq!(summary.count, 4);
+        assert_eq!(summary.mean, 2.5);
+        assert_eq!(summary.median, 2.5);
+        assert_eq!(summary.min, 1.0);
+        assert_eq!(summary.max, 4.0);
+        assert!((summary.std_dev - 1.290_994).abs() < 1e-6);
+        // t(3) = 3.182
+        let half_width = 3.182 * summary.std_dev / 2.0;
+        assert!((summary.ci95.0 - (2.5 - half_width)).abs() < 1e-9);
+        assert!((summary.ci95.1 - (2.5 + half_width)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_summary_single_sample() {
+        let summary = Summary::new(&[7.0]).unwrap();
+        assert_eq!(summary.median, 7.0);
+        assert_eq!(summary.std_dev, 0.0);
+        assert_eq!(summary.ci95, (7.0, 7.0));
+        assert!(Summary::new(&[]).is_none());
+    }
+}
@@ -0,0 +1,367 @@
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    experiment::{print_results, Experiment, JoinParams},
+    join_benchmark::{prefetch::Prefetch, shared::SharedTable, BucketSizing, Instrumentation},
+    pool::PoolInfo,
+    record::{
+        append_records, read_records, BenchmarkRecord, HostInfo, OutputFormat, RunContext, Workload,
+    },
+    tuple::TupleGenerator,
+};
+
+/// A list of parameter values parsed from a comma-separated list of items. Each item is
+/// a number (`64`), a power of 2 (`2^6`), an inclusive range (`1..4`), or an inclusive
+/// range of powers of 2 (`2^6..2^14`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParamList(pub Vec<usize>);
+
+impl FromStr for ParamList {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse_value = |item: &str| -> Result<usize> {
+            let item = item.trim();
+            match item.split_once('^') {
+                Some((base, exp)) if base.trim() == "2" => {
+                    let exp = exp.trim().parse::<u32>()?;
+                    1usize
+                        .checked_shl(exp)
+                        .ok_or_else(|| anyhow!("2^{} is too large", exp))
+                }
+                Some(_) => Err(anyhow!("only powers of 2 are supported: {}", item)),
+                None => Ok(item.parse()?),
+            }
+        };
+
+        let mut values = Vec::new();
+        for item in s.split(',') {
+            match item.split_once("..") {
+                Some((start, end)) => {
+                    let powers = start.contains('^') && end.contains('^');
+                    let (start, end) = (parse_value(start)?, parse_value(end)?);
+                    if start > end {
+                        return Err(anyhow!("empty range: {}", item));
+                    }
+                    let mut value = Some(start);
+                    while let Some(v) = value.filter(|&v| v <= end) {
+                        values.push(v);
+                        value = if powers {
+                            v.checked_mul(2)
+                        } else {
+                            v.checked_add(1)
+                        };
+                    }
+                }
+                None => values.push(parse_value(item)?),
+            }
+        }
+        Ok(Self(values))
+    }
+}
+
+/// A cartesian product of experiments and parameters to run.
+pub struct Sweep {
+    pub workload: Workload,
+    pub experiments: Vec<Experiment>,
+    pub threads: Vec<usize>,
+    pub partition_nums: Vec<usize>,
+    pub bucket_sizings: Vec<BucketSizing>,
+    /// The hash table of the shared algorithm.
+    pub shared_table: SharedTable,
+    /// Memory limit of the algorithms that spill to disk.
+    pub memory_limit: Option<usize>,
+    /// Prefetching in the probe phase.
+    pub prefetch: Prefetch,
+    /// Bits per key of the Bloom filters, if any.
+    pub bloom_bits_per_key: Option<usize>,
+    /// Whether dense inner keys may be joined with a direct-addressed array.
+    pub dense_tables: bool,
+    /// Whether the partitioned algorithm places partitions on NUMA nodes.
+    pub numa: bool,
+    /// The memory pool reserved for the sweep, if any.
+    pub pool: Option<PoolInfo>,
+    pub warmup: usize,
+    pub repetitions: usize,
+    pub format: OutputFormat,
+    pub output_file: PathBuf,
+    /// Skip points that already have all their records in `output_file`.
+    pub resume: bool,
+}
+
+/// Identifies a point of the sweep in its records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct PointKey {
+    experiment: Experiment,
+    workload: Workload,
+    threads: usize,
+    params: JoinParams,
+    memory_pool: bool,
+}
+
+impl From<&BenchmarkRecord> for PointKey {
+    fn from(record: &BenchmarkRecord) -> Self {
+        Self {
+            experiment: Experiment::new(record.distribution, record.algorithm, record.scheduling),
+            workload: Workload {
+                inner_tuple_num: record.inner_tuple_num,
+                outer_ratio: record.outer_ratio,
+                batch_size: record.batch_size,
+                selectivity: record.selectivity,
+            },
+            threads: record.threads,
+            params: JoinParams {
+                buckets: record.buckets(),
+                shared_table: record.shared_table,
+                partition_num: record.partition_num,
+                memory_limit: record.memory_limit,
+                prefetch: Prefetch::new(
+                    record.prefetch,
+                    record.prefetch_distance.unwrap_or_default(),
+                ),
+                bloom_bits_per_key: record.bloom_bits_per_key,
+                dense_tables: record.dense_tables,
+                numa: record.numa,
+            },
+            memory_pool: record.memory_pool,
+        }
+    }
+}
+
+impl Sweep {
+    /// Run every point of the sweep, appending the records of each point as soon as it
+    /// finishes so that an interrupted sweep can be resumed.
+    pub fn run(&self) -> Result<()> {
+        let mut finished_runs = HashMap::<PointKey, usize>::new();
+        if self.resume {
+            for record in read_records(&self.output_file, self.format)? {
+                *finished_runs.entry(PointKey::from(&record)).or_default() += 1;
+            }
+        }
+
+        let points = self.points();
+        // Points with some of their runs recorded only run the missing ones.
+        let pending = points
+            .iter()
+            .map(|point| (*point, finished_runs.get(point).copied().unwrap_or(0)))
+            .filter(|&(_, finished)| finished < self.repetitions)
+            .collect::<Vec<_>>();
+        println!(
+            " ==> Sweep: {} points, {} already finished",
+            points.len(),
+            points.len() - pending.len()
+        );
+
+        let tuple_gen = TupleGenerator::new(
+            self.workload.inner_tuple_num as u64,
+            self.workload.outer_ratio as u64,
+            self.workload.batch_size as u64,
+        )
+        .with_selectivity(self.workload.selectivity);
+        let host = HostInfo::detect();
+        let mut distributions = pending
+            .iter()
+            .map(|(point, _)| point.experiment.distribution)
+            .collect::<Vec<_>>();
+        distributions.dedup();
+
+        // The input only depends on the distribution, so generate it once for all points.
+        for distribution in distributions {
+            let (inner, outer) = tuple_gen.generate(distribution);
+            for &threads in &self.threads {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()?;
+                for (point, finished) in pending.iter().filter(|(point, _)| {
+                    point.experiment.distribution == distribution && point.threads == threads
+                }) {
+                    println!(
+                        "Running {} (threads = {}, partitions = {}, {})...",
+                        point.experiment, threads, point.params.partition_num, point.params.buckets
+                    );
+                    let results = pool.install(|| {
+                        point.experiment.run_repeated(
+                            &point.params,
+                            &Instrumentation::default(),
+                            &inner,
+                            &outer,
+                            self.warmup,
+                            self.repetitions - finished,
+                        )
+                    });
+                    print_results(&results);
+                    println!();
+
+                    let context = RunContext {
+                        workload: self.workload,
+                        threads,
+                        params: point.params,
+                        pool: self.pool,
+                        host: host.clone(),
+                    };
+                    let records = results
+                        .iter()
+                        .enumerate()
+                        .map(|(i, result)| {
+                            BenchmarkRecord::new(&point.experiment, &context, finished + i, result)
+                        })
+                        .collect::<Vec<_>>();
+                    append_records(&self.output_file, self.format, &records)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// All valid points, ordered by distribution so that inputs can be reused. Algorithms
+    /// that do not partition are only run with the first partition number.
+    fn points(&self) -> Vec<PointKey> {
+        let mut experiments = self.experiments.clone();
+        experiments.sort_by_key(|experiment| experiment.distribution as u8);
+
+        let mut points = Vec::new();
+        for experiment in experiments {
+            for &threads in &self.threads {
+                for (i, &partition_num) in self.partition_nums.iter().enumerate() {
+                    if i > 0 && !experiment.algorithm.uses_partitions() {
+                        continue;
+                    }
+                    for &buckets in &self.bucket_sizings {
+                        let point = PointKey {
+                            experiment,
+                            workload: self.workload,
+                            threads,
+                            params: JoinParams {
+                                buckets,
+                                shared_table: self.shared_table,
+                                partition_num,
+                                memory_limit: self.memory_limit,
+                                prefetch: self.prefetch,
+                                bloom_bits_per_key: self.bloom_bits_per_key,
+                                dense_tables: self.dense_tables,
+                                numa: self.numa,
+                            },
+                            memory_pool: self.pool.is_some(),
+                        };
+                        match self.validate(&point) {
+                            Ok(()) => points.push(point),
+                            Err(e) => println!(
+                                "Skipping {} (threads = {}, partitions = {}, {}): {}",
+                                experiment, threads, partition_num, buckets, e
+                            ),
+                        }
+                    }
+                }
+            }
+        }
+        points
+    }
+
+    fn validate(&self, point: &PointKey) -> Result<()> {
+        let JoinParams {
+            buckets,
+            partition_num,
+            ..
+        } = point.params;
+        if !point.threads.is_power_of_two() {
+            return Err(anyhow!("thread number must be a power of 2"));
+        }
+        if !self.workload.batch_size.is_multiple_of(point.threads) {
+            return Err(anyhow!("batch size must be a multiple of thread number"));
+        }
+        if buckets.fixed().is_some_and(|n| !n.is_power_of_two()) {
+            return Err(anyhow!("bucket number must be a power of 2"));
+        }
+        if point.experiment.algorithm.uses_partitions() {
+            if !partition_num.is_power_of_two() || !partition_num.is_multiple_of(point.threads) {
+                return Err(anyhow!(
+                    "partition number must be a power of 2 and a multiple of thread number"
+                ));
+            }
+            if buckets
+                .fixed()
+                .is_some_and(|n| !n.is_multiple_of(partition_num))
+            {
+                return Err(anyhow!(
+                    "bucket number must be a multiple of partition number"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{experiment::Algorithm, tuple::Distribution};
+
+    #[test]
+    fn test_param_list() {
+        let parse = |s: &str| s.parse::<ParamList>().unwrap().0;
+        assert_eq!(parse("1,2,4,8"), vec![1, 2, 4, 8]);
+        assert_eq!(parse("2^6..2^9"), vec![64, 128, 256, 512]);
+        assert_eq!(parse("3..5, 2^4"), vec![3, 4, 5, 16]);
+        // Ranges that end at the largest values stop instead of overflowing.
+        assert_eq!(parse("2^62..2^63"), vec![1 << 62, 1 << 63]);
+        assert_eq!(
+            parse("18446744073709551614..18446744073709551615"),
+            vec![usize::MAX - 1, usize::MAX]
+        );
+        assert!("3^2".parse::<ParamList>().is_err());
+        assert!("8..4".parse::<ParamList>().is_err());
+        assert!("x".parse::<ParamList>().is_err());
+    }
+
+    /// A resumed point that has some of its runs recorded only runs the missing ones,
+    /// and numbers them after the recorded ones.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_resume_partial_point() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sweep = Sweep {
+            workload: Workload {
+                inner_tuple_num: 256,
+                outer_ratio: 2,
+                batch_size: 64,
+                selectivity: 100,
+            },
+            experiments: vec![Experiment::new(
+                Distribution::Uniform,
+                Algorithm::Sequential,
+                None,
+            )],
+            threads: vec![1],
+            partition_nums: vec![1],
+            bucket_sizings: vec![BucketSizing::Fixed(64)],
+            shared_table: SharedTable::default(),
+            memory_limit: None,
+            prefetch: Prefetch::default(),
+            bloom_bits_per_key: None,
+            dense_tables: false,
+            numa: false,
+            pool: None,
+            warmup: 0,
+            repetitions: 1,
+            format: OutputFormat::Json,
+            output_file: dir.path().join("results.json"),
+            resume: true,
+        };
+        let repetitions = |sweep: &Sweep| {
+            read_records(&sweep.output_file, sweep.format)
+                .unwrap()
+                .iter()
+                .map(|record| record.repetition)
+                .collect::<Vec<_>>()
+        };
+        sweep.run().unwrap();
+        assert_eq!(repetitions(&sweep), [0]);
+        sweep.repetitions = 3;
+        sweep.run().unwrap();
+        assert_eq!(repetitions(&sweep), [0, 1, 2]);
+        sweep.run().unwrap();
+        assert_eq!(repetitions(&sweep), [0, 1, 2]);
+    }
+}
@@ -0,0 +1,143 @@
+//! Synchronization primitives that can be swapped for loom's model-checked ones.
+//!
+//! Building with `RUSTFLAGS="--cfg loom"` replaces the locks, atomics and cells, including
+//! the cell behind `Mutex`, with loom's, so that loom tests explore every interleaving of concurrent
+//! inserts and probes and detect unsynchronized accesses. Only the loom tests can run
+//! in that build, because loom primitives panic outside of `loom::model`.
+
+#[cfg(not(loom))]
+pub use not_loom::{Mutex, RwLock, UnsafeCell};
+#[cfg(not(loom))]
+pub use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
+
+#[cfg(loom)]
+pub use loom::cell::UnsafeCell;
+#[cfg(loom)]
+pub use loom::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
+#[cfg(loom)]
+pub use with_loom::{Mutex, RwLock};
+
+#[cfg(not(loom))]
+mod not_loom {
+    /// A mutex that also allows unlocked reads once writers are known to be done.
+    pub struct Mutex<T>(parking_lot::Mutex<T>);
+
+    impl<T> Mutex<T> {
+        pub fn new(value: T) -> Self {
+            Self(parking_lot::Mutex::new(value))
+        }
+
+        pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+            f(&mut self.0.lock())
+        }
+
+        /// Read the value without locking.
+        ///
+        /// # Safety
+        ///
+        /// No thread may call `with_lock` while the returned reference is alive.
+        pub unsafe fn get_unchecked(&self) -> &T {
+            &*self.0.data_ptr()
+        }
+    }
+
+    /// `std::cell::UnsafeCell` with the accessors of loom's, which loom checks for
+    /// unsynchronized accesses.
+    pub struct UnsafeCell<T>(std::cell::UnsafeCell<T>);
+
+    impl<T> UnsafeCell<T> {
+        pub fn new(value: T) -> Self {
+            Self(std::cell::UnsafeCell::new(value))
+        }
+
+        pub fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+            f(self.0.get())
+        }
+
+        pub fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+            f(self.0.get())
+        }
+    }
+
+    pub struct RwLock<T>(parking_lot::RwLock<T>);
+
+    impl<T> RwLock<T> {
+        pub fn new(value: T) -> Self {
+            Self(parking_lot::RwLock::new(value))
+        }
+
+        pub fn read(&self) -> impl std::ops::Deref<Target = T> + '_ {
+            self.0.read()
+        }
+
+        pub fn write(&self) -> impl std::ops::DerefMut<Target = T> + '_ {
+            self.0.write()
+        }
+
+        pub fn into_inner(self) -> T {
+            self.0.into_inner()
+        }
+    }
+}
+
+#[cfg(loom)]
+mod with_loom {
+    use loom::{
+        cell::UnsafeCell,
+        sync::{Mutex as LoomMutex, RwLock as LoomRwLock},
+    };
+
+    /// A mutex that also allows unlocked reads once writers are known to be done.
+    pub struct Mutex<T> {
+        lock: LoomMutex<()>,
+        value: UnsafeCell<T>,
+    }
+
+    // Accesses to `value` are synchronized by `lock` or by the caller of `get_unchecked`.
+    unsafe impl<T: Send> Send for Mutex<T> {}
+    unsafe impl<T: Send> Sync for Mutex<T> {}
+
+    impl<T> Mutex<T> {
+        pub fn new(value: T) -> Self {
+            Self {
+                lock: LoomMutex::new(()),
+                value: UnsafeCell::new(value),
+            }
+        }
+
+        pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+            let _guard = self.lock.lock().unwrap();
+            self.value.with_mut(|value| f(unsafe { &mut *value }))
+        }
+
+        /// Read the value without locking. Loom checks that the read happens after every
+        /// write, but not that the reference is dropped before the next write.
+        ///
+        /// # Safety
+        ///
+        /// No thread may call `with_lock` while the returned reference is alive.
+        pub unsafe fn get_unchecked(&self) -> &T {
+            self.value.with(|value| &*value)
+        }
+    }
+
+    pub struct RwLock<T>(LoomRwLock<T>);
+
+    impl<T> RwLock<T> {
+        pub fn new(value: T) -> Self {
+            Self(LoomRwLock::new(value))
+        }
+
+        pub fn read(&self) -> impl std::ops::Deref<Target = T> + '_ {
+            self.0.read().unwrap()
+        }
+
+        pub fn write(&self) -> impl std::ops::DerefMut<Target = T> + '_ {
+            self.0.write().unwrap()
+        }
+
+        pub fn into_inner(self) -> T {
+            self.0.into_inner().unwrap()
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use rand::{distributions::Distribution, Rng};
+use rand::{distributions::Distribution as _, Rng};
 
 const LOW_SKEW_ZIPF_ALPHA: f64 = 1.05;
 const HIGH_SKEW_ZIPF_ALPHA: f64 = 1.25;
@@ -27,16 +27,39 @@ impl Tuple {
 
 pub type DataChunk = Vec<Tuple>;
 
+/// Distribution of the outer relation's foreign keys.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Distribution {
+    Uniform,
+    LowSkew,
+    HighSkew,
+}
+
+impl std::fmt::Display for Distribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Distribution::Uniform => write!(f, "Uniform"),
+            Distribution::LowSkew => write!(f, "Low Skew"),
+            Distribution::HighSkew => write!(f, "High Skew"),
+        }
+    }
+}
+
 pub struct TupleGenerator {
     inner_tuple_num: u64,
     inner_batch_num: u64,
     outer_batch_num: u64,
     batch_size: u64,
+    /// Percentage of outer tuples that match an inner tuple.
+    selectivity: u8,
 }
 
 impl TupleGenerator {
     pub fn new(inner_tuple_num: u64, outer_ratio: u64, batch_size: u64) -> Self {
-        if inner_tuple_num % batch_size != 0 {
+        if !inner_tuple_num.is_multiple_of(batch_size) {
             panic!("inner_tuple_num must be a multiple of batch_size");
         }
         let inner_batch_num = inner_tuple_num / batch_size;
@@ -46,6 +69,24 @@ impl TupleGenerator {
             inner_batch_num,
             outer_batch_num,
             batch_size,
+            selectivity: 100,
+        }
+    }
+
+    /// Only let the given percentage of outer tuples match. The keys of the others are
+    /// moved past the inner keys, so that their distribution is kept.
+    pub fn with_selectivity(mut self, selectivity: u8) -> Self {
+        assert!(selectivity <= 100, "selectivity must be a percentage");
+        self.selectivity = selectivity;
+        self
+    }
+
+    /// Generate the inner and outer relations for the given distribution.
+    pub fn generate(&self, distribution: Distribution) -> (Vec<DataChunk>, Vec<DataChunk>) {
+        match distribution {
+            Distribution::Uniform => self.gen_uniform(),
+            Distribution::LowSkew => self.gen_low_skew(),
+            Distribution::HighSkew => self.gen_high_skew(),
         }
     }
 
@@ -80,8 +121,16 @@ impl TupleGenerator {
         self.gen_table(self.inner_batch_num, Tuple::new)
     }
 
-    fn gen_outer_table(&self, gen_fn: impl FnMut(u64) -> Tuple) -> Vec<DataChunk> {
-        self.gen_table(self.outer_batch_num, gen_fn)
+    fn gen_outer_table(&self, mut gen_fn: impl FnMut(u64) -> Tuple) -> Vec<DataChunk> {
+        let mut rng = rand::thread_rng();
+        self.gen_table(self.outer_batch_num, |i| {
+            let tuple = gen_fn(i);
+            if self.selectivity == 100 || rng.gen_range(0..100) < self.selectivity {
+                tuple
+            } else {
+                Tuple::new(tuple.key() + self.inner_tuple_num)
+            }
+        })
     }
 
     /// Generate a table consisting of `batch_num`` batches. `gen_fn` maps index to `Tuple``.
@@ -121,6 +170,23 @@ mod tests {
         verify_gen(&gen, &inner, &outer);
     }
 
+    #[test]
+    fn test_selectivity() {
+        let gen = TupleGenerator::new(100, 100, 100).with_selectivity(25);
+        let (_, outer) = gen.gen_low_skew();
+        let matching = outer.iter().flatten().filter(|t| t.key() < 100).count();
+        assert!((2000..3000).contains(&matching), "{} matching", matching);
+        assert!(outer.iter().flatten().all(|t| t.key() < 200));
+
+        let (_, outer) = TupleGenerator::new(100, 10, 10)
+            .with_selectivity(0)
+            .gen_uniform();
+        assert!(outer
+            .iter()
+            .flatten()
+            .all(|t| (100..200).contains(&t.key())));
+    }
+
     fn verify_gen(gen: &TupleGenerator, inner: &[DataChunk], outer: &[DataChunk]) {
         let batch_size = gen.batch_size;
         let inner_tuple_num = gen.inner_tuple_num;
@@ -0,0 +1,319 @@
+//! Checks join results against a straightforward reference join.
+
+use std::collections::HashMap;
+
+use anyhow::{ensure, Result};
+
+use crate::{
+    join_benchmark::JoinOutput,
+    tuple::{DataChunk, Key, Tuple},
+};
+
+/// Join the relations with a `HashMap`, producing every matching inner tuple for each
+/// outer tuple like the benchmarked joins.
+pub fn reference_join(inner: &[DataChunk], outer: &[DataChunk]) -> JoinOutput {
+    let mut table = HashMap::<Key, Vec<&Tuple>>::new();
+    for tuple in inner.iter().flatten() {
+        table.entry(tuple.key()).or_default().push(tuple);
+    }
+    let mut output = JoinOutput::default();
+    for tuple in outer.iter().flatten() {
+        for &tuple in table.get(&tuple.key()).into_iter().flatten() {
+            output.push(tuple);
+        }
+    }
+    output
+}
+
+/// Fail unless `actual` has the same cardinality and checksum as `expected`.
+pub fn verify(expected: &JoinOutput, actual: &JoinOutput) -> Result<()> {
+    ensure!(
+        expected.tuple_num == actual.tuple_num,
+        "expected {} output tuples, got {}",
+        expected.tuple_num,
+        actual.tuple_num
+    );
+    ensure!(
+        expected.checksum == actual.checksum,
+        "expected output checksum {:#018x}, got {:#018x}",
+        expected.checksum,
+        actual.checksum
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        experiment::{Algorithm, Experiment, JoinParams, Scheduling},
+        join_benchmark::{
+            prefetch::Prefetch, shared::SharedTable, BucketSizing, Instrumentation, LoadFactor,
+            TableKind,
+        },
+        tuple::{Distribution, TupleGenerator},
+    };
+
+    const THREAD_NUM: usize = 4;
+
+    fn chunks(keys: impl IntoIterator<Item = Key>, chunk_size: usize) -> Vec<DataChunk> {
+        let tuples = keys.into_iter().map(Tuple::new).collect::<Vec<_>>();
+        tuples.chunks(chunk_size).map(<[Tuple]>::to_vec).collect()
+    }
+
+    /// Run every algorithm and scheduling on the input and compare with the reference.
+    fn check(inner: &[DataChunk], outer: &[DataChunk], params: JoinParams) {
+        let expected = reference_join(inner, outer);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(THREAD_NUM)
+            .build()
+            .unwrap();
+        let experiments = Experiment::product(
+            &[Distribution::Uniform],
+            &[
+                Algorithm::Sequential,
+                Algorithm::Shared,
+                Algorithm::Partitioned,
+                Algorithm::Grace,
+                Algorithm::SortMerge,
+                Algorithm::Radix,
+                Algorithm::IndependentBroadcast,
+                Algorithm::IndependentRouted,
+            ],
+            &[Scheduling::Static, Scheduling::Dynamic],
+        );
+        for experiment in experiments {
+            let result = pool.install(|| {
+                experiment.run(
+                    &params,
+                    &Instrumentation::default(),
+                    inner.to_vec(),
+                    outer.to_vec(),
+                )
+            });
+            if let Err(e) = verify(&expected, &result.output) {
+                panic!("{} with {:?}: {}", experiment, params, e);
+            }
+        }
+    }
+
+    /// The memory limit of 64 tuples makes the grace join spill most partitions and
+    /// re-partition the larger ones.
+    fn params(bucket_num: usize, partition_num: usize) -> JoinParams {
+        JoinParams {
+            buckets: BucketSizing::Fixed(bucket_num),
+            shared_table: SharedTable::Fixed,
+            partition_num,
+            memory_limit: Some(64 * std::mem::size_of::<Tuple>()),
+            prefetch: Prefetch::None,
+            bloom_bits_per_key: None,
+            dense_tables: false,
+            numa: false,
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_generated_workloads() {
+        let tuple_gen = TupleGenerator::new(4096, 4, 512);
+        for distribution in [
+            Distribution::Uniform,
+            Distribution::LowSkew,
+            Distribution::HighSkew,
+        ] {
+            let (inner, outer) = tuple_gen.generate(distribution);
+            check(&inner, &outer, params(1024, 16));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_prefetching() {
+        let (inner, outer) = TupleGenerator::new(2048, 2, 256).generate(Distribution::LowSkew);
+        for prefetch in [
+            Prefetch::Group { distance: 1 },
+            Prefetch::Group { distance: 7 },
+            Prefetch::Amac { distance: 1 },
+            Prefetch::Amac { distance: 16 },
+        ] {
+            let params = JoinParams {
+                prefetch,
+                ..params(512, 8)
+            };
+            check(&inner, &outer, params);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_filtered_probes() {
+        let (inner, outer) = TupleGenerator::new(2048, 2, 256)
+            .with_selectivity(10)
+            .generate(Distribution::LowSkew);
+        for bloom_bits_per_key in [Some(1), Some(12)] {
+            for prefetch in [Prefetch::None, Prefetch::Amac { distance: 4 }] {
+                let params = JoinParams {
+                    prefetch,
+                    bloom_bits_per_key,
+                    ..params(512, 8)
+                };
+                check(&inner, &outer, params);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_dense_tables() {
+        let (inner, outer) = TupleGenerator::new(2048, 2, 256)
+            .with_selectivity(50)
+            .generate(Distribution::HighSkew);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let duplicates = chunks((0..1000).map(|_| rng.gen_range(500..1000)), 64);
+        let sparse = chunks((0..1000).map(|key| key * 3), 64);
+        let params = JoinParams {
+            dense_tables: true,
+            bloom_bits_per_key: Some(8),
+            ..params(512, 8)
+        };
+        for (inner, table) in [
+            (&inner, TableKind::Dense),
+            (&duplicates, TableKind::Dense),
+            (&sparse, TableKind::Hash),
+        ] {
+            check(inner, &outer, params);
+            let experiment = Experiment::new(Distribution::Uniform, Algorithm::Sequential, None);
+            let result = experiment.run(
+                &params,
+                &Instrumentation::default(),
+                inner.to_vec(),
+                outer.to_vec(),
+            );
+            assert_eq!(result.table, Some(table));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_load_factors() {
+        let (inner, outer) = TupleGenerator::new(2048, 2, 256).generate(Distribution::HighSkew);
+        for load_factor in [0.25, 1.0, 64.0] {
+            let params = JoinParams {
+                buckets: BucketSizing::LoadFactor(LoadFactor::new(load_factor).unwrap()),
+                ..params(512, 8)
+            };
+            check(&inner, &outer, params);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_shared_tables() {
+        let (inner, outer) = TupleGenerator::new(2048, 2, 256).generate(Distribution::HighSkew);
+        for (shared_table, buckets, prefetch) in [
+            (
+                SharedTable::Growable,
+                BucketSizing::Fixed(1),
+                Prefetch::None,
+            ),
+            (
+                SharedTable::Growable,
+                BucketSizing::LoadFactor(LoadFactor::new(0.5).unwrap()),
+                Prefetch::Amac { distance: 4 },
+            ),
+            (
+                SharedTable::LockFree,
+                BucketSizing::Fixed(64),
+                Prefetch::None,
+            ),
+            (
+                SharedTable::LockFree,
+                BucketSizing::default(),
+                Prefetch::Amac { distance: 4 },
+            ),
+        ] {
+            let params = JoinParams {
+                buckets,
+                shared_table,
+                prefetch,
+                bloom_bits_per_key: Some(4),
+                ..params(512, 8)
+            };
+            check(&inner, &outer, params);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_memory_limits() {
+        let (inner, outer) = TupleGenerator::new(2048, 2, 256).generate(Distribution::HighSkew);
+        let inner_size = 2048 * std::mem::size_of::<Tuple>();
+        for memory_limit in [None, Some(0), Some(inner_size / 2), Some(inner_size)] {
+            let params = JoinParams {
+                memory_limit,
+                ..params(512, 8)
+            };
+            check(&inner, &outer, params);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_duplicate_keys() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let inner = chunks((0..1000).map(|_| rng.gen_range(0..100)), 64);
+        let outer = chunks((0..4000).map(|_| rng.gen_range(0..120)), 100);
+        let expected = reference_join(&inner, &outer);
+        assert!(expected.tuple_num > 4000);
+        check(&inner, &outer, params(64, 8));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_single_hot_key() {
+        let inner = chunks([7; 10].into_iter().chain(0..100), 16);
+        let outer = chunks((0..1000).map(|i| if i % 2 == 0 { 7 } else { i }), 16);
+        check(&inner, &outer, params(128, 8));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_empty_relations() {
+        let keys = chunks(0..100, 10);
+        check(&[], &keys, params(16, 4));
+        check(&keys, &[], params(16, 4));
+        check(&[], &[], params(16, 4));
+        check(&[Vec::new()], &[Vec::new()], params(16, 4));
+        assert_eq!(reference_join(&keys, &[]), JoinOutput::default());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_non_matching() {
+        let inner = chunks(0..500, 50);
+        let outer = chunks(1000..3000, 50);
+        assert_eq!(reference_join(&inner, &outer).tuple_num, 0);
+        check(&inner, &outer, params(64, 8));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_fewer_items_than_threads() {
+        // Chunks and partitions smaller than the thread number.
+        let inner = chunks(0..6, 3);
+        let outer = chunks([0, 1, 2, 5, 5, 9], 1);
+        check(&inner, &outer, params(4, 2));
+    }
+
+    #[test]
+    fn test_verify_detects_difference() {
+        let inner = chunks(0..10, 5);
+        let expected = reference_join(&inner, &chunks(0..10, 5));
+        let actual = reference_join(&inner, &chunks((0..9).chain([8]), 5));
+        assert_eq!(expected.tuple_num, actual.tuple_num);
+        assert!(verify(&expected, &actual).is_err());
+        assert!(verify(&expected, &expected).is_ok());
+    }
+}
@@ -0,0 +1,130 @@
+//! Per-worker accounting of the tuples processed and the time spent busy in each phase.
+//!
+//! Parallel loops create a `WorkTracker` for each task they run. When the tracker is
+//! dropped, its tuple count and busy time are added to the slot of the rayon worker it
+//! ran on. Tracking is disabled outside of `start_phase` and `finish_phase`, in which
+//! case trackers do not read the clock.
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Maximum number of workers that can be tracked. Workers beyond it share the last slot.
+const MAX_WORKERS: usize = 256;
+
+#[derive(Default)]
+#[repr(align(128))]
+struct WorkerSlot {
+    tuples: AtomicU64,
+    busy_nanos: AtomicU64,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SLOTS: [WorkerSlot; MAX_WORKERS] = [const {
+    WorkerSlot {
+        tuples: AtomicU64::new(0),
+        busy_nanos: AtomicU64::new(0),
+    }
+}; MAX_WORKERS];
+
+/// Records the work of a task on the current worker when dropped.
+pub struct WorkTracker {
+    start: Option<Instant>,
+    tuples: u64,
+}
+
+impl WorkTracker {
+    pub fn start() -> Self {
+        Self {
+            start: ENABLED.load(Ordering::Relaxed).then(Instant::now),
+            tuples: 0,
+        }
+    }
+
+    /// Count processed tuples.
+    pub fn add(&mut self, tuples: usize) {
+        self.tuples += tuples as u64;
+    }
+}
+
+impl Drop for WorkTracker {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            let worker = rayon::current_thread_index().unwrap_or(0);
+            let slot = &SLOTS[worker.min(MAX_WORKERS - 1)];
+            slot.tuples.fetch_add(self.tuples, Ordering::Relaxed);
+            slot.busy_nanos
+                .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The work done by one worker in a phase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkerStats {
+    pub tuples: u64,
+    pub busy: Duration,
+    /// Phase time not spent in tracked tasks, i.e. waiting, stealing and scheduling.
+    pub idle: Duration,
+}
+
+/// Reset all workers and start tracking.
+pub fn start_phase() {
+    for slot in &SLOTS {
+        slot.tuples.store(0, Ordering::Relaxed);
+        slot.busy_nanos.store(0, Ordering::Relaxed);
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Stop tracking and return the work of the first `worker_num` workers in a phase that
+/// took `elapsed`. Returns `None` if no work was tracked, e.g. in a sequential phase.
+pub fn finish_phase(worker_num: usize, elapsed: Duration) -> Option<Vec<WorkerStats>> {
+    ENABLED.store(false, Ordering::Relaxed);
+    let stats = SLOTS[..worker_num.min(MAX_WORKERS)]
+        .iter()
+        .map(|slot| {
+            let busy = Duration::from_nanos(slot.busy_nanos.load(Ordering::Relaxed));
+            WorkerStats {
+                tuples: slot.tuples.load(Ordering::Relaxed),
+                busy,
+                idle: elapsed.saturating_sub(busy),
+            }
+        })
+        .collect::<Vec<_>>();
+    stats
+        .iter()
+        .any(|worker| worker.tuples > 0)
+        .then_some(stats)
+}
+
+/// Max divided by mean. 1.0 means perfect balance.
+pub fn imbalance(values: impl IntoIterator<Item = f64>) -> f64 {
+    let values = values.into_iter().collect::<Vec<_>>();
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    if mean == 0.0 {
+        return 1.0;
+    }
+    values.iter().copied().fold(f64::MIN, f64::max) / mean
+}
+
+/// Imbalance of busy time and of tuples across workers.
+pub fn worker_imbalance(workers: &[WorkerStats]) -> (f64, f64) {
+    (
+        imbalance(workers.iter().map(|w| w.busy.as_secs_f64())),
+        imbalance(workers.iter().map(|w| w.tuples as f64)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_imbalance() {
+        assert_eq!(imbalance([1.0, 1.0, 1.0, 1.0]), 1.0);
+        assert_eq!(imbalance([4.0, 0.0, 0.0, 0.0]), 4.0);
+        assert_eq!(imbalance([0.0, 0.0]), 1.0);
+    }
+}